@@ -1,4 +1,9 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/orderbook.proto")?;
+    // Closed candles and spread samples are persisted as JSON lines.
+    tonic_build::configure()
+        .type_attribute("orderbook.Candle", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .type_attribute("orderbook.SpreadSample", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .type_attribute("orderbook.TopOfBook", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .compile(&["proto/orderbook.proto"], &["proto"])?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,243 @@
+use std::{collections::{BTreeMap, HashMap}, str::FromStr, sync::{Arc, Mutex}, time::Duration};
+use chrono::Utc;
+use ordered_float::OrderedFloat;
+use crate::{AppError, fees::FeeTable, fx::{Conversion, Rate}, models::{BookKey, FeedStatus, InstrumentKind, OrderBook}, orderbook::{self, Level, Summary, VenueStatus}};
+
+/// How often [`watch_freshness`] looks for silent venues.
+const FRESHNESS_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of levels per side returned by `book_summary` unless the request asks for another depth.
+pub const SUMMARY_DEPTH: usize = 10;
+/// Tolerance when placing a price in a bucket, so that e.g. 30003.3 falls in the 30003.3 bucket of a 0.1 grid.
+const BUCKET_EPSILON: f64 = 1e-9;
+
+/// How levels are grouped into price buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Grouping {
+    /// Every venue level on its own.
+    #[default]
+    Raw,
+    /// Buckets of a fixed price width, e.g. 10.0 for $10 buckets.
+    Step(f64),
+    /// Buckets a percentage of the mid wide, laid out from the mid.
+    PercentOfMid(f64),
+}
+
+impl Grouping {
+    pub fn new(bucket_size: f64, bucket_percent: f64) -> Result<Grouping, AppError> {
+        match (bucket_size, bucket_percent) {
+            (size, percent) if size < 0.0 || percent < 0.0 || !size.is_finite() || !percent.is_finite() => Err(AppError::ParsingFailed(format!("invalid bucket {} / {}%", size, percent))),
+            (size, percent) if size > 0.0 && percent > 0.0 => Err(AppError::ParsingFailed("bucket size and bucket percent are exclusive".to_string())),
+            (size, _) if size > 0.0 => Ok(Grouping::Step(size)),
+            (_, percent) if percent > 0.0 => Ok(Grouping::PercentOfMid(percent)),
+            _ => Ok(Grouping::Raw),
+        }
+    }
+}
+
+/// Parses `10` as $10 buckets and `0.1%` as buckets 0.1% of the mid wide; an empty string or `raw` means no grouping.
+impl FromStr for Grouping {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Grouping, AppError> {
+        let parse = |value: &str| value.trim().parse::<f64>().map_err(|_| AppError::ParsingFailed(format!("grouping {}", s)));
+        match s.trim() {
+            "" | "raw" => Ok(Grouping::Raw),
+            percent if percent.ends_with('%') => Grouping::new(0.0, parse(percent.trim_end_matches('%'))?),
+            size => Grouping::new(parse(size)?, 0.0),
+        }
+    }
+}
+
+/// How many levels per side to show, or buckets when grouped, and how to group them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub depth: usize,
+    pub grouping: Grouping,
+}
+
+impl Default for View {
+    fn default() -> View {View { depth: SUMMARY_DEPTH, grouping: Grouping::Raw }}
+}
+
+/// Adds `name` to a comma-separated list unless it is already there.
+fn add_name(list: &mut String, name: &str) {
+    if list.split(',').any(|existing| existing == name) {return;}
+    if !list.is_empty() {list.push(',');}
+    list.push_str(name);
+}
+
+/// Groups `levels`, sorted best first, into `view`'s price buckets, summing size across venues, and keeps the best
+/// `view.depth`. A bucket is labelled with its outer edge, bids rounding down and asks up, so it never looks better
+/// than the levels in it. `exchange` and `instrument` list everything that contributed, `effective_price` is the
+/// amount-weighted average and `fx_rate` is 0 if the levels were converted at different rates.
+pub fn group(levels: Vec<Level>, is_bid: bool, view: View, mid: f64) -> Vec<Level> {
+    let (width, anchor) = match view.grouping {
+        Grouping::Raw => (0.0, 0.0),
+        Grouping::Step(width) => (width, 0.0),
+        Grouping::PercentOfMid(percent) => (mid * percent / 100.0, mid),
+    };
+    if width <= 0.0 {return levels.into_iter().take(view.depth).collect();}
+
+    let mut buckets: BTreeMap<OrderedFloat<f64>, Level> = BTreeMap::new();
+    for level in levels {
+        let steps = (level.price - anchor) / width;
+        let price = anchor + width * if is_bid {(steps + BUCKET_EPSILON).floor()} else {(steps - BUCKET_EPSILON).ceil()};
+        let bucket = buckets.entry(OrderedFloat(price)).or_insert_with(|| Level { price, amount: 0.0, effective_price: 0.0, exchange: String::new(), instrument: String::new(), ..level.clone() });
+        let amount = bucket.amount + level.amount;
+        if amount > 0.0 {bucket.effective_price = (bucket.effective_price * bucket.amount + level.effective_price * level.amount) / amount;}
+        bucket.amount = amount;
+        bucket.converted |= level.converted;
+        if bucket.fx_rate != level.fx_rate {bucket.fx_rate = 0.0;}
+        add_name(&mut bucket.exchange, &level.exchange);
+        add_name(&mut bucket.instrument, &level.instrument);
+    }
+    let buckets = buckets.into_values();
+    if is_bid {buckets.rev().take(view.depth).collect()} else {buckets.take(view.depth).collect()}
+}
+
+/// Midpoint of the best bid and ask, or whichever side exists.
+fn mid(bids: &[Level], asks: &[Level]) -> f64 {
+    match (bids.iter().map(|l| l.price).reduce(f64::max), asks.iter().map(|l| l.price).reduce(f64::min)) {
+        (Some(bid), Some(ask)) => (bid + ask) / 2.0,
+        (Some(price), None) | (None, Some(price)) => price,
+        (None, None) => 0.0,
+    }
+}
+
+/// Merges every live venue book of `instrument`, or of every instrument of `kind` when none is named, into one
+/// ladder per side, best level first. Stale or still-connecting venues are left out of the ladder but reported
+/// in `venues`, which lists every venue book. `view` sets the depth of the ladder and how it is grouped.
+///
+/// With `fees`, levels are ranked by the price a taker would actually pay or receive on their venue,
+/// `effective_price` is filled in and `net_spread` is the spread between the best effective prices.
+///
+/// With `conversion`, every price is converted into its currency before ranking and converted levels are marked.
+/// Books of instruments it has no rate for are left out of the ladder.
+pub fn consolidate(books: &HashMap<BookKey, OrderBook>, instrument: Option<&str>, kind: InstrumentKind, view: View, fees: Option<&FeeTable>, conversion: Option<&Conversion>) -> Summary {
+    let mut bids: Vec<Level> = Vec::new();
+    let mut asks: Vec<Level> = Vec::new();
+    let selected = |key: &BookKey, book: &OrderBook| instrument.map_or(book.kind == kind, |name| key.instrument == name);
+    for (key, book) in books.iter().filter(|(key, book)| book.is_live() && selected(key, book)) {
+        let rate = match conversion {
+            Some(conversion) => match conversion.rates.get(&key.instrument) {Some(rate) => *rate, None => continue},
+            None => Rate { rate: 1.0, converted: false },
+        };
+        let schedule = fees.map(|table| table.for_exchange(&key.exchange));
+        // Buckets can take any number of levels from each book.
+        let depth = if view.grouping == Grouping::Raw {view.depth} else {usize::MAX};
+        bids.extend(book.bids_best_first().take(depth).map(|bid| Level {
+            exchange: key.exchange.clone(),
+            price: bid.price.into_inner() * rate.rate,
+            amount: bid.size.into_inner(),
+            effective_price: schedule.map_or(0.0, |s| s.effective_bid(bid.price.into_inner() * rate.rate)),
+            instrument: key.instrument.clone(),
+            converted: rate.converted,
+            fx_rate: rate.rate,
+        }));
+        asks.extend(book.asks.iter().take(depth).map(|ask| Level {
+            exchange: key.exchange.clone(),
+            price: ask.price.into_inner() * rate.rate,
+            amount: ask.size.into_inner(),
+            effective_price: schedule.map_or(0.0, |s| s.effective_ask(ask.price.into_inner() * rate.rate)),
+            instrument: key.instrument.clone(),
+            converted: rate.converted,
+            fx_rate: rate.rate,
+        }));
+    }
+
+    let rank = |level: &Level| if fees.is_some() {level.effective_price} else {level.price};
+    bids.sort_by(|a, b| rank(b).total_cmp(&rank(a)).then(b.amount.total_cmp(&a.amount)));
+    asks.sort_by(|a, b| rank(a).total_cmp(&rank(b)).then(b.amount.total_cmp(&a.amount)));
+    let spread = match (bids.iter().map(|l| l.price).reduce(f64::max), asks.iter().map(|l| l.price).reduce(f64::min)) {
+        (Some(bid), Some(ask)) => ask - bid,
+        _ => 0.0,
+    };
+    let net_spread = match (fees, bids.first(), asks.first()) {
+        (Some(_), Some(bid), Some(ask)) => ask.effective_price - bid.effective_price,
+        _ => 0.0,
+    };
+    let mid = mid(&bids, &asks);
+    let (bids, asks) = (group(bids, true, view, mid), group(asks, false, view, mid));
+    let quote_currency = conversion.map(|c| c.currency.clone()).unwrap_or_default();
+    Summary { spread, bids, asks, net_spread, venues: venue_statuses(books), quote_currency }
+}
+
+pub fn venue_statuses(books: &HashMap<BookKey, OrderBook>) -> Vec<VenueStatus> {
+    let mut keys: Vec<&BookKey> = books.keys().collect();
+    keys.sort();
+    keys.into_iter().map(|key| {
+        let book = &books[key];
+        let (status, reason) = match &book.status {
+            FeedStatus::Connecting => ("connecting", String::new()),
+            FeedStatus::Live => ("live", String::new()),
+            FeedStatus::Stale(reason) => ("stale", reason.clone()),
+        };
+        VenueStatus { exchange: key.exchange.clone(), status: status.to_string(), reason, last_update_id: book.last_update_id.unwrap_or_default(), last_update_micros: book.last_update_micros, kind: orderbook::InstrumentKind::from(book.kind) as i32, instrument: key.instrument.clone() }
+    }).collect()
+}
+
+/// Marks live venue books stale once they have gone `stale_after` without an update.
+pub async fn watch_freshness(books: Arc<Mutex<HashMap<BookKey, OrderBook>>>, stale_after: Duration) {
+    loop {
+        let now = Utc::now().timestamp_micros();
+        for book in books.lock().unwrap().values_mut().filter(|book| book.is_live()) {
+            let silent_ms = (now - book.last_update_micros) / 1000;
+            if silent_ms > stale_after.as_millis() as i64 {book.mark_stale(format!("no update for {} ms", silent_ms));}
+        }
+        tokio::time::sleep(FRESHNESS_CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level { exchange: exchange.to_string(), price, amount, effective_price: price, instrument: "BTC/USD".to_string(), converted: false, fx_rate: 1.0 }
+    }
+
+    fn prices(levels: &[Level]) -> Vec<(f64, f64)> {levels.iter().map(|level| (level.price, level.amount)).collect()}
+
+    #[test]
+    fn groupings_parse() {
+        assert_eq!("".parse::<Grouping>().unwrap(), Grouping::Raw);
+        assert_eq!("10".parse::<Grouping>().unwrap(), Grouping::Step(10.0));
+        assert_eq!(" 0.1% ".parse::<Grouping>().unwrap(), Grouping::PercentOfMid(0.1));
+        assert!("ten".parse::<Grouping>().is_err());
+        assert!("-1".parse::<Grouping>().is_err());
+        assert!(Grouping::new(10.0, 0.1).is_err());
+    }
+
+    #[test]
+    fn raw_levels_are_only_truncated() {
+        let bids = vec![level("Kraken", 100.5, 1.0), level("Bitstamp", 100.2, 2.0), level("Kraken", 99.0, 3.0)];
+        assert_eq!(prices(&group(bids, true, View { depth: 2, grouping: Grouping::Raw }, 0.0)), vec![(100.5, 1.0), (100.2, 2.0)]);
+    }
+
+    #[test]
+    fn buckets_sum_across_venues_and_round_outwards() {
+        let view = View { depth: 10, grouping: Grouping::Step(1.0) };
+        let bids = group(vec![level("Kraken", 100.5, 1.0), level("Bitstamp", 100.2, 3.0), level("Kraken", 99.0, 2.0)], true, view, 0.0);
+        assert_eq!(prices(&bids), vec![(100.0, 4.0), (99.0, 2.0)]);
+        assert_eq!((bids[0].exchange.as_str(), bids[0].effective_price), ("Kraken,Bitstamp", (100.5 + 3.0 * 100.2) / 4.0));
+
+        let asks = group(vec![level("Kraken", 100.5, 1.0), level("Bitstamp", 101.0, 2.0), level("Kraken", 101.2, 1.0)], false, view, 0.0);
+        assert_eq!(prices(&asks), vec![(101.0, 3.0), (102.0, 1.0)]);
+        assert_eq!(group(asks, false, View { depth: 1, ..view }, 0.0).len(), 1);
+    }
+
+    #[test]
+    fn percent_buckets_are_laid_out_from_the_mid() {
+        let view = View { depth: 10, grouping: Grouping::PercentOfMid(1.0) };
+        let bids = group(vec![level("Kraken", 199.0, 1.0), level("Kraken", 197.5, 1.0)], true, view, 200.0);
+        assert_eq!(prices(&bids), vec![(198.0, 1.0), (196.0, 1.0)]);
+    }
+
+    #[test]
+    fn mixed_rates_are_flagged() {
+        let converted = Level { converted: true, fx_rate: 0.999, ..level("Binance", 100.4, 1.0) };
+        let bids = group(vec![level("Kraken", 100.5, 1.0), converted], true, View { depth: 10, grouping: Grouping::Step(1.0) }, 0.0);
+        assert_eq!((bids[0].converted, bids[0].fx_rate, bids[0].instrument.as_str()), (true, 0.0, "BTC/USD"));
+    }
+}
@@ -0,0 +1,112 @@
+use chrono::Utc;
+use crate::orderbook::{Level, MarketAnalytics, Summary};
+
+/// Price levels used for the imbalance unless the request asks for another number.
+pub const IMBALANCE_LEVELS: usize = 5;
+/// Band around the mid, in basis points, over which depth is summed unless the request asks for another.
+pub const DEPTH_BPS: f64 = 10.0;
+/// Amount in base units the VWAP is computed for unless the request asks for another.
+pub const VWAP_AMOUNT: f64 = 1.0;
+
+/// What to compute from a consolidated book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Parameters {
+    pub imbalance_levels: usize,
+    pub depth_bps: f64,
+    pub vwap_amount: f64,
+}
+
+impl Default for Parameters {
+    fn default() -> Parameters {Parameters { imbalance_levels: IMBALANCE_LEVELS, depth_bps: DEPTH_BPS, vwap_amount: VWAP_AMOUNT }}
+}
+
+/// Sums the size of consecutive levels at the same price, e.g. the same price on several venues.
+fn price_levels(levels: &[Level]) -> Vec<(f64, f64)> {
+    let mut merged: Vec<(f64, f64)> = Vec::new();
+    for level in levels {
+        match merged.last_mut() {
+            Some((price, amount)) if *price == level.price => *amount += level.amount,
+            _ => merged.push((level.price, level.amount)),
+        }
+    }
+    merged
+}
+
+/// Takes up to `amount` from `levels`, best first. Returns the amount filled and its volume-weighted average price.
+fn vwap(levels: &[(f64, f64)], amount: f64) -> (f64, f64) {
+    let (mut filled, mut cost) = (0.0, 0.0);
+    for &(price, size) in levels {
+        if filled >= amount {break;}
+        let take = size.min(amount - filled);
+        filled += take;
+        cost += take * price;
+    }
+    if filled > 0.0 {(filled, cost / filled)} else {(0.0, 0.0)}
+}
+
+/// Derives mid, microprice, imbalance, depth and VWAP from a consolidated `summary`, which must hold raw levels
+/// ranked by price. Every figure is 0 when the side it needs is empty.
+///
+/// The microprice weights each best price by the size on the opposite side, so it leans towards the side that is
+/// more likely to trade through. The imbalance is `(bid - ask) / (bid + ask)` over the top price levels.
+pub fn analyze(summary: &Summary, parameters: Parameters) -> MarketAnalytics {
+    let (bids, asks) = (price_levels(&summary.bids), price_levels(&summary.asks));
+    let mut analytics = MarketAnalytics { timestamp_micros: Utc::now().timestamp_micros(), quote_currency: summary.quote_currency.clone(), ..Default::default() };
+
+    let top = |side: &[(f64, f64)]| side.iter().take(parameters.imbalance_levels).map(|(_, amount)| amount).sum::<f64>();
+    let (bid_volume, ask_volume) = (top(&bids), top(&asks));
+    if bid_volume + ask_volume > 0.0 {analytics.imbalance = (bid_volume - ask_volume) / (bid_volume + ask_volume);}
+
+    (analytics.vwap_sell_filled, analytics.vwap_sell) = vwap(&bids, parameters.vwap_amount);
+    (analytics.vwap_buy_filled, analytics.vwap_buy) = vwap(&asks, parameters.vwap_amount);
+
+    let (Some(&(bid, bid_size)), Some(&(ask, ask_size))) = (bids.first(), asks.first()) else {return analytics};
+    analytics.mid = (bid + ask) / 2.0;
+    analytics.microprice = (bid * ask_size + ask * bid_size) / (bid_size + ask_size);
+    let band = analytics.mid * parameters.depth_bps / 10_000.0;
+    analytics.bid_depth = bids.iter().filter(|(price, _)| *price >= analytics.mid - band).map(|(_, amount)| amount).sum();
+    analytics.ask_depth = asks.iter().filter(|(price, _)| *price <= analytics.mid + band).map(|(_, amount)| amount).sum();
+    analytics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level { exchange: exchange.to_string(), price, amount, effective_price: 0.0, instrument: "BTC/USD".to_string(), converted: false, fx_rate: 1.0 }
+    }
+
+    fn summary(bids: Vec<Level>, asks: Vec<Level>) -> Summary {Summary { bids, asks, quote_currency: "USD".to_string(), ..Default::default() }}
+
+    #[test]
+    fn top_of_book_figures() {
+        let book = summary(
+            vec![level("Kraken", 10_000.0, 1.0), level("Coinbase", 10_000.0, 2.0), level("Kraken", 9_995.0, 4.0), level("Kraken", 9_980.0, 8.0)],
+            vec![level("Kraken", 10_002.0, 1.0), level("Kraken", 10_008.0, 2.0), level("Coinbase", 10_030.0, 5.0)],
+        );
+        let analytics = analyze(&book, Parameters { imbalance_levels: 2, depth_bps: 10.0, vwap_amount: 4.0 });
+        assert_eq!((analytics.mid, analytics.quote_currency.as_str()), (10_001.0, "USD"));
+        // 3 bid against 1 ask at the top: the microprice leans towards the ask.
+        assert_eq!(analytics.microprice, (10_000.0 * 1.0 + 10_002.0 * 3.0) / 4.0);
+        assert_eq!(analytics.imbalance, (7.0 - 3.0) / 10.0);
+        // The band is 10.001 either side of the mid.
+        assert_eq!((analytics.bid_depth, analytics.ask_depth), (7.0, 3.0));
+        assert_eq!((analytics.vwap_sell_filled, analytics.vwap_sell), (4.0, (3.0 * 10_000.0 + 9_995.0) / 4.0));
+        assert_eq!((analytics.vwap_buy_filled, analytics.vwap_buy), (4.0, (10_002.0 + 2.0 * 10_008.0 + 10_030.0) / 4.0));
+    }
+
+    #[test]
+    fn a_thin_book_fills_partially() {
+        let analytics = analyze(&summary(vec![level("Kraken", 100.0, 0.5)], vec![]), Parameters::default());
+        assert_eq!((analytics.vwap_sell_filled, analytics.vwap_sell), (0.5, 100.0));
+        assert_eq!((analytics.vwap_buy_filled, analytics.vwap_buy), (0.0, 0.0));
+        assert_eq!((analytics.mid, analytics.microprice, analytics.imbalance), (0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn an_empty_book_is_all_zero() {
+        let analytics = analyze(&summary(vec![], vec![]), Parameters::default());
+        assert_eq!(MarketAnalytics { timestamp_micros: 0, quote_currency: String::new(), ..analytics }, MarketAnalytics::default());
+    }
+}
@@ -0,0 +1,245 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use chrono::Utc;
+use tokio::sync::broadcast;
+use crate::{fees::{FeeSchedule, FeeTable}, fx::FxTable, instruments::Registry, models::{BookKey, OrderBook}, orderbook::ArbitrageOpportunity, reload::Settings};
+
+/// How often the venue books are scanned for crossed markets.
+pub const SCAN_INTERVAL_MS: u64 = 100;
+
+/// Size and value of buying on one venue and selling on another while it stays profitable after fees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Execution {
+    amount: f64,
+    buy_cost: f64,
+    sell_proceeds: f64,
+}
+
+/// One side of an opportunity: a venue book, its fees and the rate of its quote currency into the one compared in.
+#[derive(Clone, Copy)]
+struct Leg<'a> {
+    book: &'a OrderBook,
+    fees: FeeSchedule,
+    rate: f64,
+}
+
+/// Walks `buy`'s asks up and `sell`'s bids down until the fee-adjusted bid no longer exceeds the fee-adjusted ask.
+/// Prices and the resulting cost and proceeds are in the common quote currency.
+fn walk(buy: Leg, sell: Leg) -> Option<Execution> {
+    let (buy_fees, sell_fees) = (buy.fees, sell.fees);
+    let mut asks = buy.book.asks.iter().map(|l| (l.price.into_inner() * buy.rate, l.size.into_inner()));
+    let mut bids = sell.book.bids_best_first().map(|l| (l.price.into_inner() * sell.rate, l.size.into_inner()));
+    let (mut ask, mut bid) = (asks.next()?, bids.next()?);
+    let mut execution = Execution { amount: 0.0, buy_cost: 0.0, sell_proceeds: 0.0 };
+
+    while sell_fees.effective_bid(bid.0) > buy_fees.effective_ask(ask.0) {
+        let amount = ask.1.min(bid.1);
+        execution.amount += amount;
+        execution.buy_cost += amount * buy_fees.effective_ask(ask.0);
+        execution.sell_proceeds += amount * sell_fees.effective_bid(bid.0);
+        ask.1 -= amount;
+        bid.1 -= amount;
+        if ask.1 <= 0.0 {match asks.next() {Some(next) => ask = next, None => break}}
+        if bid.1 <= 0.0 {match bids.next() {Some(next) => bid = next, None => break}}
+    }
+    if execution.amount > 0.0 {Some(execution)} else {None}
+}
+
+struct Open {
+    started_at: i64,
+    last: ArbitrageOpportunity,
+}
+
+/// Tracks cross-venue opportunities between scans so each one is reported when it opens,
+/// whenever its size or profit changes, and once more with `active = false` when it closes.
+pub struct Detector {
+    fees: FeeTable,
+    fx: FxTable,
+    open: HashMap<(BookKey, BookKey), Open>,
+}
+
+impl Detector {
+    pub fn new(fees: FeeTable, fx: FxTable) -> Detector {Detector { fees, fx, open: HashMap::new() }}
+
+    /// Compares every pair of live books on different venues whose instruments in `instruments` share a base currency
+    /// and kind. Prices of instruments quoted in different currencies are converted into the currency the registry
+    /// merges that kind in, and a pair without a rate for either leg is skipped.
+    pub fn scan(&mut self, books: &HashMap<BookKey, OrderBook>, instruments: &Registry) -> Vec<ArbitrageOpportunity> {
+        let now = Utc::now().timestamp_micros();
+        let mut updates = Vec::new();
+        let mut seen = Vec::new();
+
+        for (buy_key, buy) in books.iter().filter(|(_, book)| book.is_live()) {
+            for (sell_key, sell) in books.iter().filter(|(_, book)| book.is_live()) {
+                // Only the same asset can be bought on one venue and sold on another; anything else, such as a
+                // spot/perpetual difference, is basis, not arbitrage.
+                if buy_key.exchange == sell_key.exchange {continue;}
+                let (Some(buy_instrument), Some(sell_instrument)) = (instruments.get(&buy_key.instrument), instruments.get(&sell_key.instrument)) else {continue};
+                if buy_instrument.base != sell_instrument.base || buy_instrument.kind != sell_instrument.kind {continue;}
+                let currency = match buy_instrument.quote == sell_instrument.quote {
+                    true => buy_instrument.quote.as_str(),
+                    false => instruments.merge_currency(buy_instrument.kind).unwrap_or(&buy_instrument.quote),
+                };
+                let (Some(buy_rate), Some(sell_rate)) = (self.fx.rate(&buy_instrument.quote, currency, books), self.fx.rate(&sell_instrument.quote, currency, books)) else {continue};
+                let buy_leg = Leg { book: buy, fees: self.fees.for_exchange(&buy_key.exchange), rate: buy_rate };
+                let sell_leg = Leg { book: sell, fees: self.fees.for_exchange(&sell_key.exchange), rate: sell_rate };
+                let Some(execution) = walk(buy_leg, sell_leg) else {continue};
+
+                let key = (buy_key.clone(), sell_key.clone());
+                let started_at = self.open.get(&key).map_or(now, |open| open.started_at);
+                let opportunity = ArbitrageOpportunity {
+                    buy_exchange: buy_key.exchange.clone(),
+                    sell_exchange: sell_key.exchange.clone(),
+                    best_ask: buy.asks.first().map_or(0.0, |l| l.price.into_inner() * buy_rate),
+                    best_bid: sell.bids_best_first().next().map_or(0.0, |l| l.price.into_inner() * sell_rate),
+                    amount: execution.amount,
+                    buy_cost: execution.buy_cost,
+                    sell_proceeds: execution.sell_proceeds,
+                    profit: execution.sell_proceeds - execution.buy_cost,
+                    started_at_micros: started_at,
+                    updated_at_micros: now,
+                    duration_ms: (now - started_at) as f64 / 1000.0,
+                    active: true,
+                    instrument: buy_key.instrument.clone(),
+                    sell_instrument: sell_key.instrument.clone(),
+                    quote_currency: currency.to_string(),
+                };
+                let changed = self.open.get(&key).is_none_or(|open| open.last.amount != opportunity.amount || open.last.profit != opportunity.profit);
+                if changed {updates.push(opportunity.clone());}
+                self.open.insert(key.clone(), Open { started_at, last: opportunity });
+                seen.push(key);
+            }
+        }
+
+        let closed: Vec<(BookKey, BookKey)> = self.open.keys().filter(|key| !seen.contains(key)).cloned().collect();
+        for key in closed {
+            if let Some(open) = self.open.remove(&key) {
+                updates.push(ArbitrageOpportunity { updated_at_micros: now, duration_ms: (now - open.started_at) as f64 / 1000.0, active: false, ..open.last });
+            }
+        }
+        updates
+    }
+}
+
+/// Scans `books` every [`SCAN_INTERVAL_MS`] against the instruments currently in `settings` and publishes opportunity
+/// updates to `updates`.
+pub async fn run(books: Arc<Mutex<HashMap<BookKey, OrderBook>>>, settings: Arc<Mutex<Settings>>, fees: FeeTable, fx: FxTable, updates: broadcast::Sender<ArbitrageOpportunity>) {
+    let mut detector = Detector::new(fees, fx);
+    loop {
+        let instruments = settings.lock().unwrap().instruments.clone();
+        let found = detector.scan(&books.lock().unwrap(), &instruments);
+        for opportunity in found {let _ = updates.send(opportunity);}
+        tokio::time::sleep(Duration::from_millis(SCAN_INTERVAL_MS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fx::RateSource, models::OrderType};
+
+    fn book(bids: &[(f64, f64)], asks: &[(f64, f64)]) -> OrderBook {
+        let mut book = OrderBook::default();
+        for &(price, size) in bids {book.set_level(OrderType::Buy as u8, price, size);}
+        for &(price, size) in asks {book.set_level(OrderType::Sell as u8, price, size);}
+        book.touch(Some(1));
+        book
+    }
+
+    fn close(a: f64, b: f64) -> bool {(a - b).abs() < 1e-9}
+
+    fn fee_free() -> FeeTable {FeeTable { schedules: HashMap::new() }}
+
+    fn leg(book: &OrderBook, fees: FeeSchedule) -> Leg<'_> {Leg { book, fees, rate: 1.0 }}
+
+    #[test]
+    fn the_walk_takes_every_level_that_stays_crossed() {
+        let buy = book(&[(99.0, 1.0)], &[(100.0, 1.0), (101.0, 1.0), (104.0, 5.0)]);
+        let sell = book(&[(103.0, 0.5), (102.0, 2.0), (98.0, 5.0)], &[(105.0, 1.0)]);
+        let execution = walk(leg(&buy, FeeSchedule::default()), leg(&sell, FeeSchedule::default())).unwrap();
+        // 103 and 102 lift the asks at 100 and 101, then 102 no longer reaches 104.
+        assert!(close(execution.amount, 2.0));
+        assert!(close(execution.buy_cost, 100.0 + 101.0));
+        assert!(close(execution.sell_proceeds, 0.5 * 103.0 + 1.5 * 102.0));
+    }
+
+    #[test]
+    fn fees_stop_the_walk_where_the_net_bid_no_longer_exceeds_the_net_ask() {
+        let buy = book(&[], &[(100.0, 1.0), (101.0, 1.0)]);
+        let sell = book(&[(102.0, 5.0)], &[]);
+        let fees = FeeSchedule { maker_bps: 0.0, taker_bps: 50.0 };
+        // 102 × 0.995 = 101.49 beats 100 × 1.005 = 100.5 but not 101 × 1.005 = 101.505.
+        let execution = walk(leg(&buy, fees), leg(&sell, fees)).unwrap();
+        assert!(close(execution.amount, 1.0));
+        assert!(close(execution.buy_cost, 100.5) && close(execution.sell_proceeds, 101.49));
+
+        assert_eq!(walk(leg(&book(&[], &[(101.5, 1.0)]), fees), leg(&sell, fees)), None, "crossed, but not after fees");
+        assert_eq!(walk(leg(&book(&[], &[(102.0, 1.0)]), FeeSchedule::default()), leg(&sell, FeeSchedule::default())), None, "touching is not crossed");
+        assert_eq!(walk(leg(&book(&[], &[]), fees), leg(&sell, fees)), None);
+    }
+
+    #[test]
+    fn an_opportunity_is_reported_when_it_opens_changes_and_closes() {
+        let (kraken, coinbase) = (BookKey::new("Kraken", "BTC/USD"), BookKey::new("Coinbase", "BTC/USD"));
+        let mut detector = Detector::new(fee_free(), FxTable::default());
+        let mut books = HashMap::from([(kraken.clone(), book(&[(99.0, 1.0)], &[(100.0, 1.0)])), (coinbase.clone(), book(&[(101.0, 0.5)], &[(102.0, 1.0)]))]);
+
+        let opened = detector.scan(&books, &Registry::default());
+        assert_eq!(opened.len(), 1);
+        let opportunity = &opened[0];
+        assert_eq!((opportunity.buy_exchange.as_str(), opportunity.sell_exchange.as_str(), opportunity.instrument.as_str()), ("Kraken", "Coinbase", "BTC/USD"));
+        assert_eq!((opportunity.best_ask, opportunity.best_bid, opportunity.amount), (100.0, 101.0, 0.5));
+        assert!(close(opportunity.buy_cost, 50.0) && close(opportunity.sell_proceeds, 50.5) && close(opportunity.profit, 0.5));
+        assert!(opportunity.active);
+        assert_eq!((opportunity.started_at_micros, opportunity.duration_ms), (opportunity.updated_at_micros, 0.0));
+        let started_at = opportunity.started_at_micros;
+
+        assert!(detector.scan(&books, &Registry::default()).is_empty(), "an unchanged opportunity is not reported again");
+
+        std::thread::sleep(Duration::from_millis(2));
+        books.get_mut(&coinbase).unwrap().set_level(OrderType::Buy as u8, 101.0, 0.8);
+        let changed = detector.scan(&books, &Registry::default());
+        assert_eq!(changed.len(), 1);
+        assert_eq!((changed[0].amount, changed[0].started_at_micros, changed[0].active), (0.8, started_at, true));
+        assert!(changed[0].updated_at_micros > started_at);
+        assert!(close(changed[0].duration_ms, (changed[0].updated_at_micros - started_at) as f64 / 1000.0));
+
+        books.get_mut(&coinbase).unwrap().set_level(OrderType::Buy as u8, 101.0, 0.0);
+        let closed = detector.scan(&books, &Registry::default());
+        assert_eq!(closed.len(), 1);
+        assert!(!closed[0].active);
+        assert_eq!((closed[0].amount, closed[0].started_at_micros), (0.8, started_at), "a closed opportunity reports what it last was");
+        assert!(closed[0].duration_ms >= 2.0);
+        assert!(detector.scan(&books, &Registry::default()).is_empty());
+    }
+
+    #[test]
+    fn instruments_with_the_same_base_are_compared_in_one_quote_currency() {
+        let (binance, bitstamp) = (BookKey::new("Binance", "BTC/USDT"), BookKey::new("Bitstamp", "BTC/USD"));
+        let fx = FxTable { rates: HashMap::from([("USDT/USD".to_string(), RateSource::Static(0.99))]) };
+        let mut detector = Detector::new(fee_free(), fx);
+        // 100 USDT is 99 USD, below Bitstamp's 99.5 USD bid, though not in the quoted numbers.
+        let books = HashMap::from([(binance.clone(), book(&[(98.0, 1.0)], &[(100.0, 1.0)])), (bitstamp.clone(), book(&[(99.5, 2.0)], &[(101.0, 1.0)]))]);
+        let found = detector.scan(&books, &Registry::default());
+        assert_eq!(found.len(), 1);
+        let opportunity = &found[0];
+        assert_eq!((opportunity.buy_exchange.as_str(), opportunity.instrument.as_str()), ("Binance", "BTC/USDT"));
+        assert_eq!((opportunity.sell_exchange.as_str(), opportunity.sell_instrument.as_str()), ("Bitstamp", "BTC/USD"));
+        assert_eq!(opportunity.quote_currency, "USD");
+        assert!(close(opportunity.best_ask, 99.0) && close(opportunity.best_bid, 99.5));
+        assert!(close(opportunity.amount, 1.0) && close(opportunity.profit, 0.5));
+
+        let mut at_par = Detector::new(fee_free(), FxTable::default());
+        assert!(at_par.scan(&books, &Registry::default()).is_empty(), "at par 100 USDT is above the 99.5 USD bid");
+        let mut without_rate = Detector::new(fee_free(), FxTable { rates: HashMap::new() });
+        assert!(without_rate.scan(&books, &Registry::default()).is_empty(), "a pair without a rate is skipped");
+    }
+
+    #[test]
+    fn stale_books_and_other_instruments_are_not_compared() {
+        let mut detector = Detector::new(fee_free(), FxTable::default());
+        let mut books = HashMap::from([(BookKey::new("Kraken", "BTC/USD"), book(&[], &[(100.0, 1.0)])), (BookKey::new("Coinbase", "BTC/USD"), book(&[(101.0, 1.0)], &[]))]);
+        books.get_mut(&BookKey::new("Coinbase", "BTC/USD")).unwrap().mark_stale("test".to_string());
+        books.insert(BookKey::new("OKX", "BTC/USDT-PERP"), book(&[(105.0, 1.0)], &[]));
+        assert!(detector.scan(&books, &Registry::default()).is_empty());
+    }
+}
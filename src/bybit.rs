@@ -0,0 +1,120 @@
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tungstenite::{connect, Message};
+use url::Url;
+use crate::{AppError, FeedConfig, VENUE_BOOKS, log_crossing, keepalive::Keepalive, models::{parse_decimal, OrderBook, OrderType, Sequence}, impairment::ImpairedFeed};
+
+/// Bybit recommends a `{"op": "ping"}` every 20 s to keep the connection open.
+pub const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrderbookData {
+    pub s: String,
+    /// `[price, size]`; a zero size removes the level.
+    pub b: Vec<[String; 2]>,
+    pub a: Vec<[String; 2]>,
+    /// Update ID. A snapshot with `u` of 1 follows a service restart and replaces the book.
+    pub u: u64,
+    pub seq: u64,
+}
+
+/// An `orderbook` topic push; `kind` is "snapshot" or "delta".
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrderbookMessage { pub topic: String, #[serde(rename = "type")] pub kind: String, pub ts: u64, pub data: OrderbookData }
+
+pub fn subscribe_request(topic: &str) -> String {json!({"op": "subscribe", "args": [topic]}).to_string()}
+
+/// Applies a push to `order_book`, replacing it on a snapshot. `synced` tells whether this connection has received
+/// its snapshot. Returns false for a delta to discard: one before the snapshot or older than the book. Update IDs
+/// are consecutive, so a delta that skips one means pushes were lost.
+pub fn apply(order_book: &mut OrderBook, message: &OrderbookMessage, synced: bool) -> Result<bool, AppError> {
+    if message.kind == "snapshot" {
+        order_book.bids.clear();
+        order_book.asks.clear();
+    } else if !synced {
+        return Ok(false);
+    } else {
+        match order_book.sequence(message.data.u, true) {
+            Sequence::Outdated => return Ok(false),
+            Sequence::Gap { expected, received } => return Err(AppError::SequenceGap(format!("Bybit expected update {} but received {}", expected, received))),
+            Sequence::Next => {}
+        }
+    }
+    for [price, size] in &message.data.b {order_book.set_level(OrderType::Buy as u8, parse_decimal(price)?, parse_decimal(size)?);}
+    for [price, size] in &message.data.a {order_book.set_level(OrderType::Sell as u8, parse_decimal(price)?, parse_decimal(size)?);}
+    order_book.touch(Some(message.data.u));
+    Ok(true)
+}
+
+/// Maintains the Bybit venue book from the listing's `orderbook` topic, discarding deltas older than the book.
+/// A missing update stops the connector and the supervisor resubscribes from scratch.
+pub fn pull(feed: &FeedConfig) -> Result<(), AppError> {
+    let topic = feed.listing.channel()?;
+    let mut impaired = feed.impaired()?;
+    let (mut socket, _response) = connect(Url::parse(&feed.url)?).map_err(|_| AppError::ConnectionFailed(feed.key.to_string()))?;
+    let mut keepalive = Keepalive::new(&mut socket, "Bybit", PING_INTERVAL, json!({"op": "ping"}).to_string())?;
+    {
+        let mut books = VENUE_BOOKS.lock().unwrap();
+        let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+        order_book.resync();
+    }
+    socket.write_message(Message::Text(subscribe_request(topic))).map_err(|e| AppError::MessageError(e.to_string()))?;
+    // Whether this connection has received its snapshot; until then the book may hold levels from before it.
+    let mut synced = false;
+
+    loop {
+        let msg = keepalive.read(&mut socket, impaired.as_ref().and_then(ImpairedFeed::release_at))?;
+        if feed.stopped() {return Ok(());}
+        if let (Some(recorder), Some(msg)) = (&feed.recorder, &msg) {recorder.lock().unwrap().record(&feed.key, msg).map_err(|e| AppError::RecordingFailed(e.to_string()))?;}
+        let frames = match impaired.as_mut() {Some(impaired) => impaired.process(msg)?, None => msg.into_iter().collect()};
+
+        for msg in frames {
+            let Message::Text(text) = msg else {continue};
+            let value: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+            // Public streams answer a ping with `"op": "ping", "ret_msg": "pong"`, other streams with `"op": "pong"`.
+            if value["op"] == "ping" || value["op"] == "pong" {keepalive.pong(); continue;}
+            if value["success"] == false {return Err(AppError::ConnectionFailed(format!("Bybit rejected {}: {}", value["op"], value["ret_msg"])));}
+            if value["topic"] != topic {continue;}
+            let message: OrderbookMessage = serde_json::from_value(value).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+
+            let mut books = VENUE_BOOKS.lock().unwrap();
+            let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+            if message.kind == "snapshot" {synced = true;}
+            if !apply(order_book, &message, synced)? {continue;}
+            log_crossing(&feed.key, order_book);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ordered_float::OrderedFloat;
+
+    fn message(kind: &str, u: u64, b: &[[&str; 2]], a: &[[&str; 2]]) -> OrderbookMessage {
+        let side = |levels: &[[&str; 2]]| levels.iter().map(|[price, size]| [price.to_string(), size.to_string()]).collect();
+        OrderbookMessage { topic: "orderbook.50.BTCUSDT".to_string(), kind: kind.to_string(), ts: 0, data: OrderbookData { s: "BTCUSDT".to_string(), b: side(b), a: side(a), u, seq: u } }
+    }
+
+    #[test]
+    fn deltas_continue_from_the_snapshot() {
+        let mut book = OrderBook::default();
+        assert!(!apply(&mut book, &message("delta", 7, &[["100", "1"]], &[]), false).unwrap());
+        assert!(apply(&mut book, &message("snapshot", 10, &[["100", "1"], ["99", "2"]], &[["101", "1"]]), true).unwrap());
+        assert!(apply(&mut book, &message("delta", 11, &[["100", "0"]], &[["100.5", "3"]]), true).unwrap());
+        assert!(!apply(&mut book, &message("delta", 11, &[["98", "1"]], &[]), true).unwrap());
+        assert_eq!((book.best_bid().unwrap().price, book.best_ask().unwrap().price, book.last_update_id), (OrderedFloat(99.0), OrderedFloat(100.5), Some(11)));
+    }
+
+    #[test]
+    fn a_skipped_update_is_a_gap() {
+        let mut book = OrderBook::default();
+        apply(&mut book, &message("snapshot", 10, &[["100", "1"]], &[]), true).unwrap();
+        assert!(matches!(apply(&mut book, &message("delta", 12, &[["100", "2"]], &[]), true), Err(AppError::SequenceGap(_))));
+        assert_eq!(book.best_bid().unwrap().size, OrderedFloat(1.0));
+        // After a service restart Bybit starts again from a snapshot with `u` of 1.
+        assert!(apply(&mut book, &message("snapshot", 1, &[["100", "4"]], &[]), true).unwrap());
+        assert!(apply(&mut book, &message("delta", 2, &[], &[["101", "1"]]), true).unwrap());
+    }
+}
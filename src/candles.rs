@@ -0,0 +1,178 @@
+use std::{collections::{HashMap, VecDeque}, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::Path, sync::{Arc, Mutex}, time::Duration};
+use chrono::Utc;
+use tokio::sync::broadcast;
+use crate::{AppError, orderbook::{Candle, TradeEvent}};
+
+/// Intervals built unless `EXCHANGE_CANDLE_INTERVALS` lists others.
+pub const DEFAULT_INTERVALS: &str = "1s,1m,5m,1h";
+/// Closed candles kept in memory per series for the `Candles` RPC.
+pub const HISTORY_LEN: usize = 1000;
+/// Closed candles are appended to this file in the candle directory, one JSON object per line.
+const CANDLES_FILE: &str = "candles.jsonl";
+/// How long after its interval ends a candle stays open for trades still in flight.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Parses an interval such as `1s`, `5m`, `1h` or `1d` into seconds.
+pub fn parse_interval(interval: &str) -> Result<u64, AppError> {
+    let interval = interval.trim();
+    // The unit is the last character, which need not be a single byte.
+    let (count, unit) = interval.split_at(interval.char_indices().last().map_or(0, |(i, _)| i));
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(AppError::ParsingFailed(format!("interval {}", interval))),
+    };
+    match count.parse::<u64>() {
+        Ok(count) if count > 0 => count.checked_mul(unit_secs).ok_or_else(|| AppError::ParsingFailed(format!("interval {}", interval))),
+        _ => Err(AppError::ParsingFailed(format!("interval {}", interval))),
+    }
+}
+
+/// One candle series: a venue, or every venue of the instrument when `exchange` is empty.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Series {
+    pub exchange: String,
+    pub instrument: String,
+    pub interval_secs: u64,
+}
+
+impl Series {
+    fn of(candle: &Candle) -> Series {Series { exchange: candle.exchange.clone(), instrument: candle.instrument.clone(), interval_secs: candle.interval_secs }}
+}
+
+/// Builds OHLCV candles of every configured interval from trades, per venue and across the venues of each instrument.
+/// Candles are bucketed by trade time. A trade for an interval that has already closed is dropped.
+pub struct CandleBuilder {
+    intervals: Vec<u64>,
+    open: HashMap<Series, Candle>,
+    history: HashMap<Series, VecDeque<Candle>>,
+    file: Option<File>,
+}
+
+impl CandleBuilder {
+    /// With `dir`, closed candles are persisted there and the history is reloaded from previous runs.
+    pub fn new(intervals: Vec<u64>, dir: Option<&Path>) -> Result<CandleBuilder, AppError> {
+        let mut builder = CandleBuilder { intervals, open: HashMap::new(), history: HashMap::new(), file: None };
+        let Some(dir) = dir else {return Ok(builder)};
+        let path = dir.join(CANDLES_FILE);
+        let error = |e: std::io::Error| AppError::RecordingFailed(format!("{}: {}", path.display(), e));
+        fs::create_dir_all(dir).map_err(error)?;
+        if path.exists() {
+            for line in BufReader::new(File::open(&path).map_err(error)?).lines() {
+                let line = line.map_err(error)?;
+                let candle: Candle = serde_json::from_str(&line).map_err(|_| AppError::ParsingFailed(format!("{}: {}", path.display(), line)))?;
+                builder.remember(candle);
+            }
+        }
+        builder.file = Some(OpenOptions::new().create(true).append(true).open(&path).map_err(error)?);
+        Ok(builder)
+    }
+
+    pub fn intervals(&self) -> &[u64] {&self.intervals}
+
+    /// Adds a trade to the open candle of every series it belongs to, closing any the trade has moved past.
+    /// Returns every candle that changed, closed ones included.
+    pub fn add(&mut self, trade: &TradeEvent) -> Vec<Candle> {
+        let mut changed = Vec::new();
+        for interval_secs in self.intervals.clone() {
+            let open_time_micros = trade.timestamp_micros - trade.timestamp_micros.rem_euclid(interval_secs as i64 * 1_000_000);
+            for exchange in [trade.exchange.as_str(), ""] {
+                let series = Series { exchange: exchange.to_string(), instrument: trade.instrument.clone(), interval_secs };
+                if self.history.get(&series).and_then(|closed| closed.back()).is_some_and(|last| last.open_time_micros >= open_time_micros) {continue;}
+                match self.open.get(&series) {
+                    Some(candle) if candle.open_time_micros > open_time_micros => continue,
+                    Some(candle) if candle.open_time_micros < open_time_micros => changed.extend(self.close(&series)),
+                    _ => {}
+                }
+                let candle = self.open.entry(series).or_insert_with(|| Candle {
+                    exchange: exchange.to_string(),
+                    instrument: trade.instrument.clone(),
+                    interval_secs,
+                    open_time_micros,
+                    open: trade.price,
+                    high: trade.price,
+                    low: trade.price,
+                    ..Default::default()
+                });
+                candle.high = candle.high.max(trade.price);
+                candle.low = candle.low.min(trade.price);
+                candle.close = trade.price;
+                candle.volume += trade.amount;
+                candle.trade_count += 1;
+                changed.push(candle.clone());
+            }
+        }
+        changed
+    }
+
+    /// Closes every candle whose interval ended at or before `cutoff_micros`.
+    pub fn close_expired(&mut self, cutoff_micros: i64) -> Vec<Candle> {
+        let expired: Vec<Series> = self.open.values()
+            .filter(|candle| candle.open_time_micros + candle.interval_secs as i64 * 1_000_000 <= cutoff_micros)
+            .map(Series::of)
+            .collect();
+        expired.iter().filter_map(|series| self.close(series)).collect()
+    }
+
+    fn close(&mut self, series: &Series) -> Option<Candle> {
+        let mut candle = self.open.remove(series)?;
+        candle.closed = true;
+        if let Some(file) = self.file.as_mut() {
+            let line = serde_json::to_string(&candle).expect("Candle serializes");
+            if let Err(e) = writeln!(file, "{}", line) {eprintln!("Failed to persist candle: {}", e);}
+        }
+        self.remember(candle.clone());
+        Some(candle)
+    }
+
+    fn remember(&mut self, candle: Candle) {
+        let closed = self.history.entry(Series::of(&candle)).or_default();
+        closed.push_back(candle);
+        if closed.len() > HISTORY_LEN {closed.pop_front();}
+    }
+
+    /// The closed candles of `series`, oldest first, followed by the open one if any.
+    pub fn history(&self, series: &Series) -> Vec<Candle> {
+        let closed = self.history.get(series).into_iter().flatten();
+        closed.chain(self.open.get(series)).cloned().collect()
+    }
+}
+
+/// Feeds `trades` into `builder`, closes candles once their interval has passed, and publishes every changed candle to `updates`.
+pub async fn run(builder: Arc<Mutex<CandleBuilder>>, mut trades: broadcast::Receiver<TradeEvent>, updates: broadcast::Sender<Candle>) {
+    let mut ticker = tokio::time::interval(CLOSE_CHECK_INTERVAL);
+    loop {
+        let changed = tokio::select! {
+            trade = trades.recv() => match trade {
+                Ok(trade) => builder.lock().unwrap().add(&trade),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {eprintln!("Candle builder skipped {} trades", skipped); continue}
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = ticker.tick() => builder.lock().unwrap().close_expired(Utc::now().timestamp_micros() - CLOSE_GRACE.as_micros() as i64),
+        };
+        for candle in changed {let _ = updates.send(candle);}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_parse() {
+        assert_eq!(parse_interval("1s").unwrap(), 1);
+        assert_eq!(parse_interval(" 5m ").unwrap(), 300);
+        assert_eq!(parse_interval("1h").unwrap(), 3600);
+        assert_eq!(parse_interval("2d").unwrap(), 172_800);
+    }
+
+    #[test]
+    fn malformed_intervals_are_rejected() {
+        for interval in ["", " ", "m", "1", "0m", "-1m", "1.5m", "1w", "1M", "m1", "1é", "é", "10ms", "99999999999999999999d", "18446744073709551615d"] {
+            assert!(parse_interval(interval).is_err(), "{:?} parsed", interval);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::{cmp::Reverse, time::{Duration, Instant}};
+use chrono::DateTime;
+use ordered_float::OrderedFloat;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tungstenite::{connect, Message};
+use url::Url;
+use crate::{AppError, FeedConfig, VENUE_BOOKS, TRADES, log_crossing, models::{parse_decimal, BookKey, LimitPrice, OrderBook, OrderType, Sequence}, orderbook::TradeEvent, impairment};
+
+/// How long a book is maintained from `l2update`s alone before it is replaced by a fresh snapshot.
+pub const RESNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Match {
+    /// Numbered consecutively per product.
+    pub trade_id: u64,
+    pub sequence: u64,
+    pub maker_order_id: String,
+    pub taker_order_id: String,
+    pub time: String,
+    pub product_id: String,
+    pub size: String,
+    pub price: String,
+    /// Side of the maker order.
+    pub side: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedMessage {
+    Snapshot { product_id: String, bids: Vec<[String; 2]>, asks: Vec<[String; 2]> },
+    /// `changes` are `[side, price, size]`; a zero size removes the level.
+    L2update { product_id: String, time: String, changes: Vec<[String; 3]> },
+    Match(Match),
+    /// The last trade before the subscription, sent once so the trade sequence can be checked from the start.
+    LastMatch(Match),
+    Heartbeat { sequence: u64, last_trade_id: u64, product_id: String, time: String },
+    Error { message: String, #[serde(default)] reason: String },
+    /// `subscriptions` acknowledgements and anything else this connector does not use.
+    #[serde(other)]
+    Other,
+}
+
+/// `level2_batch` carries the same snapshot and l2update messages as `level2` in 50 ms batches and needs no authentication.
+pub fn subscribe_request(product_id: &str, channels: &[String]) -> String {json!({"type": "subscribe", "product_ids": [product_id], "channels": channels}).to_string()}
+
+pub fn unsubscribe_request(product_id: &str, channel: &str) -> String {json!({"type": "unsubscribe", "product_ids": [product_id], "channels": [channel]}).to_string()}
+
+pub fn snapshot_side(levels: &[[String; 2]], is_bid: bool) -> Result<Vec<LimitPrice>, AppError> {
+    let mut side = levels.iter()
+        .map(|[price, size]| Ok(LimitPrice { price: OrderedFloat(parse_decimal(price)?), size: OrderedFloat(parse_decimal(size)?), orders: Vec::new() }))
+        .collect::<Result<Vec<LimitPrice>, AppError>>()?;
+    if is_bid {side.sort_by_key(|level| Reverse(level.price));} else {side.sort_by_key(|level| level.price);}
+    Ok(side)
+}
+
+/// Coinbase reports the maker's side; the normalized event carries the taker's.
+pub fn trade_event(key: &BookKey, m: &Match) -> Result<TradeEvent, AppError> {
+    let time = DateTime::parse_from_rfc3339(&m.time).map_err(|_| AppError::ParsingFailed(m.time.clone()))?;
+    Ok(TradeEvent {
+        exchange: key.exchange.clone(),
+        price: parse_decimal(&m.price)?,
+        amount: parse_decimal(&m.size)?,
+        side: if m.side == "sell" {"buy"} else {"sell"}.to_string(),
+        trade_id: m.trade_id,
+        timestamp_micros: time.timestamp_micros(),
+        instrument: key.instrument.clone(),
+    })
+}
+
+/// Per-connection state of the Coinbase feed.
+struct Session {
+    last_trade_id: Option<u64>,
+    /// When this connection received its snapshot; until then the book may hold levels from before it.
+    snapshot_at: Option<Instant>,
+}
+
+impl Session {
+    fn new() -> Session {Session { last_trade_id: None, snapshot_at: None }}
+
+    /// Applies one message to `order_book` and returns whether the book needs a fresh snapshot. A trade
+    /// gap is an error.
+    fn handle(&mut self, key: &BookKey, order_book: &mut OrderBook, message: FeedMessage, now: Instant) -> Result<bool, AppError> {
+        match message {
+            FeedMessage::Snapshot { bids, asks, .. } => {
+                order_book.bids = snapshot_side(&bids, true)?;
+                order_book.asks = snapshot_side(&asks, false)?;
+                order_book.touch(None);
+                self.snapshot_at = Some(now);
+                log_crossing(key, order_book);
+            }
+            FeedMessage::L2update { changes, .. } if self.snapshot_at.is_some() => {
+                for [side, price, size] in &changes {
+                    let order_type = if side == "buy" {OrderType::Buy} else {OrderType::Sell};
+                    order_book.set_level(order_type as u8, parse_decimal(price)?, parse_decimal(size)?);
+                }
+                order_book.touch(None);
+                log_crossing(key, order_book);
+            }
+            FeedMessage::LastMatch(m) => self.last_trade_id = Some(m.trade_id),
+            FeedMessage::Match(m) => {
+                match Sequence::classify(self.last_trade_id, m.trade_id, true) {
+                    Sequence::Outdated => return Ok(false),
+                    Sequence::Gap { expected, received } => return Err(AppError::SequenceGap(format!("{} expected trade {} but received {}", key, expected, received))),
+                    Sequence::Next => {}
+                }
+                self.last_trade_id = Some(m.trade_id);
+                if self.snapshot_at.is_some() {order_book.touch(Some(m.sequence));}
+                let _ = TRADES.send(trade_event(key, &m)?);
+            }
+            FeedMessage::Heartbeat { sequence, last_trade_id: latest, .. } => {
+                match self.last_trade_id {
+                    Some(last) if latest > last => return Err(AppError::SequenceGap(format!("{} heartbeat reports trade {} but the last received was {}", key, latest, last))),
+                    None => self.last_trade_id = Some(latest),
+                    _ => {}
+                }
+                let Some(snapshot_at) = self.snapshot_at else {return Ok(false)};
+                order_book.touch(Some(sequence));
+                if now.duration_since(snapshot_at) >= RESNAPSHOT_INTERVAL {
+                    self.snapshot_at = None;
+                    return Ok(true);
+                }
+            }
+            FeedMessage::Error { message, reason } => return Err(AppError::ConnectionFailed(format!("{} {}: {}", key, message, reason))),
+            _ => {}
+        }
+        Ok(false)
+    }
+}
+
+/// Maintains the Coinbase venue book from `level2_batch` and publishes `matches` to [`TRADES`]. Trade IDs
+/// are checked for gaps, including against the latest ID reported by heartbeats; a gap means frames
+/// were lost, so the connector stops and the supervisor rebuilds the book from a fresh snapshot. Level 2
+/// messages carry no sequence numbers, so a lost `l2update` cannot be detected; instead the book channel is
+/// resubscribed on the first heartbeat every [`RESNAPSHOT_INTERVAL`], and the fresh snapshot replaces the book.
+pub fn pull(feed: &FeedConfig) -> Result<(), AppError> {
+    let channel = feed.listing.channel()?;
+    let mut impaired = feed.impaired()?;
+    let (mut socket, _response) = connect(Url::parse(&feed.url)?).map_err(|_| AppError::ConnectionFailed(feed.key.to_string()))?;
+    match feed.book(&mut VENUE_BOOKS.lock().unwrap()) {Some(order_book) => order_book.resync(), None => return Ok(())}
+    socket.write_message(Message::Text(subscribe_request(&feed.listing.symbol, &feed.listing.channels))).map_err(|e| AppError::MessageError(e.to_string()))?;
+    let mut session = Session::new();
+
+    loop {
+        let msg = impairment::read(&mut socket, impaired.as_ref())?;
+        if feed.stopped() {return Ok(());}
+        if let (Some(recorder), Some(msg)) = (&feed.recorder, &msg) {recorder.lock().unwrap().record(&feed.key, msg).map_err(|e| AppError::RecordingFailed(e.to_string()))?;}
+        let frames = match impaired.as_mut() {Some(impaired) => impaired.process(msg)?, None => msg.into_iter().collect()};
+
+        for msg in frames {
+            let Message::Text(text) = msg else {continue};
+            let message: FeedMessage = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+            let mut books = VENUE_BOOKS.lock().unwrap();
+            let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+            let resnapshot = session.handle(&feed.key, order_book, message, Instant::now())?;
+            drop(books);
+            if resnapshot {
+                for request in [unsubscribe_request(&feed.listing.symbol, channel), subscribe_request(&feed.listing.symbol, &[channel.to_string()])] {
+                    socket.write_message(Message::Text(request)).map_err(|e| AppError::MessageError(e.to_string()))?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> FeedMessage {serde_json::from_str(text).unwrap()}
+
+    fn heartbeat(last_trade_id: u64) -> FeedMessage {
+        message(&format!(r#"{{"type":"heartbeat","sequence":90,"last_trade_id":{},"product_id":"BTC-USD","time":"2024-05-01T12:00:00.000000Z"}}"#, last_trade_id))
+    }
+
+    fn trade(trade_id: u64) -> FeedMessage {
+        message(&format!(r#"{{"type":"match","trade_id":{},"sequence":91,"maker_order_id":"a","taker_order_id":"b","time":"2024-05-01T12:00:00.000000Z","product_id":"BTC-USD","size":"0.1","price":"64000.00","side":"sell"}}"#, trade_id))
+    }
+
+    const SNAPSHOT: &str = r#"{"type":"snapshot","product_id":"BTC-USD","bids":[["63999.00","1.0"],["64000.00","0.5"]],"asks":[["64001.00","0.7"]]}"#;
+    const UPDATE: &str = r#"{"type":"l2update","product_id":"BTC-USD","time":"2024-05-01T12:00:00.000000Z","changes":[["buy","64000.00","0"],["sell","64000.50","0.2"]]}"#;
+
+    fn key() -> BookKey {BookKey::new("Coinbase", "BTC/USD")}
+
+    #[test]
+    fn updates_apply_only_after_the_snapshot() {
+        let (mut session, mut book, now) = (Session::new(), OrderBook::default(), Instant::now());
+        assert!(!session.handle(&key(), &mut book, message(UPDATE), now).unwrap());
+        assert!(book.asks.is_empty());
+        session.handle(&key(), &mut book, message(SNAPSHOT), now).unwrap();
+        assert_eq!(book.best_bid().unwrap().price, OrderedFloat(64000.0));
+        session.handle(&key(), &mut book, message(UPDATE), now).unwrap();
+        assert_eq!((book.best_bid().unwrap().price, book.best_ask().unwrap().price), (OrderedFloat(63999.0), OrderedFloat(64000.5)));
+    }
+
+    #[test]
+    fn trade_gaps_stop_the_connection() {
+        let (mut session, mut book, now) = (Session::new(), OrderBook::default(), Instant::now());
+        session.handle(&key(), &mut book, heartbeat(10), now).unwrap();
+        session.handle(&key(), &mut book, trade(11), now).unwrap();
+        session.handle(&key(), &mut book, trade(11), now).unwrap();
+        assert!(matches!(session.handle(&key(), &mut book, trade(13), now), Err(AppError::SequenceGap(_))));
+        assert!(matches!(session.handle(&key(), &mut book, heartbeat(12), now), Err(AppError::SequenceGap(_))));
+    }
+
+    #[test]
+    fn a_heartbeat_after_the_interval_asks_for_a_fresh_snapshot() {
+        let (mut session, mut book, now) = (Session::new(), OrderBook::default(), Instant::now());
+        session.handle(&key(), &mut book, message(SNAPSHOT), now).unwrap();
+        assert!(!session.handle(&key(), &mut book, heartbeat(0), now + RESNAPSHOT_INTERVAL / 2).unwrap());
+        assert!(session.handle(&key(), &mut book, heartbeat(0), now + RESNAPSHOT_INTERVAL).unwrap());
+
+        // Updates ahead of the new snapshot are already part of it and are dropped; the old levels stay meanwhile.
+        session.handle(&key(), &mut book, message(UPDATE), now + RESNAPSHOT_INTERVAL).unwrap();
+        assert_eq!(book.best_bid().unwrap().price, OrderedFloat(64000.0));
+        assert!(!session.handle(&key(), &mut book, heartbeat(0), now + RESNAPSHOT_INTERVAL * 2).unwrap());
+        session.handle(&key(), &mut book, message(SNAPSHOT), now + RESNAPSHOT_INTERVAL * 2).unwrap();
+        session.handle(&key(), &mut book, message(UPDATE), now + RESNAPSHOT_INTERVAL * 2).unwrap();
+        assert_eq!(book.best_bid().unwrap().price, OrderedFloat(63999.0));
+    }
+}
@@ -0,0 +1,406 @@
+use std::{collections::{BTreeMap, HashSet}, env, fs, net::SocketAddr, path::{Path, PathBuf}, str::FromStr, time::Duration};
+use serde::Deserialize;
+use url::Url;
+use crate::{AppError, EXCHANGES, venue, aggregator, analytics, candles, snapshots, spreads, recorder, instruments::Registry};
+use crate::{DEFAULT_LISTEN_ADDR, DEFAULT_DISPLAY_DEPTH, DEFAULT_STALE_AFTER, RECORD_DIR_ENV, FEES_FILE_ENV, FX_FILE_ENV, INSTRUMENTS_FILE_ENV, DISPLAY_DEPTH_ENV, DISPLAY_GROUPING_ENV,
+    CANDLE_INTERVALS_ENV, CANDLES_DIR_ENV, SPREAD_SAMPLE_MS_ENV, SPREADS_DIR_ENV, SNAPSHOT_DIR_ENV, SNAPSHOT_INTERVAL_MS_ENV, WARM_START_ENV, SQLITE_PATH_ENV, STALE_AFTER_ENV};
+
+/// Everything the aggregator can be configured with, read from a TOML file. Every section and key is optional, and
+/// the environment variables of the same settings take precedence over the file.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    /// Keyed by exchange name. Exchanges left out are enabled with their public endpoint.
+    pub exchanges: BTreeMap<String, Exchange>,
+    /// Replaces the built-in registry, in the format of `EXCHANGE_INSTRUMENTS_FILE`.
+    pub instruments: Option<Registry>,
+    pub recording: Recording,
+    pub analytics: Analytics,
+    pub display: Display,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    /// Address of the gRPC server, 127.0.0.1:50051 by default.
+    pub listen: Option<SocketAddr>,
+    pub tls: Option<Tls>,
+}
+
+/// PEM files of the gRPC server's certificate chain and private key.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Exchange {
+    /// A disabled exchange's listings are not followed.
+    pub enabled: bool,
+    /// WebSocket endpoint replacing the public one.
+    pub url: Option<String>,
+    /// Credentials for private channels. The public market data feeds need none. A value of the form `${NAME}` is
+    /// read from the environment variable `NAME`, so secrets stay out of the file.
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+    pub passphrase: Option<String>,
+}
+
+impl Default for Exchange {
+    fn default() -> Exchange {Exchange { enabled: true, url: None, api_key: None, api_secret: None, passphrase: None }}
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Recording {
+    /// Directory to record every received frame into.
+    pub dir: Option<PathBuf>,
+    /// Size in MiB of uncompressed frames after which the recorder rolls over to a new file.
+    pub max_file_mb: Option<u64>,
+    pub snapshot_dir: Option<PathBuf>,
+    pub snapshot_interval_ms: Option<u64>,
+    /// Restore the books from the last snapshot on startup.
+    pub warm_start: bool,
+    pub sqlite_path: Option<PathBuf>,
+    pub candles_dir: Option<PathBuf>,
+    pub spreads_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Analytics {
+    pub fees_file: Option<PathBuf>,
+    pub fx_file: Option<PathBuf>,
+    /// e.g. `["1m", "15m", "1d"]`.
+    pub candle_intervals: Option<Vec<String>>,
+    pub spread_sample_ms: Option<u64>,
+    pub stale_after_ms: Option<u64>,
+    /// Defaults of the `Analytics` RPC for requests that set none.
+    pub imbalance_levels: Option<usize>,
+    pub depth_bps: Option<f64>,
+    pub vwap_amount: Option<f64>,
+}
+
+/// The ladder of the terminal UI.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Display {
+    pub depth: Option<usize>,
+    /// `raw`, a bucket size such as `10`, or a percentage of the mid such as `0.1%`.
+    pub grouping: Option<String>,
+}
+
+fn invalid(message: String) -> AppError {AppError::ConfigInvalid(message)}
+
+/// The environment variable `name`, parsed, if it is set.
+fn env_value<T: FromStr>(name: &str) -> Result<Option<T>, AppError> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| invalid(format!("{}={} is not a valid value", name, value))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn positive<T: PartialOrd + Default + Copy + std::fmt::Display>(name: &str, value: Option<T>) -> Result<(), AppError> {
+    match value {
+        Some(value) if value <= T::default() => Err(invalid(format!("{} must be positive, not {}", name, value))),
+        _ => Ok(()),
+    }
+}
+
+/// Like [`positive`], for settings that may not be NaN or infinite either.
+fn positive_finite(name: &str, value: Option<f64>) -> Result<(), AppError> {
+    match value {
+        Some(value) if !(value > 0.0 && value.is_finite()) => Err(invalid(format!("{} must be a positive number, not {}", name, value))),
+        _ => Ok(()),
+    }
+}
+
+/// Replaces a `${NAME}` placeholder with the environment variable `NAME`.
+fn resolve_placeholder(setting: &str, value: &mut Option<String>) -> Result<(), AppError> {
+    let Some(name) = value.as_deref().and_then(|value| value.strip_prefix("${")).and_then(|value| value.strip_suffix('}')) else {return Ok(())};
+    let resolved = env::var(name).map_err(|_| invalid(format!("{} refers to {}, which is not set", setting, name)))?;
+    *value = Some(resolved);
+    Ok(())
+}
+
+impl Config {
+    /// Reads the file at `path`, or starts from the defaults without one, applies the environment variables and
+    /// validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Config, AppError> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+                toml::from_str(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), AppError> {
+        for exchange in EXCHANGES {
+            let (_, _, url_env) = venue(exchange).expect("every listed exchange has a connector");
+            if let Ok(url) = env::var(url_env) {self.exchanges.entry(exchange.to_string()).or_default().url = Some(url);}
+        }
+        if let Some(path) = env_value::<PathBuf>(INSTRUMENTS_FILE_ENV)? {self.instruments = Some(Registry::load(&path)?);}
+
+        let recording = &mut self.recording;
+        recording.dir = env_value(RECORD_DIR_ENV)?.or(recording.dir.take());
+        recording.snapshot_dir = env_value(SNAPSHOT_DIR_ENV)?.or(recording.snapshot_dir.take());
+        recording.snapshot_interval_ms = env_value(SNAPSHOT_INTERVAL_MS_ENV)?.or(recording.snapshot_interval_ms);
+        recording.warm_start |= env::var(WARM_START_ENV).is_ok();
+        recording.sqlite_path = env_value(SQLITE_PATH_ENV)?.or(recording.sqlite_path.take());
+        recording.candles_dir = env_value(CANDLES_DIR_ENV)?.or(recording.candles_dir.take());
+        recording.spreads_dir = env_value(SPREADS_DIR_ENV)?.or(recording.spreads_dir.take());
+
+        let analytics = &mut self.analytics;
+        analytics.fees_file = env_value(FEES_FILE_ENV)?.or(analytics.fees_file.take());
+        analytics.fx_file = env_value(FX_FILE_ENV)?.or(analytics.fx_file.take());
+        if let Ok(intervals) = env::var(CANDLE_INTERVALS_ENV) {analytics.candle_intervals = Some(intervals.split(',').map(str::to_string).collect());}
+        analytics.spread_sample_ms = env_value(SPREAD_SAMPLE_MS_ENV)?.or(analytics.spread_sample_ms);
+        analytics.stale_after_ms = env_value(STALE_AFTER_ENV)?.or(analytics.stale_after_ms);
+
+        self.display.depth = env_value(DISPLAY_DEPTH_ENV)?.or(self.display.depth);
+        self.display.grouping = env_value(DISPLAY_GROUPING_ENV)?.or(self.display.grouping.take());
+        Ok(())
+    }
+
+    fn validate(&mut self) -> Result<(), AppError> {
+        if let Some(tls) = &self.server.tls {
+            for (setting, path) in [("server.tls.cert", &tls.cert), ("server.tls.key", &tls.key)] {
+                if !path.is_file() {return Err(invalid(format!("{} {} is not a file", setting, path.display())));}
+            }
+        }
+
+        for (name, exchange) in &mut self.exchanges {
+            if venue(name).is_none() {return Err(invalid(format!("unsupported exchange {}; supported are {}", name, EXCHANGES.join(", "))));}
+            if let Some(url) = &exchange.url {
+                let parsed = Url::parse(url).map_err(|e| invalid(format!("exchanges.{}.url {}: {}", name, url, e)))?;
+                if !matches!(parsed.scheme(), "ws" | "wss") {return Err(invalid(format!("exchanges.{}.url {} is not a ws:// or wss:// URL", name, url)));}
+            }
+            resolve_placeholder(&format!("exchanges.{}.api_key", name), &mut exchange.api_key)?;
+            resolve_placeholder(&format!("exchanges.{}.api_secret", name), &mut exchange.api_secret)?;
+            resolve_placeholder(&format!("exchanges.{}.passphrase", name), &mut exchange.passphrase)?;
+        }
+
+        if let Some(registry) = &self.instruments {
+            let mut names = HashSet::new();
+            for instrument in &registry.instruments {
+                let setting = format!("instrument {}", instrument.name);
+                if instrument.name.is_empty() {return Err(invalid("an instrument has no name".to_string()));}
+                if !names.insert(&instrument.name) {return Err(invalid(format!("{} is listed twice", setting)));}
+                positive_finite(&format!("{} tick_size", setting), Some(instrument.tick_size))?;
+                positive_finite(&format!("{} lot_size", setting), Some(instrument.lot_size))?;
+                if instrument.venues.is_empty() {return Err(invalid(format!("{} has no venues", setting)));}
+                for (exchange, listing) in &instrument.venues {
+                    let setting = format!("{} on {}", setting, exchange);
+                    if venue(exchange).is_none() {return Err(invalid(format!("{} is listed on unsupported exchange {}", instrument.name, exchange)));}
+                    if listing.symbol.is_empty() {return Err(invalid(format!("{} has no symbol", setting)));}
+                    if listing.channels.is_empty() {return Err(invalid(format!("{} has no channels", setting)));}
+                    positive_finite(&format!("{} tick_size", setting), listing.tick_size)?;
+                    positive_finite(&format!("{} lot_size", setting), listing.lot_size)?;
+                    positive_finite(&format!("{} contract_size", setting), listing.contract_size)?;
+                }
+            }
+        }
+        if self.registry().instruments.is_empty() {return Err(invalid("no instrument is listed on an enabled exchange".to_string()));}
+
+        positive("recording.max_file_mb", self.recording.max_file_mb)?;
+        self.max_file_bytes()?;
+        positive("recording.snapshot_interval_ms", self.recording.snapshot_interval_ms)?;
+        if self.recording.warm_start && self.recording.snapshot_dir.is_none() {return Err(invalid(format!("warm start needs recording.snapshot_dir or {}", SNAPSHOT_DIR_ENV)));}
+
+        self.candle_intervals()?;
+        positive("analytics.spread_sample_ms", self.analytics.spread_sample_ms)?;
+        positive("analytics.stale_after_ms", self.analytics.stale_after_ms)?;
+        positive("analytics.imbalance_levels", self.analytics.imbalance_levels)?;
+        positive_finite("analytics.depth_bps", self.analytics.depth_bps)?;
+        positive_finite("analytics.vwap_amount", self.analytics.vwap_amount)?;
+
+        positive("display.depth", self.display.depth)?;
+        self.display()?;
+        Ok(())
+    }
+
+    pub fn listen(&self) -> SocketAddr {self.server.listen.unwrap_or(DEFAULT_LISTEN_ADDR)}
+
+    pub fn exchange(&self, name: &str) -> Exchange {self.exchanges.get(name).cloned().unwrap_or_default()}
+
+    /// The configured or built-in instruments, without the listings on disabled exchanges.
+    pub fn registry(&self) -> Registry {
+        let mut registry = self.instruments.clone().unwrap_or_default();
+        for instrument in &mut registry.instruments {instrument.venues.retain(|exchange, _| self.exchange(exchange).enabled);}
+        registry.instruments.retain(|instrument| !instrument.venues.is_empty());
+        registry
+    }
+
+    pub fn max_file_bytes(&self) -> Result<u64, AppError> {
+        match self.recording.max_file_mb {
+            Some(mb) => mb.checked_mul(1024 * 1024).ok_or_else(|| invalid(format!("recording.max_file_mb {} is too large", mb))),
+            None => Ok(recorder::DEFAULT_MAX_FILE_BYTES),
+        }
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {self.recording.snapshot_interval_ms.map_or(snapshots::DEFAULT_INTERVAL, Duration::from_millis)}
+
+    pub fn candle_intervals(&self) -> Result<Vec<u64>, AppError> {
+        let intervals = match &self.analytics.candle_intervals {
+            Some(intervals) => intervals.iter().map(|interval| candles::parse_interval(interval)).collect::<Result<Vec<u64>, AppError>>(),
+            None => candles::DEFAULT_INTERVALS.split(',').map(candles::parse_interval).collect(),
+        };
+        intervals.map_err(|e| invalid(format!("analytics.candle_intervals: {}", e)))
+    }
+
+    pub fn sample_interval(&self) -> Duration {self.analytics.spread_sample_ms.map_or(spreads::DEFAULT_SAMPLE_INTERVAL, Duration::from_millis)}
+
+    pub fn stale_after(&self) -> Duration {self.analytics.stale_after_ms.map_or(DEFAULT_STALE_AFTER, Duration::from_millis)}
+
+    pub fn analytics(&self) -> analytics::Parameters {
+        let defaults = analytics::Parameters::default();
+        analytics::Parameters {
+            imbalance_levels: self.analytics.imbalance_levels.unwrap_or(defaults.imbalance_levels),
+            depth_bps: self.analytics.depth_bps.unwrap_or(defaults.depth_bps),
+            vwap_amount: self.analytics.vwap_amount.unwrap_or(defaults.vwap_amount),
+        }
+    }
+
+    pub fn display(&self) -> Result<aggregator::View, AppError> {
+        let grouping = self.display.grouping.as_deref().unwrap_or_default().parse().map_err(|e| invalid(format!("display.grouping: {}", e)))?;
+        Ok(aggregator::View { depth: self.display.depth.unwrap_or(DEFAULT_DISPLAY_DEPTH), grouping })
+    }
+
+    /// Levels per side of `BookSummary` responses to requests that set no depth: `[display] depth`, or 10.
+    pub fn summary_depth(&self) -> usize {self.display.depth.unwrap_or(aggregator::SUMMARY_DEPTH)}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, atomic::{AtomicUsize, Ordering}};
+    use crate::{KRAKEN_URL_ENV, aggregator::Grouping};
+
+    /// Held by every test that loads a configuration, since loading reads the environment the others change.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn load(toml: &str) -> Result<Config, AppError> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!("config-test-{}-{}.toml", std::process::id(), FILES.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&path, toml).unwrap();
+        let config = Config::load(Some(&path));
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    fn error(toml: &str) -> String {
+        match load(toml) {
+            Err(AppError::ConfigInvalid(message)) => message,
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_file_sets_every_section() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = load(r#"
+            [server]
+            listen = "0.0.0.0:6000"
+
+            [exchanges.Bybit]
+            enabled = false
+
+            [exchanges.Kraken]
+            url = "wss://kraken.example/v2"
+
+            [recording]
+            max_file_mb = 16
+            snapshot_dir = "snapshots"
+            warm_start = true
+
+            [analytics]
+            candle_intervals = ["1m", "1h"]
+            depth_bps = 25.0
+
+            [display]
+            depth = 5
+            grouping = "0.1%"
+        "#).unwrap();
+        assert_eq!(config.listen(), "0.0.0.0:6000".parse().unwrap());
+        assert_eq!(config.exchange("Kraken").url.as_deref(), Some("wss://kraken.example/v2"));
+        assert!(config.exchange("Binance").enabled && !config.exchange("Bybit").enabled);
+        let perpetual = config.registry().get("BTC/USDT-PERP").unwrap().venues.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(perpetual, ["OKX"]);
+        assert_eq!(config.max_file_bytes().unwrap(), 16 * 1024 * 1024);
+        assert!(config.recording.warm_start);
+        assert_eq!(config.candle_intervals().unwrap(), [60, 3_600]);
+        assert_eq!(config.analytics().depth_bps, 25.0);
+        let view = config.display().unwrap();
+        assert_eq!((view.depth, view.grouping), (5, Grouping::new(0.0, 0.1).unwrap()));
+        assert_eq!(config.summary_depth(), 5);
+    }
+
+    #[test]
+    fn invalid_settings_are_named_in_the_error() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (toml, expected) in [
+            ("[server]\nport = 1", "unknown field `port`"),
+            ("[exchanges.Gemini]", "unsupported exchange Gemini"),
+            ("[exchanges.Kraken]\nurl = \"https://kraken.example\"", "exchanges.Kraken.url https://kraken.example is not a ws:// or wss:// URL"),
+            ("[recording]\nwarm_start = true", "warm start needs recording.snapshot_dir"),
+            ("[recording]\nmax_file_mb = 0", "recording.max_file_mb must be positive, not 0"),
+            ("[recording]\nmax_file_mb = 9223372036854775807", "recording.max_file_mb 9223372036854775807 is too large"),
+            ("[analytics]\nstale_after_ms = 0", "analytics.stale_after_ms must be positive"),
+            ("[analytics]\ndepth_bps = nan", "analytics.depth_bps must be a positive number, not NaN"),
+            ("[analytics]\nvwap_amount = inf", "analytics.vwap_amount must be a positive number, not inf"),
+            ("[analytics]\ncandle_intervals = [\"7x\"]", "analytics.candle_intervals"),
+            ("[display]\ngrouping = \"wide\"", "display.grouping"),
+            ("[exchanges]\nBinance.enabled = false\nBitstamp.enabled = false\nKraken.enabled = false\nCoinbase.enabled = false\nOKX.enabled = false\nBybit.enabled = false", "no instrument is listed on an enabled exchange"),
+            ("[[instruments]]\nname = \"ETH/USD\"\nbase = \"ETH\"\nquote = \"USD\"\ntick_size = 0.0\nlot_size = 0.001\nvenues.Kraken = { symbol = \"ETH/USD\", channels = [\"book\"] }", "instrument ETH/USD tick_size must be a positive number, not 0"),
+            ("[[instruments]]\nname = \"ETH/USD\"\nbase = \"ETH\"\nquote = \"USD\"\ntick_size = 0.01\nlot_size = 0.001\nvenues.Kraken = { symbol = \"ETH/USD\", channels = [] }", "instrument ETH/USD on Kraken has no channels"),
+        ] {
+            let message = error(toml);
+            assert!(message.contains(expected), "{:?} gave {:?}", toml, message);
+        }
+    }
+
+    #[test]
+    fn the_environment_takes_precedence_over_the_file() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let file = "[exchanges.Kraken]\nurl = \"wss://file.example\"\n[analytics]\nspread_sample_ms = 500\n[display]\ndepth = 5";
+        env::set_var(KRAKEN_URL_ENV, "wss://env.example");
+        env::set_var(DISPLAY_DEPTH_ENV, "7");
+        env::set_var(CANDLE_INTERVALS_ENV, "5m,1d");
+        let config = load(file);
+        for name in [KRAKEN_URL_ENV, DISPLAY_DEPTH_ENV, CANDLE_INTERVALS_ENV] {env::remove_var(name);}
+        let config = config.unwrap();
+        assert_eq!(config.exchange("Kraken").url.as_deref(), Some("wss://env.example"));
+        assert_eq!(config.display.depth, Some(7));
+        assert_eq!(config.candle_intervals().unwrap(), [300, 86_400]);
+        // What the environment leaves unset comes from the file.
+        assert_eq!(config.analytics.spread_sample_ms, Some(500));
+
+        // A variable that does not parse is an invalid configuration like a bad file.
+        env::set_var(SPREAD_SAMPLE_MS_ENV, "soon");
+        let message = error(file);
+        env::remove_var(SPREAD_SAMPLE_MS_ENV);
+        assert_eq!(message, format!("{}=soon is not a valid value", SPREAD_SAMPLE_MS_ENV));
+    }
+
+    #[test]
+    fn credential_placeholders_are_read_from_the_environment() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let file = "[exchanges.Coinbase]\napi_key = \"${CONFIG_TEST_KEY}\"\napi_secret = \"plain\"";
+        env::set_var("CONFIG_TEST_KEY", "from-env");
+        let config = load(file);
+        env::remove_var("CONFIG_TEST_KEY");
+        let coinbase = config.unwrap().exchange("Coinbase");
+        assert_eq!((coinbase.api_key.as_deref(), coinbase.api_secret.as_deref()), (Some("from-env"), Some("plain")));
+
+        assert_eq!(error(file), "exchanges.Coinbase.api_key refers to CONFIG_TEST_KEY, which is not set");
+    }
+}
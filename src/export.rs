@@ -0,0 +1,638 @@
+use std::{collections::{BTreeMap, HashMap, hash_map::Entry}, fs::{self, File}, path::PathBuf, sync::Arc};
+use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use chrono::DateTime;
+use clap::{Args, ValueEnum};
+use ordered_float::OrderedFloat;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties, format::KeyValue};
+use crate::{AppError, bitstamp_trade, bybit, coinbase, kraken, okx, instruments::{decimals, Instrument, Listing, Registry}, models::{parse_decimal, BookKey, Data, DepthStreamData, LimitPrice, Msg, Order, OrderBook, OrderType}, orderbook::TradeEvent, recorder};
+
+/// Rows buffered per table before they are written out.
+const BATCH_ROWS: usize = 65_536;
+
+/// What to export from a recorded session.
+#[derive(Args, Debug, Clone)]
+pub struct Options {
+    /// Directory of `frames-*.jsonl.gz` files written with `EXCHANGE_RECORD_DIR`, or a single such file.
+    #[arg(required_unless_present = "schema")]
+    pub input: Option<PathBuf>,
+    /// Directory to write one file per table and format into.
+    #[arg(long, default_value = "export")]
+    pub output: PathBuf,
+    #[arg(long, value_delimiter = ',', default_value = "csv,parquet")]
+    pub format: Vec<Format>,
+    #[arg(long, value_delimiter = ',', default_value = "book,l2,l3,trades")]
+    pub tables: Vec<Table>,
+    /// Canonical instruments to export, e.g. `BTC/USD,BTC/USDT`. All by default.
+    #[arg(long, value_delimiter = ',')]
+    pub symbols: Vec<String>,
+    /// Exchanges to export. All by default.
+    #[arg(long, value_delimiter = ',')]
+    pub exchanges: Vec<String>,
+    /// Start of the range by receive time, inclusive: RFC 3339 or microseconds since the Unix epoch.
+    #[arg(long, value_parser = parse_time)]
+    pub from: Option<i64>,
+    /// End of the range by receive time, exclusive.
+    #[arg(long, value_parser = parse_time)]
+    pub to: Option<i64>,
+    /// Levels per side in the `book` table.
+    #[arg(long, default_value_t = 10)]
+    pub depth: usize,
+    /// Minimum time between two `book` rows of the same venue book. With 0, every change of the top levels is a row.
+    #[arg(long, default_value_t = 0)]
+    pub interval_ms: i64,
+    /// Print the columns of every table and exit.
+    #[arg(long)]
+    pub schema: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Table {
+    Book,
+    L2,
+    L3,
+    Trades,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Int,
+    Float,
+    Text,
+    Bool,
+}
+
+impl ColumnType {
+    fn data_type(self) -> DataType {
+        match self {
+            ColumnType::Int => DataType::Int64,
+            ColumnType::Float => DataType::Float64,
+            ColumnType::Text => DataType::Utf8,
+            ColumnType::Bool => DataType::Boolean,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnType,
+    pub description: String,
+}
+
+fn column(name: &str, kind: ColumnType, description: &str) -> Column {Column { name: name.to_string(), kind, description: description.to_string() }}
+
+/// A cell. Every column may be empty, which is `Null`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bool(bool),
+    Null,
+}
+
+impl Table {
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Book => "book",
+            Table::L2 => "l2",
+            Table::L3 => "l3",
+            Table::Trades => "trades",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Table::Book => "The top levels of a venue book, one row each time they change.",
+            Table::L2 => "Changes to the price levels of a venue book, one row per level and frame.",
+            Table::L3 => "Individual order events, from venues that publish them (Bitstamp).",
+            Table::Trades => "Trades, from venues whose feed carries them (Bitstamp and Coinbase).",
+        }
+    }
+
+    /// The columns of the table; `book` has four per level for `depth` levels.
+    pub fn columns(self, depth: usize) -> Vec<Column> {
+        use ColumnType::*;
+        let mut columns = vec![
+            column("received_micros", Int, "Time the frame was received, in microseconds since the Unix epoch."),
+            column("exchange", Text, "Venue, e.g. Kraken."),
+            column("instrument", Text, "Canonical instrument, e.g. BTC/USD."),
+        ];
+        match self {
+            Table::Book => {
+                columns.push(column("update_id", Int, "Venue sequence number of the last applied update. Empty where the venue has none."));
+                for i in 1..=depth {
+                    columns.push(column(&format!("bid_price_{}", i), Float, &format!("Price of the bid level {} from the top. Empty when the book has fewer levels.", i)));
+                    columns.push(column(&format!("bid_size_{}", i), Float, &format!("Size of the bid level {} from the top, in base units.", i)));
+                    columns.push(column(&format!("ask_price_{}", i), Float, &format!("Price of the ask level {} from the top. Empty when the book has fewer levels.", i)));
+                    columns.push(column(&format!("ask_size_{}", i), Float, &format!("Size of the ask level {} from the top, in base units.", i)));
+                }
+            }
+            Table::L2 => columns.extend([
+                column("update_id", Int, "Venue sequence number of the update. Empty where the venue has none."),
+                column("side", Text, "bid or ask."),
+                column("price", Float, "Price of the level."),
+                column("size", Float, "New size of the level in base units. 0 removes it."),
+                column("snapshot", Bool, "Whether the frame replaced the whole book, as a snapshot or a resubscription does, rather than updating it."),
+            ]),
+            Table::L3 => columns.extend([
+                column("timestamp_micros", Int, "Venue time of the event, in microseconds since the Unix epoch."),
+                column("event", Text, "created, changed or deleted."),
+                column("order_id", Int, "Venue order ID."),
+                column("side", Text, "buy or sell."),
+                column("price", Float, "Limit price of the order."),
+                column("amount", Float, "Remaining amount of the order in base units."),
+            ]),
+            Table::Trades => columns.extend([
+                column("timestamp_micros", Int, "Venue time of the trade, in microseconds since the Unix epoch."),
+                column("trade_id", Int, "Venue trade ID."),
+                column("side", Text, "Side of the taker: buy or sell."),
+                column("price", Float, "Trade price."),
+                column("amount", Float, "Traded amount in base units."),
+            ]),
+        }
+        columns
+    }
+}
+
+/// Parses an RFC 3339 time, e.g. `2024-05-01T12:00:00Z`, or a number of microseconds since the Unix epoch.
+pub fn parse_time(time: &str) -> Result<i64, AppError> {
+    time.parse::<i64>().or_else(|_| DateTime::parse_from_rfc3339(time).map(|time| time.timestamp_micros())).map_err(|_| AppError::ParsingFailed(format!("time {}", time)))
+}
+
+/// Prints every table with its columns, types and descriptions.
+pub fn print_schema(depth: usize) {
+    for table in [Table::Book, Table::L2, Table::L3, Table::Trades] {
+        println!("{}: {}", table.name(), table.description());
+        for column in table.columns(depth) {println!("  {:<18}{:<9}{}", column.name, format!("{:?}", column.kind.data_type()).to_lowercase(), column.description);}
+        println!();
+    }
+}
+
+/// The rows of one table on their way to its CSV and Parquet files.
+struct Sink {
+    schema: Arc<Schema>,
+    rows: Vec<Vec<Value>>,
+    written: usize,
+    csv: Option<(PathBuf, csv::Writer<File>)>,
+    parquet: Option<(PathBuf, ArrowWriter<File>)>,
+}
+
+impl Sink {
+    fn create(table: Table, options: &Options) -> Result<Sink, AppError> {
+        let columns = table.columns(options.depth);
+        let fields: Vec<Field> = columns.iter()
+            .map(|column| Field::new(&column.name, column.kind.data_type(), true).with_metadata(HashMap::from([("description".to_string(), column.description.clone())])))
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        let path = |extension: &str| options.output.join(format!("{}.{}", table.name(), extension));
+        let mut sink = Sink { schema: schema.clone(), rows: Vec::new(), written: 0, csv: None, parquet: None };
+
+        if options.format.contains(&Format::Csv) {
+            let path = path("csv");
+            let mut writer = csv::Writer::from_path(&path).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            writer.write_record(columns.iter().map(|column| &column.name)).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            sink.csv = Some((path, writer));
+        }
+        if options.format.contains(&Format::Parquet) {
+            let path = path("parquet");
+            let file = File::create(&path).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .set_key_value_metadata(Some(vec![KeyValue::new("description".to_string(), table.description().to_string())]))
+                .build();
+            let writer = ArrowWriter::try_new(file, schema, Some(properties)).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            sink.parquet = Some((path, writer));
+        }
+        Ok(sink)
+    }
+
+    fn push(&mut self, row: Vec<Value>) -> Result<(), AppError> {
+        self.rows.push(row);
+        if self.rows.len() >= BATCH_ROWS {self.flush()?;}
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), AppError> {
+        if self.rows.is_empty() {return Ok(());}
+        let rows = std::mem::take(&mut self.rows);
+        self.written += rows.len();
+
+        if let Some((path, writer)) = self.csv.as_mut() {
+            for row in &rows {
+                let cells = row.iter().map(|value| match value {
+                    Value::Int(value) => value.to_string(),
+                    Value::Float(value) => value.to_string(),
+                    Value::Text(value) => value.clone(),
+                    Value::Bool(value) => value.to_string(),
+                    Value::Null => String::new(),
+                });
+                writer.write_record(cells).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            }
+        }
+        if let Some((path, writer)) = self.parquet.as_mut() {
+            let arrays: Vec<ArrayRef> = self.schema.fields().iter().enumerate().map(|(i, field)| -> ArrayRef {
+                let cells = rows.iter().map(|row| &row[i]);
+                match field.data_type() {
+                    DataType::Int64 => Arc::new(cells.map(|value| match value {Value::Int(value) => Some(*value), _ => None}).collect::<Int64Array>()),
+                    DataType::Float64 => Arc::new(cells.map(|value| match value {Value::Float(value) => Some(*value), _ => None}).collect::<Float64Array>()),
+                    DataType::Boolean => Arc::new(cells.map(|value| match value {Value::Bool(value) => Some(*value), _ => None}).collect::<BooleanArray>()),
+                    _ => Arc::new(cells.map(|value| match value {Value::Text(value) => Some(value.as_str()), _ => None}).collect::<StringArray>()),
+                }
+            }).collect();
+            let batch = RecordBatch::try_new(self.schema.clone(), arrays).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            writer.write(&batch).map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining rows and closes the files. Returns the paths written and the number of rows.
+    fn finish(mut self) -> Result<(Vec<PathBuf>, usize), AppError> {
+        self.flush()?;
+        let mut paths = Vec::new();
+        if let Some((path, mut writer)) = self.csv.take() {
+            writer.flush().map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            paths.push(path);
+        }
+        if let Some((path, writer)) = self.parquet.take() {
+            writer.close().map_err(|e| AppError::ExportFailed(format!("{}: {}", path.display(), e)))?;
+            paths.push(path);
+        }
+        Ok((paths, self.written))
+    }
+}
+
+/// What one frame did to a venue book, besides changing its levels.
+#[derive(Default)]
+pub struct Update {
+    /// The frame replaced the whole book.
+    pub snapshot: bool,
+    pub update_id: Option<u64>,
+    /// Order events as `(event, order)`.
+    pub orders: Vec<(&'static str, Order)>,
+    pub trades: Vec<TradeEvent>,
+}
+
+/// A venue book rebuilt from its recorded frames.
+#[derive(Default)]
+pub struct Feed {
+    pub book: OrderBook,
+    /// The OKX book as received, in contracts.
+    okx: Option<okx::RawBook>,
+    update_id: Option<u64>,
+    /// The top levels of the last `book` row, and when it was written.
+    top: Vec<Value>,
+    top_micros: Option<i64>,
+}
+
+impl Feed {
+    /// Applies a frame as the venue's connector would. Frames that are not book or trade data are ignored.
+    pub fn apply(&mut self, key: &BookKey, instrument: &Instrument, listing: &Listing, payload: &str) -> Result<Update, AppError> {
+        let mut update = Update::default();
+        let parsing = || AppError::ParsingFailed(format!("{} {}", key, payload));
+        match key.exchange.as_str() {
+            "Binance" => {
+                let depth: DepthStreamData = serde_json::from_str(payload).map_err(|_| parsing())?;
+                let snapshot = OrderBook::from(&depth);
+                (self.book.bids, self.book.asks) = (snapshot.bids, snapshot.asks);
+                update.snapshot = true;
+                update.update_id = Some(depth.last_update_id);
+            }
+            "Bitstamp" => {
+                let Ok(msg) = serde_json::from_str::<Msg>(payload) else {return Ok(update)};
+                match msg.data {
+                    Data::Order(order) => {
+                        let event = match msg.event.as_str() {
+                            "order_created" => {self.book.upsert_order(order.clone()); "created"}
+                            "order_changed" => {self.book.upsert_order(order.clone()); "changed"}
+                            "order_deleted" => {self.book.remove_order(&order); "deleted"}
+                            _ => return Ok(update),
+                        };
+                        update.orders.push((event, order));
+                    }
+                    Data::Trade(trade) if msg.event == "trade" => update.trades.push(bitstamp_trade(key, &trade)),
+                    // The connector starts a new book on every connection, which the subscription confirms.
+                    _ if msg.event == "bts:subscription_succeeded" && msg.channel.starts_with("live_orders") => {
+                        self.book.clear();
+                        update.snapshot = true;
+                    }
+                    _ => {}
+                }
+            }
+            "Kraken" => {
+                let value: serde_json::Value = serde_json::from_str(payload).map_err(|_| parsing())?;
+                if value["channel"] != listing.channel()? {return Ok(update);}
+                let message: kraken::BookMessage = serde_json::from_value(value).map_err(|_| parsing())?;
+                // A checksum mismatch made the connector resubscribe, and the snapshot that follows is in the recording.
+                kraken::apply(&mut self.book, &message, decimals(instrument.tick_size_on(listing)), decimals(instrument.lot_size_on(listing)));
+                update.snapshot = message.kind == "snapshot";
+            }
+            "Coinbase" => match serde_json::from_str(payload).map_err(|_| parsing())? {
+                coinbase::FeedMessage::Snapshot { bids, asks, .. } => {
+                    self.book.bids = coinbase::snapshot_side(&bids, true)?;
+                    self.book.asks = coinbase::snapshot_side(&asks, false)?;
+                    update.snapshot = true;
+                }
+                coinbase::FeedMessage::L2update { changes, .. } => {
+                    for [side, price, size] in &changes {
+                        let order_type = if side == "buy" {OrderType::Buy} else {OrderType::Sell};
+                        self.book.set_level(order_type as u8, parse_decimal(price)?, parse_decimal(size)?);
+                    }
+                }
+                coinbase::FeedMessage::Match(m) => {
+                    update.update_id = Some(m.sequence);
+                    update.trades.push(coinbase::trade_event(key, &m)?);
+                }
+                _ => {}
+            },
+            "OKX" => {
+                if payload == "pong" {return Ok(update);}
+                let value: serde_json::Value = serde_json::from_str(payload).map_err(|_| parsing())?;
+                if value["arg"]["channel"] != listing.channel()? || value["arg"]["instId"] != listing.symbol.as_str() || value.get("data").is_none() {return Ok(update);}
+                let message: okx::BooksMessage = serde_json::from_value(value).map_err(|_| parsing())?;
+                for data in &message.data {
+                    if message.action == "snapshot" {
+                        self.okx = Some(okx::RawBook::new(listing.contract_size.unwrap_or(1.0)));
+                        update.snapshot = true;
+                    }
+                    let Some(book) = self.okx.as_mut() else {continue};
+                    book.apply(data)?;
+                    update.update_id = u64::try_from(data.seq_id).ok();
+                }
+                if let Some(book) = &self.okx {book.fill(&mut self.book);}
+            }
+            "Bybit" => {
+                let value: serde_json::Value = serde_json::from_str(payload).map_err(|_| parsing())?;
+                if value["topic"] != listing.channel()? {return Ok(update);}
+                let message: bybit::OrderbookMessage = serde_json::from_value(value).map_err(|_| parsing())?;
+                if message.kind == "snapshot" {
+                    self.book.bids.clear();
+                    self.book.asks.clear();
+                    update.snapshot = true;
+                }
+                for [price, size] in &message.data.b {self.book.set_level(OrderType::Buy as u8, parse_decimal(price)?, parse_decimal(size)?);}
+                for [price, size] in &message.data.a {self.book.set_level(OrderType::Sell as u8, parse_decimal(price)?, parse_decimal(size)?);}
+                update.update_id = Some(message.data.u);
+            }
+            _ => {}
+        }
+        if update.update_id.is_some() {self.update_id = update.update_id;}
+        Ok(update)
+    }
+
+    /// Size by price on each side.
+    fn levels(&self) -> [BTreeMap<OrderedFloat<f64>, f64>; 2] {
+        [&self.book.bids, &self.book.asks].map(|side| side.iter().map(|level| (level.price, level.size.into_inner())).collect())
+    }
+
+    /// The best `depth` levels of each side as `book` columns.
+    fn top(&self, depth: usize) -> Vec<Value> {
+        let cell = |level: Option<&LimitPrice>, price: bool| match level {
+            Some(level) => Value::Float(if price {level.price.into_inner()} else {level.size.into_inner()}),
+            None => Value::Null,
+        };
+        (0..depth).flat_map(|i| {
+            let (bid, ask) = (self.book.bids.get(i), self.book.asks.get(i));
+            [cell(bid, true), cell(bid, false), cell(ask, true), cell(ask, false)]
+        }).collect()
+    }
+}
+
+/// Replays the recording at `options.input` and writes the selected tables.
+pub fn run(options: &Options, registry: &Registry) -> Result<(), AppError> {
+    if options.schema {
+        print_schema(options.depth);
+        return Ok(());
+    }
+    let input = options.input.as_deref().ok_or_else(|| AppError::ExportFailed("no recording given".to_string()))?;
+    if options.depth == 0 {return Err(AppError::ExportFailed("depth must be at least 1".to_string()));}
+    fs::create_dir_all(&options.output).map_err(|e| AppError::ExportFailed(format!("{}: {}", options.output.display(), e)))?;
+
+    let mut sinks: HashMap<Table, Sink> = HashMap::new();
+    for &table in &options.tables {
+        if let Entry::Vacant(entry) = sinks.entry(table) {entry.insert(Sink::create(table, options)?);}
+    }
+    let wanted = |list: &[String], value: &str| list.is_empty() || list.iter().any(|item| item == value);
+    let in_range = |micros: i64| options.from.is_none_or(|from| micros >= from);
+    let needs_levels = sinks.contains_key(&Table::L2);
+    let mut feeds: HashMap<BookKey, Feed> = HashMap::new();
+    let mut unknown = 0;
+
+    'files: for path in recorder::recordings(input)? {
+        for frame in recorder::frames(&path)? {
+            if options.to.is_some_and(|to| frame.received_at >= to) {break 'files;}
+            if !wanted(&options.exchanges, &frame.venue) {continue;}
+            let Some(name) = frame.instrument(registry) else {unknown += 1; continue};
+            if !wanted(&options.symbols, name) {continue;}
+            let (Some(instrument), Some(listing)) = (registry.get(name), registry.get(name).and_then(|instrument| instrument.venues.get(&frame.venue))) else {unknown += 1; continue};
+
+            let key = BookKey::new(&frame.venue, name);
+            let feed = feeds.entry(key.clone()).or_default();
+            let before = if needs_levels {Some(feed.levels())} else {None};
+            let update = feed.apply(&key, instrument, listing, &frame.text()?)?;
+            // Frames before the range only build up the books.
+            if !in_range(frame.received_at) {continue;}
+
+            let row = |cells: Vec<Value>| [vec![Value::Int(frame.received_at), Value::Text(key.exchange.clone()), Value::Text(key.instrument.clone())], cells].concat();
+            let update_id = |id: Option<u64>| id.map_or(Value::Null, |id| Value::Int(id as i64));
+            if let (Some(sink), Some(before)) = (sinks.get_mut(&Table::L2), before) {
+                for (side, (before, after)) in ["bid", "ask"].into_iter().zip(before.iter().zip(feed.levels().iter())) {
+                    let changed = after.iter().filter(|(price, size)| before.get(price) != Some(size)).map(|(price, size)| (*price, *size));
+                    let removed = before.keys().filter(|price| !after.contains_key(price)).map(|price| (*price, 0.0));
+                    let mut changes: Vec<(OrderedFloat<f64>, f64)> = changed.chain(removed).collect();
+                    changes.sort_by_key(|(price, _)| *price);
+                    for (price, size) in changes {
+                        sink.push(row(vec![update_id(update.update_id), Value::Text(side.to_string()), Value::Float(price.into_inner()), Value::Float(size), Value::Bool(update.snapshot)]))?;
+                    }
+                }
+            }
+            if let Some(sink) = sinks.get_mut(&Table::Book) {
+                let top = feed.top(options.depth);
+                let due = feed.top_micros.is_none_or(|last| frame.received_at - last >= options.interval_ms * 1000);
+                if top != feed.top && due {
+                    sink.push(row([vec![update_id(feed.update_id)], top.clone()].concat()))?;
+                    (feed.top, feed.top_micros) = (top, Some(frame.received_at));
+                }
+            }
+            if let Some(sink) = sinks.get_mut(&Table::L3) {
+                for (event, order) in update.orders {
+                    let side = if order.order_type == OrderType::Buy as u8 {"buy"} else {"sell"};
+                    let cells = vec![Value::Int(order.microtimestamp.parse().unwrap_or_default()), Value::Text(event.to_string()), Value::Int(order.id as i64), Value::Text(side.to_string()), Value::Float(order.price), Value::Float(order.amount)];
+                    sink.push(row(cells))?;
+                }
+            }
+            if let Some(sink) = sinks.get_mut(&Table::Trades) {
+                for trade in update.trades {
+                    sink.push(row(vec![Value::Int(trade.timestamp_micros), Value::Int(trade.trade_id as i64), Value::Text(trade.side), Value::Float(trade.price), Value::Float(trade.amount)]))?;
+                }
+            }
+        }
+    }
+
+    if unknown > 0 {eprintln!("Skipped {} frames of instruments or venues that are not in the registry.", unknown);}
+    for &table in &options.tables {
+        let Some(sink) = sinks.remove(&table) else {continue};
+        let (paths, rows) = sink.finish()?;
+        for path in paths {println!("{}: {} rows", path.display(), rows);}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    /// `fixtures/export-session.jsonl`: Bitstamp orders and a trade on BTC/USD between two Binance BTC/USDT depth
+    /// snapshots, one frame a millisecond from 2024-05-01T12:00:00Z.
+    const START: i64 = 1_714_564_800_000_000;
+
+    fn options(name: &str) -> Options {
+        Options {
+            input: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/export-session.jsonl")),
+            output: std::env::temp_dir().join(format!("export-test-{}-{}", name, std::process::id())),
+            format: vec![Format::Csv, Format::Parquet],
+            tables: vec![Table::Book, Table::L2, Table::L3, Table::Trades],
+            symbols: Vec::new(),
+            exchanges: Vec::new(),
+            from: None,
+            to: None,
+            depth: 1,
+            interval_ms: 0,
+            schema: false,
+        }
+    }
+
+    /// Exports and reads back the CSV file of `table`: its header and rows.
+    fn export(options: &Options, table: Table) -> (Vec<String>, Vec<Vec<String>>) {
+        run(options, &Registry::default()).unwrap();
+        let mut reader = csv::Reader::from_path(options.output.join(format!("{}.csv", table.name()))).unwrap();
+        let header = reader.headers().unwrap().iter().map(String::from).collect();
+        let rows = reader.records().map(|record| record.unwrap().iter().map(String::from).collect()).collect();
+        (header, rows)
+    }
+
+    fn row(cells: &[&str]) -> Vec<String> {cells.iter().map(|cell| cell.to_string()).collect()}
+
+    fn micros(ms: i64) -> String {(START + ms * 1000).to_string()}
+
+    #[test]
+    fn times_are_rfc_3339_or_microseconds() {
+        assert_eq!(parse_time("2024-05-01T12:00:00Z").unwrap(), START);
+        assert_eq!(parse_time("2024-05-01T14:00:00.5+02:00").unwrap(), START + 500_000);
+        assert_eq!(parse_time("1714564800000000").unwrap(), START);
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn the_book_table_has_a_row_each_time_the_top_levels_change() {
+        let options = options("book");
+        let (header, rows) = export(&options, Table::Book);
+        assert_eq!(header, ["received_micros", "exchange", "instrument", "update_id", "bid_price_1", "bid_size_1", "ask_price_1", "ask_size_1"]);
+        // The trade leaves the book as it was, and so does the second Binance snapshot below its top bid.
+        assert_eq!(rows, [
+            row(&[&micros(0), "Bitstamp", "BTC/USD", "", "64000", "0.5", "", ""]),
+            row(&[&micros(1), "Binance", "BTC/USDT", "100", "64005.1", "1", "64006.2", "2"]),
+            row(&[&micros(2), "Bitstamp", "BTC/USD", "", "64000", "0.5", "64010", "0.3"]),
+            row(&[&micros(4), "Bitstamp", "BTC/USD", "", "64000", "0.5", "64010", "0.2"]),
+            row(&[&micros(5), "Binance", "BTC/USDT", "101", "64005.1", "1.5", "64006.2", "2"]),
+            row(&[&micros(6), "Bitstamp", "BTC/USD", "", "", "", "64010", "0.2"]),
+        ]);
+        fs::remove_dir_all(&options.output).unwrap();
+    }
+
+    #[test]
+    fn the_l2_table_has_a_row_per_changed_level() {
+        let options = options("l2");
+        let (header, rows) = export(&options, Table::L2);
+        assert_eq!(header, ["received_micros", "exchange", "instrument", "update_id", "side", "price", "size", "snapshot"]);
+        assert_eq!(rows, [
+            row(&[&micros(0), "Bitstamp", "BTC/USD", "", "bid", "64000", "0.5", "false"]),
+            row(&[&micros(1), "Binance", "BTC/USDT", "100", "bid", "64005", "0.5", "true"]),
+            row(&[&micros(1), "Binance", "BTC/USDT", "100", "bid", "64005.1", "1", "true"]),
+            row(&[&micros(1), "Binance", "BTC/USDT", "100", "ask", "64006.2", "2", "true"]),
+            row(&[&micros(2), "Bitstamp", "BTC/USD", "", "ask", "64010", "0.3", "false"]),
+            row(&[&micros(4), "Bitstamp", "BTC/USD", "", "ask", "64010", "0.2", "false"]),
+            // A level missing from a snapshot is removed.
+            row(&[&micros(5), "Binance", "BTC/USDT", "101", "bid", "64005", "0", "true"]),
+            row(&[&micros(5), "Binance", "BTC/USDT", "101", "bid", "64005.1", "1.5", "true"]),
+            row(&[&micros(6), "Bitstamp", "BTC/USD", "", "bid", "64000", "0", "false"]),
+        ]);
+        fs::remove_dir_all(&options.output).unwrap();
+    }
+
+    #[test]
+    fn the_l3_and_trades_tables_have_a_row_per_event() {
+        let options = options("l3");
+        let (header, rows) = export(&options, Table::L3);
+        assert_eq!(header, ["received_micros", "exchange", "instrument", "timestamp_micros", "event", "order_id", "side", "price", "amount"]);
+        assert_eq!(rows, [
+            row(&[&micros(0), "Bitstamp", "BTC/USD", &(START - 10_000).to_string(), "created", "1", "buy", "64000", "0.5"]),
+            row(&[&micros(2), "Bitstamp", "BTC/USD", &(START + 1_500).to_string(), "created", "2", "sell", "64010", "0.3"]),
+            row(&[&micros(4), "Bitstamp", "BTC/USD", &(START + 3_500).to_string(), "changed", "2", "sell", "64010", "0.2"]),
+            row(&[&micros(6), "Bitstamp", "BTC/USD", &(START + 5_500).to_string(), "deleted", "1", "buy", "64000", "0.5"]),
+        ]);
+
+        let (header, rows) = export(&options, Table::Trades);
+        assert_eq!(header, ["received_micros", "exchange", "instrument", "timestamp_micros", "trade_id", "side", "price", "amount"]);
+        assert_eq!(rows, [row(&[&micros(3), "Bitstamp", "BTC/USD", &(START + 2_500).to_string(), "7", "buy", "64010", "0.1"])]);
+        fs::remove_dir_all(&options.output).unwrap();
+    }
+
+    #[test]
+    fn frames_before_the_range_only_build_up_the_books() {
+        let options = Options { from: Some(START + 2_000), to: Some(START + 6_000), ..options("range") };
+        let (_, rows) = export(&options, Table::Book);
+        let rows: Vec<(&str, &str)> = rows.iter().map(|row| (row[0].as_str(), row[1].as_str())).collect();
+        assert_eq!(rows, [(micros(2).as_str(), "Bitstamp"), (micros(4).as_str(), "Bitstamp"), (micros(5).as_str(), "Binance")]);
+
+        // The order from before the range is still in the book, and the snapshot in it is what the next one changes.
+        let (_, rows) = export(&options, Table::L2);
+        assert_eq!(rows[0], row(&[&micros(2), "Bitstamp", "BTC/USD", "", "ask", "64010", "0.3", "false"]));
+        assert_eq!(rows.iter().filter(|row| row[1] == "Binance").map(|row| (row[5].as_str(), row[6].as_str())).collect::<Vec<_>>(), [("64005", "0"), ("64005.1", "1.5")]);
+        let (_, rows) = export(&options, Table::L3);
+        assert_eq!(rows.iter().map(|row| row[4].as_str()).collect::<Vec<_>>(), ["created", "changed"]);
+        fs::remove_dir_all(&options.output).unwrap();
+    }
+
+    #[test]
+    fn symbols_select_the_instruments_to_export() {
+        let options = Options { symbols: vec!["BTC/USDT".to_string()], ..options("symbols") };
+        let (_, rows) = export(&options, Table::Book);
+        assert_eq!(rows.iter().map(|row| row[3].as_str()).collect::<Vec<_>>(), ["100", "101"]);
+        assert!(rows.iter().all(|row| row[2] == "BTC/USDT"));
+        assert!(export(&options, Table::L3).1.is_empty());
+        assert!(export(&options, Table::Trades).1.is_empty());
+        fs::remove_dir_all(&options.output).unwrap();
+    }
+
+    #[test]
+    fn the_files_have_the_documented_columns() {
+        let options = Options { depth: 3, ..options("schema") };
+        run(&options, &Registry::default()).unwrap();
+        assert_eq!(Table::Book.columns(3).len(), 4 + 4 * 3);
+        for table in [Table::Book, Table::L2, Table::L3, Table::Trades] {
+            let columns = table.columns(options.depth);
+            let mut csv = csv::Reader::from_path(options.output.join(format!("{}.csv", table.name()))).unwrap();
+            assert_eq!(csv.headers().unwrap().iter().collect::<Vec<_>>(), columns.iter().map(|column| column.name.as_str()).collect::<Vec<_>>());
+
+            let parquet = ParquetRecordBatchReaderBuilder::try_new(File::open(options.output.join(format!("{}.parquet", table.name()))).unwrap()).unwrap();
+            let fields = parquet.schema().fields();
+            assert_eq!(fields.len(), columns.len());
+            for (field, column) in fields.iter().zip(&columns) {
+                assert_eq!((field.name(), field.data_type()), (&column.name, &column.kind.data_type()));
+                assert_eq!(field.metadata().get("description"), Some(&column.description));
+            }
+            let rows: usize = parquet.build().unwrap().map(|batch| batch.unwrap().num_rows()).sum();
+            assert_eq!(rows, csv.records().count());
+        }
+        fs::remove_dir_all(&options.output).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::HashMap, fs, path::Path};
+use serde::{Serialize, Deserialize};
+use crate::AppError;
+
+/// Maker/taker fees for one venue in basis points of notional. A negative maker fee is a rebate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub maker_bps: f64,
+    pub taker_bps: f64,
+}
+
+/// Whether a fill added liquidity to the book or removed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+impl FeeSchedule {
+    pub fn rate(&self, liquidity: Liquidity) -> f64 {
+        match liquidity {
+            Liquidity::Maker => self.maker_bps / 10_000.0,
+            Liquidity::Taker => self.taker_bps / 10_000.0,
+        }
+    }
+
+    /// Fee charged on a fill; negative when the venue pays a rebate.
+    pub fn fee(&self, liquidity: Liquidity, price: f64, amount: f64) -> f64 {price * amount * self.rate(liquidity)}
+
+    /// Price actually received when selling into a bid as a taker.
+    pub fn effective_bid(&self, price: f64) -> f64 {price * (1.0 - self.rate(Liquidity::Taker))}
+
+    /// Price actually paid when lifting an ask as a taker.
+    pub fn effective_ask(&self, price: f64) -> f64 {price * (1.0 + self.rate(Liquidity::Taker))}
+}
+
+/// Fee schedules keyed by exchange name as it appears in `Level.exchange`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct FeeTable {
+    pub schedules: HashMap<String, FeeSchedule>,
+}
+
+impl Default for FeeTable {
+    /// Entry-tier schedules of the supported venues: spot, and perpetual swaps for OKX and Bybit.
+    fn default() -> FeeTable {
+        let schedules = HashMap::from([
+            ("Binance".to_string(), FeeSchedule { maker_bps: 10.0, taker_bps: 10.0 }),
+            ("Bitstamp".to_string(), FeeSchedule { maker_bps: 30.0, taker_bps: 40.0 }),
+            ("Kraken".to_string(), FeeSchedule { maker_bps: 25.0, taker_bps: 40.0 }),
+            ("Coinbase".to_string(), FeeSchedule { maker_bps: 40.0, taker_bps: 60.0 }),
+            ("OKX".to_string(), FeeSchedule { maker_bps: 2.0, taker_bps: 5.0 }),
+            ("Bybit".to_string(), FeeSchedule { maker_bps: 2.0, taker_bps: 5.5 }),
+        ]);
+        FeeTable { schedules }
+    }
+}
+
+impl FeeTable {
+    /// Loads a JSON object of `{"<exchange>": {"maker_bps": .., "taker_bps": ..}}` on top of the defaults.
+    pub fn load(path: &Path) -> Result<FeeTable, AppError> {
+        let text = fs::read_to_string(path).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))?;
+        let overrides: FeeTable = serde_json::from_str(&text).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))?;
+        let mut table = FeeTable::default();
+        table.schedules.extend(overrides.schedules);
+        Ok(table)
+    }
+
+    /// Venues without a configured schedule are treated as fee free.
+    pub fn for_exchange(&self, exchange: &str) -> FeeSchedule {self.schedules.get(exchange).copied().unwrap_or_default()}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{aggregator::{self, View}, models::{BookKey, InstrumentKind, OrderBook, OrderType}};
+
+    fn close(a: f64, b: f64) -> bool {(a - b).abs() < 1e-9}
+
+    #[test]
+    fn fees_are_basis_points_of_notional() {
+        let schedule = FeeSchedule { maker_bps: -2.5, taker_bps: 40.0 };
+        assert!(close(schedule.rate(Liquidity::Taker), 0.004));
+        assert!(close(schedule.fee(Liquidity::Taker, 30_000.0, 0.5), 60.0));
+        assert!(close(schedule.fee(Liquidity::Maker, 30_000.0, 0.5), -3.75), "a negative maker fee is a rebate");
+        assert!(close(schedule.effective_bid(100.0), 99.6));
+        assert!(close(schedule.effective_ask(100.0), 100.4));
+        assert_eq!(FeeSchedule::default().effective_ask(100.0), 100.0);
+    }
+
+    #[test]
+    fn schedules_are_looked_up_by_exchange_and_overridden_from_a_file() {
+        let table = FeeTable::default();
+        assert_eq!(table.for_exchange("Kraken"), FeeSchedule { maker_bps: 25.0, taker_bps: 40.0 });
+        assert_eq!(table.for_exchange("Gemini"), FeeSchedule::default(), "unknown venues are fee free");
+
+        let path = std::env::temp_dir().join(format!("fees-test-{}.json", std::process::id()));
+        fs::write(&path, r#"{"Kraken": {"maker_bps": 0, "taker_bps": 10}, "Gemini": {"maker_bps": 20, "taker_bps": 35}}"#).unwrap();
+        let table = FeeTable::load(&path).unwrap();
+        assert_eq!(table.for_exchange("Kraken"), FeeSchedule { maker_bps: 0.0, taker_bps: 10.0 });
+        assert_eq!(table.for_exchange("Gemini").taker_bps, 35.0);
+        assert_eq!(table.for_exchange("Coinbase"), FeeTable::default().for_exchange("Coinbase"), "the other defaults stay");
+        fs::write(&path, r#"{"Kraken": {"maker_bps": "low"}}"#).unwrap();
+        assert!(FeeTable::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn levels_are_marked_and_ranked_by_their_fee_adjusted_price() {
+        let book = |bid: f64, ask: f64| {
+            let mut book = OrderBook::default();
+            book.set_level(OrderType::Buy as u8, bid, 1.0);
+            book.set_level(OrderType::Sell as u8, ask, 2.0);
+            book.touch(Some(1));
+            book
+        };
+        // Coinbase quotes the better prices, but after its 60 bps taker fee Binance's 10 bps is the better venue.
+        let books = HashMap::from([(BookKey::new("Coinbase", "BTC/USD"), book(100.2, 100.3)), (BookKey::new("Binance", "BTC/USD"), book(100.0, 100.5))]);
+        let view = View { depth: 10, ..View::default() };
+
+        let raw = aggregator::consolidate(&books, Some("BTC/USD"), InstrumentKind::Spot, view, None, None);
+        assert_eq!((raw.bids[0].exchange.as_str(), raw.asks[0].exchange.as_str()), ("Coinbase", "Coinbase"));
+        assert_eq!((raw.bids[0].effective_price, raw.net_spread), (0.0, 0.0));
+
+        let net = aggregator::consolidate(&books, Some("BTC/USD"), InstrumentKind::Spot, view, Some(&FeeTable::default()), None);
+        assert_eq!((net.bids[0].exchange.as_str(), net.asks[0].exchange.as_str()), ("Binance", "Binance"));
+        assert!(close(net.bids[0].effective_price, 100.0 * 0.999) && close(net.bids[1].effective_price, 100.2 * 0.994));
+        assert!(close(net.asks[0].effective_price, 100.5 * 1.001) && close(net.asks[1].effective_price, 100.3 * 1.006));
+        assert_eq!((net.bids[0].price, net.asks[0].price), (100.0, 100.5), "the quoted price is kept");
+        assert!(close(net.spread, 0.1), "the spread is still taken from the raw prices");
+        assert!(close(net.net_spread, 100.5 * 1.001 - 100.0 * 0.999));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, fs, path::Path};
+use serde::{Serialize, Deserialize};
+use crate::{AppError, instruments::Registry, models::{BookKey, OrderBook}};
+
+/// Where the rate of a currency pair comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RateSource {
+    Static(f64),
+    /// The mid of the live books of a registry instrument, e.g. `{"book": "USDT/USD"}`.
+    Book { book: String },
+}
+
+/// Rate sources keyed by pair, `"<from>/<to>"`: one unit of `from` is worth the rate in `to`.
+/// A pair also converts in the opposite direction at the inverse rate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct FxTable {
+    pub rates: HashMap<String, RateSource>,
+}
+
+impl Default for FxTable {
+    /// Dollar stablecoins at par.
+    fn default() -> FxTable {
+        let rates = HashMap::from([
+            ("USDT/USD".to_string(), RateSource::Static(1.0)),
+            ("USDC/USD".to_string(), RateSource::Static(1.0)),
+        ]);
+        FxTable { rates }
+    }
+}
+
+/// The rate applied to one instrument's prices. `converted` is false when it is already quoted in the target currency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub rate: f64,
+    pub converted: bool,
+}
+
+/// Rates into one quote currency, resolved once per summary. Instruments without a rate are missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversion {
+    pub currency: String,
+    pub rates: HashMap<String, Rate>,
+}
+
+impl FxTable {
+    /// Loads a JSON object of `{"<from>/<to>": <rate> | {"book": "<instrument>"}}` on top of the defaults.
+    pub fn load(path: &Path) -> Result<FxTable, AppError> {
+        let text = fs::read_to_string(path).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))?;
+        let overrides: FxTable = serde_json::from_str(&text).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))?;
+        let mut table = FxTable::default();
+        table.rates.extend(overrides.rates);
+        Ok(table)
+    }
+
+    /// Whether `currency` is one prices can be converted into or out of.
+    pub fn knows(&self, currency: &str) -> bool {self.rates.keys().any(|pair| pair.split('/').any(|c| c == currency))}
+
+    /// Units of `to` per unit of `from`, from the pair or its inverse. None when neither is configured or a
+    /// book-based rate has no live book.
+    pub fn rate(&self, from: &str, to: &str, books: &HashMap<BookKey, OrderBook>) -> Option<f64> {
+        if from == to {return Some(1.0);}
+        let resolve = |pair: String| match self.rates.get(&pair)? {
+            RateSource::Static(rate) => Some(*rate),
+            RateSource::Book { book } => mid(books, book),
+        };
+        resolve(format!("{}/{}", from, to)).or_else(|| resolve(format!("{}/{}", to, from)).map(|rate| 1.0 / rate)).filter(|rate| rate.is_finite() && *rate > 0.0)
+    }
+
+    pub fn conversion(&self, currency: &str, registry: &Registry, books: &HashMap<BookKey, OrderBook>) -> Conversion {
+        let rates = registry.instruments.iter()
+            .filter_map(|instrument| Some((instrument.name.clone(), Rate { rate: self.rate(&instrument.quote, currency, books)?, converted: instrument.quote != currency })))
+            .collect();
+        Conversion { currency: currency.to_string(), rates }
+    }
+}
+
+/// Midpoint of the best bid and best ask across the live books of `instrument`.
+fn mid(books: &HashMap<BookKey, OrderBook>, instrument: &str) -> Option<f64> {
+    let live: Vec<&OrderBook> = books.iter().filter(|(key, book)| key.instrument == instrument && book.is_live()).map(|(_, book)| book).collect();
+    let bid = live.iter().filter_map(|book| book.bids_best_first().next()).map(|l| l.price.into_inner()).reduce(f64::max)?;
+    let ask = live.iter().filter_map(|book| book.asks.first()).map(|l| l.price.into_inner()).reduce(f64::min)?;
+    Some((bid + ask) / 2.0)
+}
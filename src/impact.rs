@@ -0,0 +1,126 @@
+use crate::{fees::{FeeTable, Liquidity}, orderbook::{ImpactEstimate, Level, Side, Slice, Summary}};
+
+/// How much to trade: an amount of the base currency, or a notional in the quote currency before fees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Quantity(f64),
+    Notional(f64),
+}
+
+/// Plans a taker order of `target` against a consolidated `summary`, which must rank levels by fee-adjusted price.
+/// Levels are taken best first, so each unit goes to the venue where it costs least, or earns most, after fees.
+/// If the book is too thin the plan covers what is there and `complete` is false.
+pub fn plan(summary: &Summary, side: Side, target: Target, fees: &FeeTable) -> ImpactEstimate {
+    let levels: &[Level] = if side == Side::Buy {&summary.asks} else {&summary.bids};
+    let mut estimate = ImpactEstimate { side: side as i32, mid: mid(summary), quote_currency: summary.quote_currency.clone(), ..Default::default() };
+    let mut slices: Vec<Slice> = Vec::new();
+
+    for level in levels {
+        let remaining = match target {
+            Target::Quantity(quantity) => quantity - estimate.quantity,
+            Target::Notional(notional) => (notional - estimate.notional) / level.price,
+        };
+        if remaining <= 0.0 {break;}
+        let amount = level.amount.min(remaining);
+        let fee = fees.for_exchange(&level.exchange).fee(Liquidity::Taker, level.price, amount);
+
+        let position = slices.iter().position(|s| s.exchange == level.exchange && s.instrument == level.instrument);
+        let slice = match position {
+            Some(i) => &mut slices[i],
+            None => {slices.push(Slice { exchange: level.exchange.clone(), instrument: level.instrument.clone(), ..Default::default() }); slices.last_mut().expect("just pushed")}
+        };
+        slice.quantity += amount;
+        slice.notional += amount * level.price;
+        slice.fee += fee;
+        slice.worst_price = level.price;
+        estimate.quantity += amount;
+        estimate.notional += amount * level.price;
+        estimate.fees += fee;
+        estimate.worst_price = if side == Side::Buy {estimate.worst_price.max(level.price)} else if estimate.worst_price == 0.0 {level.price} else {estimate.worst_price.min(level.price)};
+    }
+
+    for slice in &mut slices {slice.average_price = slice.notional / slice.quantity;}
+    if estimate.quantity > 0.0 {estimate.average_price = estimate.notional / estimate.quantity;}
+    estimate.total = if side == Side::Buy {estimate.notional + estimate.fees} else {estimate.notional - estimate.fees};
+    if estimate.mid > 0.0 && estimate.quantity > 0.0 {
+        let direction = if side == Side::Buy {1.0} else {-1.0};
+        estimate.slippage_bps = direction * (estimate.average_price - estimate.mid) / estimate.mid * 10_000.0;
+        estimate.all_in_slippage_bps = direction * (estimate.total / estimate.quantity - estimate.mid) / estimate.mid * 10_000.0;
+    }
+    // Rounding can leave a sliver unfilled on an otherwise complete plan.
+    estimate.complete = match target {
+        Target::Quantity(quantity) => estimate.quantity >= quantity * (1.0 - 1e-9),
+        Target::Notional(notional) => estimate.notional >= notional * (1.0 - 1e-9),
+    };
+    estimate.slices = slices;
+    estimate
+}
+
+/// Midpoint of the best raw bid and ask across venues, or 0 if either side is empty.
+fn mid(summary: &Summary) -> f64 {
+    match (summary.bids.iter().map(|l| l.price).reduce(f64::max), summary.asks.iter().map(|l| l.price).reduce(f64::min)) {
+        (Some(bid), Some(ask)) => (bid + ask) / 2.0,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::fees::FeeSchedule;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level { exchange: exchange.to_string(), price, amount, effective_price: 0.0, instrument: "BTC/USD".to_string(), converted: false, fx_rate: 1.0 }
+    }
+
+    /// Kraken charges takers 10 bps, Coinbase nothing.
+    fn fees() -> FeeTable {
+        FeeTable { schedules: HashMap::from([("Kraken".to_string(), FeeSchedule { maker_bps: 0.0, taker_bps: 10.0 }), ("Coinbase".to_string(), FeeSchedule::default())]) }
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            bids: vec![level("Coinbase", 99.0, 1.0), level("Kraken", 98.0, 2.0)],
+            asks: vec![level("Kraken", 101.0, 1.0), level("Coinbase", 102.0, 1.0), level("Kraken", 103.0, 2.0)],
+            ..Default::default()
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {(a - b).abs() < 1e-9}
+
+    #[test]
+    fn a_buy_walks_the_asks_across_venues() {
+        let estimate = plan(&summary(), Side::Buy, Target::Quantity(3.0), &fees());
+        assert!(estimate.complete);
+        assert_eq!((estimate.quantity, estimate.notional, estimate.worst_price, estimate.mid), (3.0, 101.0 + 102.0 + 103.0, 103.0, 100.0));
+        assert!(close(estimate.fees, (101.0 + 103.0) * 0.001));
+        assert!(close(estimate.total, estimate.notional + estimate.fees));
+        assert!(close(estimate.slippage_bps, (102.0 - 100.0) / 100.0 * 10_000.0));
+        assert!(estimate.all_in_slippage_bps > estimate.slippage_bps);
+
+        let slices: Vec<(&str, f64, f64, f64)> = estimate.slices.iter().map(|s| (s.exchange.as_str(), s.quantity, s.average_price, s.worst_price)).collect();
+        assert_eq!(slices, vec![("Kraken", 2.0, 102.0, 103.0), ("Coinbase", 1.0, 102.0, 102.0)]);
+    }
+
+    #[test]
+    fn a_notional_sell_stops_at_the_amount() {
+        let estimate = plan(&summary(), Side::Sell, Target::Notional(148.0), &fees());
+        assert!(estimate.complete);
+        assert!(close(estimate.notional, 148.0));
+        assert!(close(estimate.quantity, 1.5));
+        assert_eq!(estimate.worst_price, 98.0);
+        assert!(close(estimate.total, 148.0 - 0.5 * 98.0 * 0.001));
+        assert!(close(estimate.slippage_bps, (100.0 - 148.0 / 1.5) / 100.0 * 10_000.0));
+    }
+
+    #[test]
+    fn a_thin_book_gives_an_incomplete_plan() {
+        let estimate = plan(&summary(), Side::Sell, Target::Quantity(5.0), &fees());
+        assert!(!estimate.complete);
+        assert_eq!(estimate.quantity, 3.0);
+        let empty = plan(&Summary::default(), Side::Buy, Target::Quantity(1.0), &fees());
+        assert!(!empty.complete);
+        assert_eq!((empty.quantity, empty.average_price, empty.slippage_bps, empty.slices.len()), (0.0, 0.0, 0.0, 0));
+    }
+}
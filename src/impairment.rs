@@ -0,0 +1,247 @@
+use std::{cmp::{Ordering, Reverse}, collections::BinaryHeap, io, str::FromStr, time::{Duration, Instant}};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use tungstenite::Message;
+use crate::{AppError, STOP_CHECK_INTERVAL, Socket, keepalive::set_read_timeout};
+
+/// Network conditions applied to a venue's frames between the socket and the decoder.
+///
+/// Parsed from a comma separated list such as `latency_ms=50,jitter_ms=20,drop=0.01,reorder=0.05,disconnect=0.0001`;
+/// probabilities are per frame and omitted keys keep their zero default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Impairment {
+    /// Mean delivery delay.
+    pub latency_ms: f64,
+    /// Standard deviation of the delivery delay; large jitter alone already reorders frames.
+    pub jitter_ms: f64,
+    /// Probability that a frame is held back by an extra `reorder_delay_ms`.
+    pub reorder: f64,
+    pub reorder_delay_ms: f64,
+    /// Probability that a frame is silently discarded.
+    pub drop: f64,
+    /// Probability that the connection is torn down on receiving a frame.
+    pub disconnect: f64,
+    pub seed: Option<u64>,
+}
+
+impl FromStr for Impairment {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Impairment, AppError> {
+        let mut impairment = Impairment { reorder_delay_ms: 100.0, ..Impairment::default() };
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| AppError::ParsingFailed(format!("impairment setting `{}` is not key=value", pair)))?;
+            let number = |v: &str| v.parse::<f64>().map_err(|_| AppError::ParsingFailed(format!("impairment setting `{}` has invalid value", pair)));
+            match key {
+                "latency_ms" => impairment.latency_ms = number(value)?,
+                "jitter_ms" => impairment.jitter_ms = number(value)?,
+                "reorder" => impairment.reorder = number(value)?,
+                "reorder_delay_ms" => impairment.reorder_delay_ms = number(value)?,
+                "drop" => impairment.drop = number(value)?,
+                "disconnect" => impairment.disconnect = number(value)?,
+                "seed" => impairment.seed = Some(value.parse().map_err(|_| AppError::ParsingFailed(format!("impairment setting `{}` has invalid value", pair)))?),
+                _ => return Err(AppError::ParsingFailed(format!("unknown impairment setting `{}`", key))),
+            }
+        }
+        impairment.validate()?;
+        Ok(impairment)
+    }
+}
+
+impl Impairment {
+    /// Checks that delays are finite and not negative and that probabilities are between 0 and 1.
+    pub fn validate(&self) -> Result<(), AppError> {
+        for (name, delay) in [("latency_ms", self.latency_ms), ("jitter_ms", self.jitter_ms), ("reorder_delay_ms", self.reorder_delay_ms)] {
+            if !(delay.is_finite() && delay >= 0.0) {return Err(AppError::ParsingFailed(format!("impairment setting {} must be a finite delay of at least 0, not {}", name, delay)));}
+        }
+        for (name, probability) in [("reorder", self.reorder), ("drop", self.drop), ("disconnect", self.disconnect)] {
+            if !(0.0..=1.0).contains(&probability) {return Err(AppError::ParsingFailed(format!("impairment setting {} must be a probability between 0 and 1, not {}", name, probability)));}
+        }
+        Ok(())
+    }
+}
+
+struct Pending {
+    deliver_at: Instant,
+    seq: u64,
+    msg: Message,
+}
+
+impl PartialEq for Pending {fn eq(&self, other: &Self) -> bool {self.cmp(other) == Ordering::Equal}}
+impl Eq for Pending {}
+impl PartialOrd for Pending {fn partial_cmp(&self, other: &Self) -> Option<Ordering> {Some(self.cmp(other))}}
+impl Ord for Pending {fn cmp(&self, other: &Self) -> Ordering {(self.deliver_at, self.seq).cmp(&(other.deliver_at, other.seq))}}
+
+/// Delays, reorders, drops and disconnects frames according to an [`Impairment`].
+///
+/// Frames are released in delivery-time order. A frame never arrives earlier than its sampled delay; reading through
+/// [`read`] or [`Keepalive::read`](crate::keepalive::Keepalive::read) with [`ImpairedFeed::release_at`] also releases
+/// held-back frames on time when the feed goes quiet.
+pub struct ImpairedFeed {
+    impairment: Impairment,
+    rng: StdRng,
+    delay: Normal<f64>,
+    pending: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+}
+
+impl ImpairedFeed {
+    /// `connection` numbers the connections of a feed. It is added to the seed, so that a seeded impairment of a feed
+    /// that reconnects plays out differently on each connection instead of failing it on the same frame every time.
+    pub fn new(impairment: Impairment, connection: u64) -> Result<ImpairedFeed, AppError> {
+        impairment.validate()?;
+        let delay = Normal::new(impairment.latency_ms, impairment.jitter_ms).map_err(|e| AppError::ParsingFailed(format!("impairment latency: {}", e)))?;
+        let rng = match impairment.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(connection)),
+            None => StdRng::from_entropy(),
+        };
+        Ok(ImpairedFeed { impairment, rng, delay, pending: BinaryHeap::new(), seq: 0 })
+    }
+
+    /// When the earliest held-back frame is due, if any.
+    pub fn release_at(&self) -> Option<Instant> {self.pending.peek().map(|Reverse(pending)| pending.deliver_at)}
+
+    /// Takes the frame just read from the socket, if any, and returns the frames due for decoding now.
+    pub fn process(&mut self, msg: Option<Message>) -> Result<Vec<Message>, AppError> {
+        if let Some(msg) = msg {self.admit(msg)?;}
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while self.pending.peek().is_some_and(|Reverse(p)| p.deliver_at <= now) {
+            if let Some(Reverse(pending)) = self.pending.pop() {due.push(pending.msg);}
+        }
+        Ok(due)
+    }
+
+    fn admit(&mut self, msg: Message) -> Result<(), AppError> {
+        if self.rng.gen::<f64>() < self.impairment.disconnect {
+            self.pending.clear();
+            return Err(AppError::ConnectionFailed("simulated disconnect".to_string()));
+        }
+
+        if self.rng.gen::<f64>() >= self.impairment.drop {
+            let mut delay_ms = self.delay.sample(&mut self.rng).max(0.0);
+            if self.rng.gen::<f64>() < self.impairment.reorder {delay_ms += self.impairment.reorder_delay_ms;}
+            self.seq += 1;
+            self.pending.push(Reverse(Pending { deliver_at: Instant::now() + Duration::from_secs_f64(delay_ms / 1000.0), seq: self.seq, msg }));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next frame from a socket without keepalive. The read gives up and returns `None` after
+/// [`STOP_CHECK_INTERVAL`], so that the connector sees when it is stopped, or earlier when `impaired` holds a frame back
+/// that is due, so that it is released even if the venue goes quiet.
+pub fn read(socket: &mut Socket, impaired: Option<&ImpairedFeed>) -> Result<Option<Message>, AppError> {
+    let release_at = impaired.and_then(ImpairedFeed::release_at);
+    if release_at.is_some_and(|at| at <= Instant::now()) {return Ok(None);}
+    let timeout = release_at.map_or(STOP_CHECK_INTERVAL, |at| at.saturating_duration_since(Instant::now()).min(STOP_CHECK_INTERVAL));
+    set_read_timeout(socket, Some(timeout)).map_err(|e| AppError::ConnectionFailed(e.to_string()))?;
+    match socket.read_message() {
+        Ok(msg) => Ok(Some(msg)),
+        Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(AppError::MessageError(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::models::{OrderBook, Sequence};
+
+    /// What a connector with consecutively numbered updates saw: gaps that forced a resync, and outdated updates it
+    /// discarded.
+    #[derive(Debug, Default)]
+    struct Outcome { gaps: u64, outdated: u64, last: Option<u64> }
+
+    /// Sends updates 1 to `count` through `feed` into a book, resyncing from a snapshot at the received update on every
+    /// gap, and waits for held-back frames to be released.
+    fn run(feed: &mut ImpairedFeed, count: u64) -> Outcome {
+        let mut book = OrderBook::default();
+        book.touch(Some(0));
+        let mut outcome = Outcome::default();
+        let mut apply = |frames: Vec<Message>| for frame in frames {
+            let id: u64 = frame.to_text().unwrap().parse().unwrap();
+            match book.sequence(id, true) {
+                Sequence::Next => book.touch(Some(id)),
+                Sequence::Gap { expected, received } => {
+                    assert!(received > expected);
+                    outcome.gaps += 1;
+                    book.resync();
+                    assert!(!book.is_live() && book.last_update_id.is_none());
+                    book.touch(Some(id));
+                }
+                Sequence::Outdated => outcome.outdated += 1,
+            }
+        };
+        for id in 1..=count {apply(feed.process(Some(Message::Text(id.to_string()))).unwrap());}
+        while let Some(at) = feed.release_at() {
+            thread::sleep(at.saturating_duration_since(Instant::now()));
+            apply(feed.process(None).unwrap());
+        }
+        outcome.last = book.last_update_id;
+        outcome
+    }
+
+    fn feed(spec: &str) -> ImpairedFeed {ImpairedFeed::new(spec.parse().unwrap(), 0).unwrap()}
+
+    #[test]
+    fn an_unimpaired_feed_has_no_gaps() {
+        let outcome = run(&mut feed("seed=1"), 200);
+        assert_eq!((outcome.gaps, outcome.outdated, outcome.last), (0, 0, Some(200)));
+    }
+
+    #[test]
+    fn dropped_frames_are_detected_as_gaps() {
+        let outcome = run(&mut feed("drop=0.2,seed=7"), 200);
+        assert!(outcome.gaps > 0 && outcome.gaps <= 200);
+        assert_eq!(outcome.outdated, 0);
+        assert!(outcome.last.is_some_and(|last| last <= 200));
+    }
+
+    #[test]
+    fn reordered_frames_are_detected_as_gaps_then_discarded() {
+        let outcome = run(&mut feed("reorder=0.3,reorder_delay_ms=5,seed=3"), 200);
+        assert!(outcome.gaps > 0);
+        assert!(outcome.outdated > 0);
+    }
+
+    #[test]
+    fn disconnects_fail_the_feed() {
+        let mut feed = feed("disconnect=1");
+        assert!(matches!(feed.process(Some(Message::Text("1".to_string()))), Err(AppError::ConnectionFailed(_))));
+    }
+
+    #[test]
+    fn each_connection_of_a_seeded_feed_disconnects_on_its_own_frame() {
+        let impairment: Impairment = "disconnect=0.05,seed=11".parse().unwrap();
+        let disconnect_at = |connection: u64| {
+            let mut feed = ImpairedFeed::new(impairment.clone(), connection).unwrap();
+            (1..).find(|id: &u64| feed.process(Some(Message::Text(id.to_string()))).is_err()).unwrap()
+        };
+        assert_eq!(disconnect_at(0), disconnect_at(0), "a seeded connection plays out the same way");
+        let frames: Vec<u64> = (0..5).map(disconnect_at).collect();
+        assert!(frames.windows(2).any(|pair| pair[0] != pair[1]), "every connection failed on frame {}", frames[0]);
+    }
+
+    #[test]
+    fn held_frames_are_released_without_further_frames() {
+        let mut feed = feed("reorder=1,reorder_delay_ms=20,seed=1");
+        assert!(feed.process(Some(Message::Text("1".to_string()))).unwrap().is_empty());
+        let at = feed.release_at().expect("the frame is held back");
+        assert!(feed.process(None).unwrap().is_empty());
+        thread::sleep(at.saturating_duration_since(Instant::now()));
+        assert_eq!(feed.process(None).unwrap(), vec![Message::Text("1".to_string())]);
+        assert!(feed.release_at().is_none());
+    }
+
+    #[test]
+    fn settings_are_range_checked() {
+        for spec in ["latency_ms=inf", "latency_ms=NaN", "jitter_ms=-1", "reorder_delay_ms=-5", "reorder=1.5", "drop=-0.1", "disconnect=NaN", "latency_ms", "loss=0.1"] {
+            assert!(spec.parse::<Impairment>().is_err(), "{} was accepted", spec);
+        }
+        let impairment: Impairment = "latency_ms=50,jitter_ms=20,reorder=0.05,drop=0,disconnect=1,seed=9".parse().unwrap();
+        assert_eq!((impairment.latency_ms, impairment.reorder_delay_ms, impairment.disconnect, impairment.seed), (50.0, 100.0, 1.0, Some(9)));
+        assert!(ImpairedFeed::new(Impairment { reorder_delay_ms: f64::INFINITY, ..Impairment::default() }, 0).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, fs, path::Path};
+use serde::{Serialize, Deserialize};
+use crate::{AppError, models::InstrumentKind};
+
+/// How one venue lists an instrument.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Listing {
+    /// The venue's name for the pair, e.g. `btcusd` on Bitstamp or `BTCUSDT` on Binance.
+    pub symbol: String,
+    /// Channels, streams or topics to subscribe to, named as the venue names them.
+    pub channels: Vec<String>,
+    /// Venue-specific price and size increments where they differ from the instrument's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tick_size: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lot_size: Option<f64>,
+    /// Base units per contract on venues that quote sizes in contracts, such as OKX swaps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract_size: Option<f64>,
+}
+
+impl Listing {
+    pub fn new(symbol: &str, channels: &[&str]) -> Listing {
+        Listing { symbol: symbol.to_string(), channels: channels.iter().map(|c| c.to_string()).collect(), tick_size: None, lot_size: None, contract_size: None }
+    }
+
+    /// The first channel, for venues that stream a book over a single one.
+    pub fn channel(&self) -> Result<&str, AppError> {
+        self.channels.first().map(String::as_str).ok_or_else(|| AppError::ParsingFailed(format!("listing {} has no channel", self.symbol)))
+    }
+}
+
+/// A canonical instrument and the venues it is followed on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Instrument {
+    /// Name used in requests and responses, e.g. `BTC/USD`.
+    pub name: String,
+    pub base: String,
+    pub quote: String,
+    pub tick_size: f64,
+    pub lot_size: f64,
+    #[serde(default)]
+    pub kind: InstrumentKind,
+    /// Listings keyed by exchange name.
+    pub venues: BTreeMap<String, Listing>,
+}
+
+impl Instrument {
+    pub fn tick_size_on(&self, listing: &Listing) -> f64 {listing.tick_size.unwrap_or(self.tick_size)}
+
+    pub fn lot_size_on(&self, listing: &Listing) -> f64 {listing.lot_size.unwrap_or(self.lot_size)}
+}
+
+/// Number of decimal places of an increment such as a tick or lot size, e.g. 2 for 0.01.
+pub fn decimals(step: f64) -> usize {(-step.log10()).round().max(0.0) as usize}
+
+/// The instruments to follow. A plain list, so misspelt keys are caught by [`Instrument`] and [`Listing`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Registry {
+    pub instruments: Vec<Instrument>,
+}
+
+impl Default for Registry {
+    /// BTC against USD, USDT and as a USDT-margined perpetual on the supported venues.
+    fn default() -> Registry {
+        let kraken = Listing { tick_size: Some(0.1), ..Listing::new("BTC/USD", &["book"]) };
+        let okx = Listing { contract_size: Some(0.01), ..Listing::new("BTC-USDT-SWAP", &["books"]) };
+        let instruments = vec![
+            Instrument {
+                name: "BTC/USD".to_string(), base: "BTC".to_string(), quote: "USD".to_string(), tick_size: 0.01, lot_size: 1e-8, kind: InstrumentKind::Spot,
+                venues: BTreeMap::from([
+                    ("Bitstamp".to_string(), Listing::new("btcusd", &["live_trades_btcusd", "live_orders_btcusd"])),
+                    ("Kraken".to_string(), kraken),
+                    ("Coinbase".to_string(), Listing::new("BTC-USD", &["level2_batch", "matches", "heartbeat"])),
+                ]),
+            },
+            Instrument {
+                name: "BTC/USDT".to_string(), base: "BTC".to_string(), quote: "USDT".to_string(), tick_size: 0.01, lot_size: 1e-5, kind: InstrumentKind::Spot,
+                venues: BTreeMap::from([("Binance".to_string(), Listing::new("BTCUSDT", &["btcusdt@depth10@100ms"]))]),
+            },
+            Instrument {
+                name: "BTC/USDT-PERP".to_string(), base: "BTC".to_string(), quote: "USDT".to_string(), tick_size: 0.1, lot_size: 0.001, kind: InstrumentKind::Perpetual,
+                venues: BTreeMap::from([
+                    ("OKX".to_string(), okx),
+                    ("Bybit".to_string(), Listing::new("BTCUSDT", &["orderbook.50.BTCUSDT"])),
+                ]),
+            },
+        ];
+        Registry { instruments }
+    }
+}
+
+impl Registry {
+    /// Loads a JSON array of instruments. Unlike fees, the file replaces the defaults: it lists everything to follow.
+    pub fn load(path: &Path) -> Result<Registry, AppError> {
+        let text = fs::read_to_string(path).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&text).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Instrument> {self.instruments.iter().find(|instrument| instrument.name == name)}
+
+    /// The currency to quote a view of every instrument of `kind` in: none when they share a quote currency,
+    /// otherwise the first one's, so that prices in different currencies are never ranked against each other.
+    pub fn merge_currency(&self, kind: InstrumentKind) -> Option<&str> {
+        let mut quotes = self.instruments.iter().filter(|instrument| instrument.kind == kind).map(|instrument| instrument.quote.as_str());
+        let first = quotes.next()?;
+        quotes.any(|quote| quote != first).then_some(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let instrument = |extra: &str, listing_extra: &str| format!(r#"[{{"name": "BTC/USD", "base": "BTC", "quote": "USD", "tick_size": 0.01, "lot_size": 1e-8{}, "venues": {{"Kraken": {{"symbol": "BTC/USD", "channels": ["book"]{}}}}}}}]"#, extra, listing_extra);
+        assert!(serde_json::from_str::<Registry>(&instrument("", "")).is_ok());
+        assert!(serde_json::from_str::<Registry>(&instrument(r#", "tick": 0.1"#, "")).unwrap_err().to_string().contains("unknown field `tick`"));
+        assert!(serde_json::from_str::<Registry>(&instrument("", r#", "contractsize": 0.01"#)).unwrap_err().to_string().contains("unknown field `contractsize`"));
+    }
+
+    #[test]
+    fn mixed_quote_currencies_are_merged_in_the_first() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.merge_currency(InstrumentKind::Spot), Some("USD"));
+        assert_eq!(registry.merge_currency(InstrumentKind::Perpetual), None);
+        registry.instruments.retain(|instrument| instrument.quote == "USDT");
+        assert_eq!(registry.merge_currency(InstrumentKind::Spot), None);
+        assert_eq!(Registry { instruments: Vec::new() }.merge_currency(InstrumentKind::Spot), None);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use crate::models::{LimitPrice, OrderBook};
+
+/// Relative tolerance when comparing a level's size with the sum of its orders.
+const SIZE_TOLERANCE: f64 = 1e-9;
+/// Levels per side covered by Kraken's book checksum.
+const KRAKEN_CHECKSUM_LEVELS: usize = 10;
+/// Levels per side covered by OKX's book checksum.
+const OKX_CHECKSUM_LEVELS: usize = 25;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityError {
+    /// A level's running `size` no longer equals the sum of its order amounts.
+    SizeMismatch { side: &'static str, price: f64, size: f64, orders: f64 },
+    NegativeSize { side: &'static str, price: f64, size: f64 },
+    /// Bids must be strictly descending and asks strictly ascending.
+    Unordered { side: &'static str, price: f64, previous: f64 },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityError::SizeMismatch { side, price, size, orders } => write!(f, "{} level {} has size {} but its orders sum to {}", side, price, size, orders),
+            IntegrityError::NegativeSize { side, price, size } => write!(f, "{} level {} has negative size {}", side, price, size),
+            IntegrityError::Unordered { side, price, previous } => write!(f, "{} level {} is out of order after {}", side, price, previous),
+        }
+    }
+}
+
+/// Checks the invariants of a locally maintained book. Levels without individual orders
+/// (L2 feeds such as Binance depth) are only checked for ordering and sign.
+pub fn check_book(book: &OrderBook) -> Result<(), IntegrityError> {
+    check_side("bid", &book.bids, |price, previous| price < previous)?;
+    check_side("ask", &book.asks, |price, previous| price > previous)
+}
+
+fn check_side(side: &'static str, levels: &[LimitPrice], in_order: impl Fn(f64, f64) -> bool) -> Result<(), IntegrityError> {
+    let mut previous: Option<f64> = None;
+    for level in levels {
+        let price = level.price.into_inner();
+        let size = level.size.into_inner();
+        if let Some(previous) = previous {
+            if !in_order(price, previous) {return Err(IntegrityError::Unordered { side, price, previous });}
+        }
+        previous = Some(price);
+
+        if size < 0.0 {return Err(IntegrityError::NegativeSize { side, price, size });}
+        if !level.orders.is_empty() {
+            let orders: f64 = level.orders.iter().map(|o| o.amount).sum();
+            if (size - orders).abs() > SIZE_TOLERANCE * orders.abs().max(1.0) {return Err(IntegrityError::SizeMismatch { side, price, size, orders });}
+        }
+    }
+    Ok(())
+}
+
+/// Kraken's CRC32 over the top asks (lowest first) followed by the top bids (highest first). Each level
+/// contributes its price and then its quantity, formatted at the instrument's precision with the decimal
+/// point and leading zeros removed.
+pub fn kraken_checksum(book: &OrderBook, price_precision: usize, qty_precision: usize) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for level in book.asks.iter().take(KRAKEN_CHECKSUM_LEVELS).chain(book.bids_best_first().take(KRAKEN_CHECKSUM_LEVELS)) {
+        for (value, precision) in [(level.price.into_inner(), price_precision), (level.size.into_inner(), qty_precision)] {
+            let digits = format!("{:.*}", precision, value).replace('.', "");
+            hasher.update(digits.trim_start_matches('0').as_bytes());
+        }
+    }
+    hasher.finalize()
+}
+
+/// OKX's CRC32 over the top bids and asks interleaved (bid, ask, bid, ask, ...). Each level is written as
+/// `price:size` using the venue's original strings, and the levels are joined with ':'. OKX sends it as a signed 32-bit integer.
+pub fn okx_checksum<S: AsRef<str>>(bids: &[(S, S)], asks: &[(S, S)]) -> i32 {
+    let mut fields = Vec::new();
+    for i in 0..OKX_CHECKSUM_LEVELS {
+        for side in [bids, asks] {
+            if let Some((price, size)) = side.get(i) {fields.push(format!("{}:{}", price.as_ref(), size.as_ref()));}
+        }
+    }
+    crc32fast::hash(fields.join(":").as_bytes()) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ordered_float::OrderedFloat;
+    use crate::models::{Order, OrderType};
+
+    fn level(price: f64, size: f64, amounts: &[f64]) -> LimitPrice {
+        let orders = amounts.iter().enumerate().map(|(i, &amount)| Order { id: i as u64, id_str: i.to_string(), order_type: OrderType::Buy as u8, datetime: String::new(), microtimestamp: String::new(), amount, amount_str: amount.to_string(), price, price_str: price.to_string() }).collect();
+        LimitPrice { price: OrderedFloat(price), size: OrderedFloat(size), orders }
+    }
+
+    fn book(bids: Vec<LimitPrice>, asks: Vec<LimitPrice>) -> OrderBook {OrderBook { bids, asks, ..OrderBook::default() }}
+
+    #[test]
+    fn a_consistent_book_passes() {
+        assert_eq!(check_book(&book(vec![level(100.0, 3.0, &[1.0, 2.0]), level(99.0, 1.0, &[])], vec![level(101.0, 0.1 + 0.2, &[0.1, 0.2]), level(102.0, 5.0, &[])])), Ok(()));
+        assert_eq!(check_book(&OrderBook::default()), Ok(()));
+    }
+
+    #[test]
+    fn levels_must_be_strictly_ordered() {
+        assert_eq!(check_book(&book(vec![level(99.0, 1.0, &[]), level(100.0, 1.0, &[])], vec![])), Err(IntegrityError::Unordered { side: "bid", price: 100.0, previous: 99.0 }));
+        assert_eq!(check_book(&book(vec![], vec![level(101.0, 1.0, &[]), level(101.0, 1.0, &[])])), Err(IntegrityError::Unordered { side: "ask", price: 101.0, previous: 101.0 }));
+    }
+
+    #[test]
+    fn sizes_must_be_non_negative_and_match_their_orders() {
+        assert_eq!(check_book(&book(vec![level(100.0, -1.0, &[])], vec![])), Err(IntegrityError::NegativeSize { side: "bid", price: 100.0, size: -1.0 }));
+        assert_eq!(check_book(&book(vec![], vec![level(101.0, 2.0, &[1.0, 0.5])])), Err(IntegrityError::SizeMismatch { side: "ask", price: 101.0, size: 2.0, orders: 1.5 }));
+    }
+}
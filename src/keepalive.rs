@@ -0,0 +1,62 @@
+use std::{io, time::{Duration, Instant}};
+use tungstenite::{stream::Stream, Message};
+use crate::{AppError, STOP_CHECK_INTERVAL, Socket};
+
+/// Application-level ping/pong for venues that close connections which do not ping them regularly.
+/// Blocking reads time out at least every `interval` so a ping can go out even while the feed is quiet.
+pub struct Keepalive {
+    venue: &'static str,
+    interval: Duration,
+    ping: String,
+    last_ping: Instant,
+    /// When the oldest unanswered ping was sent.
+    unanswered_since: Option<Instant>,
+    /// The read timeout currently set on the socket.
+    timeout: Duration,
+}
+
+/// Sets how long a blocking read of `socket` waits; `None` waits indefinitely. A zero timeout is raised to 1 ms, since
+/// sockets reject it.
+pub fn set_read_timeout(socket: &mut Socket, timeout: Option<Duration>) -> io::Result<()> {
+    let stream = match socket.get_ref() {
+        Stream::Plain(stream) => stream,
+        Stream::Tls(stream) => stream.get_ref(),
+    };
+    stream.set_read_timeout(timeout.map(|timeout| timeout.max(Duration::from_millis(1))))
+}
+
+impl Keepalive {
+    pub fn new(socket: &mut Socket, venue: &'static str, interval: Duration, ping: String) -> Result<Keepalive, AppError> {
+        set_read_timeout(socket, Some(interval)).map_err(|e| AppError::ConnectionFailed(format!("{}: {}", venue, e)))?;
+        Ok(Keepalive { venue, interval, ping, last_ping: Instant::now(), unanswered_since: None, timeout: interval })
+    }
+
+    /// Reads the next frame, pinging every `interval`. Fails when a ping has gone unanswered for two intervals.
+    /// Returns `None` without a frame after [`STOP_CHECK_INTERVAL`], so that the connector sees when it is stopped, or
+    /// once `release_at` passes, which is when the impairment layer has a held-back frame due.
+    pub fn read(&mut self, socket: &mut Socket, release_at: Option<Instant>) -> Result<Option<Message>, AppError> {
+        if self.last_ping.elapsed() >= self.interval {
+            if self.unanswered_since.is_some_and(|sent| sent.elapsed() >= 2 * self.interval) {
+                return Err(AppError::ConnectionFailed(format!("{} did not answer ping", self.venue)));
+            }
+            socket.write_message(Message::Text(self.ping.clone())).map_err(|e| AppError::MessageError(e.to_string()))?;
+            self.last_ping = Instant::now();
+            self.unanswered_since.get_or_insert(self.last_ping);
+        }
+        if release_at.is_some_and(|at| at <= Instant::now()) {return Ok(None);}
+        let wait = self.interval.min(STOP_CHECK_INTERVAL);
+        let timeout = release_at.map_or(wait, |at| at.saturating_duration_since(Instant::now()).min(wait));
+        if timeout != self.timeout {
+            set_read_timeout(socket, Some(timeout)).map_err(|e| AppError::ConnectionFailed(format!("{}: {}", self.venue, e)))?;
+            self.timeout = timeout;
+        }
+        match socket.read_message() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(AppError::MessageError(e.to_string())),
+        }
+    }
+
+    /// Call when the venue's pong arrives.
+    pub fn pong(&mut self) {self.unanswered_since = None;}
+}
@@ -0,0 +1,270 @@
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tungstenite::{connect, Message};
+use url::Url;
+use crate::{AppError, FeedConfig, Socket, VENUE_BOOKS, log_crossing, integrity, instruments::decimals, models::{OrderBook, OrderType}, impairment};
+
+/// Levels per side of the subscription. The local book is truncated to this depth after every message, as Kraken expects.
+pub const BOOK_DEPTH: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BookLevel { pub price: f64, pub qty: f64 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookData {
+    pub symbol: String,
+    #[serde(default)]
+    pub bids: Vec<BookLevel>,
+    #[serde(default)]
+    pub asks: Vec<BookLevel>,
+    /// CRC32 of the top of the book after this message has been applied.
+    pub checksum: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+/// A message on the v2 `book` channel; `kind` is "snapshot" or "update".
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookMessage { pub channel: String, #[serde(rename = "type")] pub kind: String, pub data: Vec<BookData> }
+
+/// `method` is "subscribe" or "unsubscribe".
+pub fn book_request(method: &str, channel: &str, symbol: &str) -> String {
+    json!({"method": method, "params": {"channel": channel, "symbol": [symbol], "depth": BOOK_DEPTH}}).to_string()
+}
+
+/// Applies a snapshot or update to `book`. Returns false if the result does not match Kraken's checksum.
+/// The precisions are the decimal places of the pair's prices and quantities, needed to rebuild the strings
+/// the checksum is computed over.
+pub fn apply(book: &mut OrderBook, message: &BookMessage, price_precision: usize, qty_precision: usize) -> bool {
+    for data in &message.data {
+        if message.kind == "snapshot" {
+            book.bids.clear();
+            book.asks.clear();
+        }
+        for level in &data.bids {book.set_level(OrderType::Buy as u8, level.price, level.qty);}
+        for level in &data.asks {book.set_level(OrderType::Sell as u8, level.price, level.qty);}
+        book.truncate(BOOK_DEPTH);
+        if integrity::kraken_checksum(book, price_precision, qty_precision) != data.checksum {return false;}
+    }
+    true
+}
+
+/// Progress of the book subscription on the current connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Subscription {
+    /// Updates may still arrive from a previous subscription and are ignored.
+    Requested,
+    /// The snapshot is next; an update now means it was lost.
+    Acknowledged,
+    Synced,
+}
+
+/// What to do with the connection after a book message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Ignored,
+    Applied,
+    Resubscribe(&'static str),
+}
+
+/// Applies a book message according to the subscription's progress, advancing it on a snapshot.
+fn step(order_book: &mut OrderBook, message: &BookMessage, subscription: &mut Subscription, price_precision: usize, qty_precision: usize) -> Step {
+    match (message.kind.as_str(), *subscription) {
+        ("update", Subscription::Requested) => return Step::Ignored,
+        ("update", Subscription::Acknowledged) => return Step::Resubscribe("update before snapshot"),
+        _ => *subscription = Subscription::Synced,
+    }
+    if apply(order_book, message, price_precision, qty_precision) {Step::Applied} else {Step::Resubscribe("checksum mismatch")}
+}
+
+/// Asks for a fresh snapshot. The book must already be cleared and marked stale; the requests are sent without holding
+/// the books' lock.
+fn resubscribe(socket: &mut Socket, feed: &FeedConfig, reason: &str) -> Result<Subscription, AppError> {
+    eprintln!("{} {}. Resubscribing.", feed.key, reason);
+    let channel = feed.listing.channel()?;
+    socket.write_message(Message::Text(book_request("unsubscribe", channel, &feed.listing.symbol))).map_err(|e| AppError::MessageError(e.to_string()))?;
+    socket.write_message(Message::Text(book_request("subscribe", channel, &feed.listing.symbol))).map_err(|e| AppError::MessageError(e.to_string()))?;
+    Ok(Subscription::Requested)
+}
+
+/// Maintains the Kraken venue book from the v2 `book` channel, resubscribing for a fresh snapshot whenever
+/// the local book stops matching the checksum.
+pub fn pull(feed: &FeedConfig) -> Result<(), AppError> {
+    let channel = feed.listing.channel()?;
+    let price_precision = decimals(feed.instrument.tick_size_on(&feed.listing));
+    let qty_precision = decimals(feed.instrument.lot_size_on(&feed.listing));
+    let mut impaired = feed.impaired()?;
+    let (mut socket, _response) = connect(Url::parse(&feed.url)?).map_err(|_| AppError::ConnectionFailed(feed.key.to_string()))?;
+    match feed.book(&mut VENUE_BOOKS.lock().unwrap()) {Some(order_book) => order_book.resync(), None => return Ok(())}
+    socket.write_message(Message::Text(book_request("subscribe", channel, &feed.listing.symbol))).map_err(|e| AppError::MessageError(e.to_string()))?;
+    let mut subscription = Subscription::Requested;
+
+    loop {
+        let msg = impairment::read(&mut socket, impaired.as_ref())?;
+        if feed.stopped() {return Ok(());}
+        if let (Some(recorder), Some(msg)) = (&feed.recorder, &msg) {recorder.lock().unwrap().record(&feed.key, msg).map_err(|e| AppError::RecordingFailed(e.to_string()))?;}
+        let frames = match impaired.as_mut() {Some(impaired) => impaired.process(msg)?, None => msg.into_iter().collect()};
+
+        for msg in frames {
+            let Message::Text(text) = msg else {continue};
+            let value: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+            if value["success"] == false {return Err(AppError::ConnectionFailed(format!("{} rejected {}: {}", feed.key, value["method"], value["error"])));}
+            if value["method"] == "subscribe" && subscription == Subscription::Requested {subscription = Subscription::Acknowledged;}
+
+            let mut books = VENUE_BOOKS.lock().unwrap();
+            let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+            let resync = match value["channel"].as_str() {
+                Some(name) if name == channel => {
+                    let message: BookMessage = serde_json::from_value(value).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                    match step(order_book, &message, &mut subscription, price_precision, qty_precision) {
+                        Step::Ignored => None,
+                        Step::Applied => {
+                            order_book.touch(None);
+                            log_crossing(&feed.key, order_book);
+                            None
+                        }
+                        Step::Resubscribe(reason) => {
+                            order_book.clear();
+                            order_book.mark_stale(reason.to_string());
+                            Some(reason)
+                        }
+                    }
+                }
+                // A quiet book is still current as long as the connection is alive.
+                Some("heartbeat") if order_book.is_live() => {order_book.touch(None); None}
+                _ => None,
+            };
+            drop(books);
+            if let Some(reason) = resync {subscription = resubscribe(&mut socket, feed, reason)?;}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc}, thread};
+    use crate::{instruments::Registry, models::{BookKey, FeedStatus}, recorder::RecordedFrame};
+
+    /// BTC/USD, one decimal place in prices and eight in quantities.
+    const SNAPSHOT: &str = r#"{"channel":"book","type":"snapshot","data":[{"symbol":"BTC/USD","bids":[{"price":64000.0,"qty":0.25},{"price":63999.5,"qty":0.35},{"price":63999.0,"qty":0.45},{"price":63998.5,"qty":0.55},{"price":63998.0,"qty":0.0015},{"price":63997.5,"qty":0.75},{"price":63997.0,"qty":0.85},{"price":63996.5,"qty":0.95},{"price":63996.0,"qty":1.05},{"price":63995.5,"qty":1.15}],"asks":[{"price":64000.5,"qty":0.3},{"price":64001.0,"qty":0.35},{"price":64001.5,"qty":0.4},{"price":64002.0,"qty":0.45},{"price":64002.5,"qty":0.5},{"price":64003.0,"qty":0.55},{"price":64003.5,"qty":0.6},{"price":64004.0,"qty":0.65},{"price":64004.5,"qty":0.7},{"price":64005.0,"qty":0.75}],"checksum":1805065658}]}"#;
+    /// A new best bid that pushes the tenth bid out, the best ask filled and a size change.
+    const UPDATE: &str = r#"{"channel":"book","type":"update","data":[{"symbol":"BTC/USD","bids":[{"price":64000.2,"qty":0.012}],"asks":[{"price":64000.5,"qty":0.0},{"price":64001.5,"qty":1.2}],"checksum":2142565987,"timestamp":"2024-05-01T12:00:00.123456Z"}]}"#;
+
+    fn message(text: &str) -> BookMessage {serde_json::from_str(text).unwrap()}
+
+    #[test]
+    fn checksum_drops_the_point_and_leading_zeros() {
+        let mut book = OrderBook::default();
+        book.set_level(OrderType::Sell as u8, 0.05005, 0.000005);
+        book.set_level(OrderType::Buy as u8, 0.05, 1.5);
+        // CRC32 of "5005" "500" "5000" "150000000".
+        assert_eq!(integrity::kraken_checksum(&book, 5, 8), 592186002);
+    }
+
+    #[test]
+    fn snapshot_and_update_match_their_checksums() {
+        let mut book = OrderBook::default();
+        assert!(apply(&mut book, &message(SNAPSHOT), 1, 8));
+        assert!(apply(&mut book, &message(UPDATE), 1, 8));
+        assert_eq!((book.bids.len(), book.asks.len()), (BOOK_DEPTH, 9));
+        assert_eq!((book.bids[0].price.into_inner(), book.bids[BOOK_DEPTH - 1].price.into_inner()), (64000.2, 63996.0));
+        assert_eq!((book.asks[0].price.into_inner(), book.asks[1].size.into_inner()), (64001.0, 1.2));
+    }
+
+    #[test]
+    fn a_checksum_mismatch_resubscribes() {
+        let mut book = OrderBook::default();
+        let mut subscription = Subscription::Acknowledged;
+        assert_eq!(step(&mut book, &message(SNAPSHOT), &mut subscription, 1, 8), Step::Applied);
+        assert_eq!(subscription, Subscription::Synced);
+
+        let mut corrupted = message(UPDATE);
+        corrupted.data[0].bids[0].qty = 0.013;
+        assert_eq!(step(&mut book, &corrupted, &mut subscription, 1, 8), Step::Resubscribe("checksum mismatch"));
+        // A wrong precision rebuilds different strings and fails the same way.
+        assert_eq!(step(&mut OrderBook::default(), &message(SNAPSHOT), &mut subscription, 2, 8), Step::Resubscribe("checksum mismatch"));
+    }
+
+    #[test]
+    fn updates_wait_for_the_snapshot() {
+        let mut book = OrderBook::default();
+        let mut subscription = Subscription::Requested;
+        assert_eq!(step(&mut book, &message(UPDATE), &mut subscription, 1, 8), Step::Ignored);
+        assert!(book.bids.is_empty());
+        subscription = Subscription::Acknowledged;
+        assert_eq!(step(&mut book, &message(UPDATE), &mut subscription, 1, 8), Step::Resubscribe("update before snapshot"));
+        assert_eq!(subscription, Subscription::Acknowledged);
+    }
+
+    /// Replays recorded frames to the first client of `listener`. A recorded reply to a request, a frame with a
+    /// `method`, is only sent once the client has made that request, which `on_request` is told of. Once the last frame
+    /// is sent `replayed` is signalled, then a heartbeat is sent when `done` is, and the connection is closed.
+    fn replay(listener: TcpListener, frames: Vec<RecordedFrame>, mut on_request: impl FnMut(&str), replayed: mpsc::Sender<()>, done: mpsc::Receiver<()>) {
+        let mut socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+        for frame in frames {
+            let payload: serde_json::Value = serde_json::from_str(&frame.payload).unwrap();
+            if let Some(method) = payload["method"].as_str() {
+                loop {
+                    let request: serde_json::Value = serde_json::from_str(socket.read_message().unwrap().to_text().unwrap()).unwrap();
+                    let requested = request["method"].as_str().unwrap().to_string();
+                    on_request(&requested);
+                    if requested == method {break;}
+                }
+            }
+            socket.write_message(Message::Text(frame.payload)).unwrap();
+        }
+        replayed.send(()).unwrap();
+        done.recv().unwrap();
+        socket.write_message(Message::Text(r#"{"channel":"heartbeat"}"#.to_string())).unwrap();
+        let _ = socket.close(None);
+    }
+
+    #[test]
+    fn the_connector_resubscribes_on_a_checksum_mismatch_from_a_recorded_session() {
+        let frames: Vec<RecordedFrame> = include_str!("../fixtures/kraken-book.jsonl").lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Registry::default();
+        let instrument = registry.get("BTC/USD").unwrap().clone();
+        // Its own key, so that other tests using the global books are not disturbed.
+        let key = BookKey::new("Kraken", "BTC/USD replayed");
+        let stop = Arc::new(AtomicBool::new(false));
+        let feed = FeedConfig { key: key.clone(), listing: instrument.venues["Kraken"].clone(), instrument, url: format!("ws://{}", addr), recorder: None, impairment: None, stop: stop.clone(), connections: AtomicU64::new(0) };
+
+        let (done, finished) = mpsc::channel();
+        let (replayed, all_sent) = mpsc::channel();
+        let server_key = key.clone();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            replay(listener, frames, |method| {
+                // The connector asks again only after marking its book stale, and without holding the lock.
+                if requests.len() == 2 {
+                    let books = VENUE_BOOKS.lock().unwrap();
+                    assert_eq!(books[&server_key].status, FeedStatus::Stale("checksum mismatch".to_string()));
+                    assert!(books[&server_key].bids.is_empty());
+                }
+                requests.push(method.to_string());
+            }, replayed, finished);
+            requests
+        });
+        let connector = thread::spawn(move || pull(&feed));
+
+        // The update moved the best ask, so after the replay it is back only once the second snapshot is applied.
+        all_sent.recv_timeout(std::time::Duration::from_secs(10)).expect("the connector did not resubscribe");
+        let snapshot_is_back = || VENUE_BOOKS.lock().unwrap().get(&key).is_some_and(|book| book.is_live() && book.asks.first().is_some_and(|ask| ask.price.into_inner() == 64000.5));
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !snapshot_is_back() {
+            assert!(std::time::Instant::now() < deadline, "the connector did not rebuild the book");
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        stop.store(true, Ordering::Relaxed);
+        done.send(()).unwrap();
+        assert_eq!(server.join().unwrap(), vec!["subscribe", "unsubscribe", "subscribe"]);
+        assert!(connector.join().unwrap().is_ok());
+
+        let book = VENUE_BOOKS.lock().unwrap().remove(&key).unwrap();
+        assert_eq!((book.bids.len(), book.asks.len()), (BOOK_DEPTH, BOOK_DEPTH));
+        assert_eq!((book.bids[0].price.into_inner(), book.bids[0].size.into_inner()), (64000.0, 0.25), "the book is the new snapshot, not the corrupted update");
+    }
+}
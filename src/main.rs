@@ -1,55 +1,328 @@
 use tungstenite::{connect, Message};
 use url::Url;
 use chrono::Utc;
-use ordered_float::OrderedFloat;
 use serde_json::json;
 use futures::SinkExt;
-use models::{OrderType, OrderBook, LimitPrice, Data, Msg};
-use tonic::{transport::Server, Request, Response, Status};
-use std::{thread, fmt, time::Instant, sync::{Arc, Mutex}};
+use models::{BookKey, OrderBook, OrderType, InstrumentKind, Data, Msg, Sequence};
+use recorder::Recorder;
+use impairment::{Impairment, ImpairedFeed};
+use fees::FeeTable;
+use fx::FxTable;
+use candles::CandleBuilder;
+use spreads::SpreadHistory;
+use snapshots::Snapshot;
+use config::Config;
+use store::Store;
+use instruments::{Instrument, Listing, Registry};
+use clap::{Args, Parser, Subcommand};
+use tonic::{transport::{Identity, Server, ServerTlsConfig}, Request, Response, Status};
+use tokio::sync::broadcast;
+use std::{env, fs, io::{self, IsTerminal}, thread, fmt, collections::HashMap, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, path::{Path, PathBuf}, time::{Duration, Instant}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}};
 mod models;
-
-static BINANCE_WS_API: &str = "wss://stream.binance.com:9443/ws/btcusd@depth20@100ms";
+mod aggregator;
+mod analytics;
+mod impact;
+mod candles;
+mod spreads;
+mod snapshots;
+mod export;
+mod config;
+mod replay;
+mod reload;
+mod tui;
+mod store;
+mod arbitrage;
+mod fees;
+mod fx;
+mod integrity;
+mod recorder;
+mod impairment;
+mod simulator;
+mod kraken;
+mod coinbase;
+mod okx;
+mod bybit;
+mod keepalive;
+mod instruments;
+
+static BINANCE_WS_API: &str = "wss://stream.binance.com:9443";
+static BITSTAMP_WS_API: &str = "wss://ws.bitstamp.net";
+static KRAKEN_WS_API: &str = "wss://ws.kraken.com/v2";
+static COINBASE_WS_API: &str = "wss://ws-feed.exchange.coinbase.com";
+static OKX_WS_API: &str = "wss://ws.okx.com:8443/ws/v5/public";
+static BYBIT_WS_API: &str = "wss://stream.bybit.com/v5/public/linear";
+/// Override the venue endpoints, e.g. to point the connectors at a local fake exchange.
+static BINANCE_URL_ENV: &str = "BINANCE_WS_URL";
+static BITSTAMP_URL_ENV: &str = "BITSTAMP_WS_URL";
+static KRAKEN_URL_ENV: &str = "KRAKEN_WS_URL";
+static COINBASE_URL_ENV: &str = "COINBASE_WS_URL";
+static OKX_URL_ENV: &str = "OKX_WS_URL";
+static BYBIT_URL_ENV: &str = "BYBIT_WS_URL";
+/// TOML configuration file, when `--config` names none.
+static CONFIG_FILE_ENV: &str = "EXCHANGE_CONFIG_FILE";
+/// When set, run only the synthetic fake exchange on this address instead of the aggregator.
+static SIMULATOR_ADDR_ENV: &str = "EXCHANGE_SIMULATOR_ADDR";
+const DEFAULT_LISTEN_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50051));
+/// Per-venue network impairment, e.g. `EXCHANGE_IMPAIRMENT_BINANCE=latency_ms=50,jitter_ms=20,drop=0.01`.
+static IMPAIRMENT_ENV_PREFIX: &str = "EXCHANGE_IMPAIRMENT_";
+static RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest a connector's blocking read waits, so that a stopped connector on a quiet feed still returns.
+static STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// When set, every received frame is written to a rotating compressed log in this directory.
+static RECORD_DIR_ENV: &str = "EXCHANGE_RECORD_DIR";
+/// JSON file of per-exchange maker/taker fees in basis points, overriding the built-in schedules.
+static FEES_FILE_ENV: &str = "EXCHANGE_FEES_FILE";
+/// JSON file of currency conversion rates or the books to take them from, added to the built-in table.
+static FX_FILE_ENV: &str = "EXCHANGE_FX_FILE";
+/// JSON file of the instruments to follow and their venue listings, replacing the built-in registry.
+static INSTRUMENTS_FILE_ENV: &str = "EXCHANGE_INSTRUMENTS_FILE";
+/// Levels per side and price grouping of the terminal UI ladder, e.g. `EXCHANGE_DISPLAY_GROUPING=10` or `0.1%`.
+static DISPLAY_DEPTH_ENV: &str = "EXCHANGE_DISPLAY_DEPTH";
+static DISPLAY_GROUPING_ENV: &str = "EXCHANGE_DISPLAY_GROUPING";
+static DEFAULT_DISPLAY_DEPTH: usize = 11;
+/// Candle intervals to build, e.g. `1s,1m,5m,1h` (the default), and a directory to persist closed candles in.
+static CANDLE_INTERVALS_ENV: &str = "EXCHANGE_CANDLE_INTERVALS";
+static CANDLES_DIR_ENV: &str = "EXCHANGE_CANDLES_DIR";
+/// How often top of book and spreads are sampled (default 1000 ms), and a directory to persist the samples in.
+static SPREAD_SAMPLE_MS_ENV: &str = "EXCHANGE_SPREAD_SAMPLE_MS";
+static SPREADS_DIR_ENV: &str = "EXCHANGE_SPREADS_DIR";
+/// Directory to snapshot every venue book into, how often in milliseconds (default 10000), and, when
+/// `EXCHANGE_WARM_START` is set, to restore the books from on startup.
+static SNAPSHOT_DIR_ENV: &str = "EXCHANGE_SNAPSHOT_DIR";
+static SNAPSHOT_INTERVAL_MS_ENV: &str = "EXCHANGE_SNAPSHOT_INTERVAL_MS";
+static WARM_START_ENV: &str = "EXCHANGE_WARM_START";
+/// SQLite database to store trades, closed candles and top of book samples in, for the history RPCs.
+static SQLITE_PATH_ENV: &str = "EXCHANGE_SQLITE_PATH";
+/// A venue book with no update for this many milliseconds is marked stale (default 5000).
+static STALE_AFTER_ENV: &str = "EXCHANGE_STALE_AFTER_MS";
+static DEFAULT_STALE_AFTER: Duration = Duration::from_secs(5);
 
 #[macro_use]
 extern crate lazy_static;
 
-lazy_static! {static ref COMBINED_ORDER_BOOK: Arc<Mutex<OrderBook>> = Arc::new(Mutex::new(OrderBook { bids: Vec::new(), asks: Vec::new() }));}
+lazy_static! {
+    static ref VENUE_BOOKS: Arc<Mutex<HashMap<BookKey, OrderBook>>> = Arc::new(Mutex::new(HashMap::new()));
+    /// Trades from every venue, normalized to the taker's side.
+    static ref TRADES: broadcast::Sender<TradeEvent> = broadcast::channel(1024).0;
+}
 
 pub mod orderbook {tonic::include_proto!("orderbook");}
 
-use crate::orderbook::{orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer}, Empty, Summary, Level};
+/// A blocking client connection as returned by `tungstenite::connect`.
+type Socket = tungstenite::WebSocket<tungstenite::client::AutoStream>;
+
+use crate::orderbook::{orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer}, SummaryRequest, Summary, Empty, ArbitrageOpportunity, TradeEvent, AnalyticsRequest, MarketAnalytics, ImpactRequest, ImpactEstimate, CandlesRequest, Candle, SpreadHistoryRequest, SpreadHistoryResponse, HistoryRequest, TradeHistoryResponse, CandleHistoryResponse, TopOfBookHistoryResponse};
+
+pub struct OrderbookService { fees: FeeTable, fx: FxTable, settings: Arc<Mutex<reload::Settings>>, opportunities: broadcast::Sender<ArbitrageOpportunity>, candles: Arc<Mutex<CandleBuilder>>, candle_updates: broadcast::Sender<Candle>, spreads: Arc<Mutex<SpreadHistory>>, store: Option<Arc<Mutex<Store>>> }
+
+/// The books a request asks about and the currency to quote them in.
+#[derive(Clone)]
+struct Selection { instrument: Option<String>, kind: InstrumentKind, quote_currency: Option<String>, fx: FxTable, instruments: Registry }
+
+impl Selection {
+    fn summary(&self, view: aggregator::View, fees: Option<&FeeTable>) -> Summary {
+        let books = VENUE_BOOKS.lock().unwrap();
+        let conversion = self.quote_currency.as_deref().map(|currency| self.fx.conversion(currency, &self.instruments, &books));
+        aggregator::consolidate(&books, self.instrument.as_deref(), self.kind, view, fees, conversion.as_ref())
+    }
+}
+
+impl OrderbookService {
+    /// The instruments as last configured.
+    fn instruments(&self) -> Registry {self.settings.lock().unwrap().instruments.clone()}
+
+    /// Checks the instrument against the registry and that some rate or instrument converts into the quote currency.
+    /// Without either, instruments of the kind quoted in different currencies are converted into one, see [`Registry::merge_currency`].
+    /// Errors are returned to the client as they are, hence `Status`.
+    #[allow(clippy::result_large_err)]
+    fn select(&self, instrument: &str, kind: InstrumentKind, quote_currency: &str) -> Result<Selection, Status> {
+        let instruments = self.instruments();
+        let instrument = match instrument {
+            "" => None,
+            name if instruments.get(name).is_some() => Some(name.to_string()),
+            name => return Err(Status::not_found(format!("unknown instrument {}", name))),
+        };
+        let quote_currency = match quote_currency {
+            "" if instrument.is_none() => instruments.merge_currency(kind).map(str::to_string),
+            "" => None,
+            currency if self.fx.knows(currency) || instruments.instruments.iter().any(|i| i.quote == currency) => Some(currency.to_string()),
+            currency => return Err(Status::invalid_argument(format!("no rate converts into {}", currency))),
+        };
+        Ok(Selection { instrument, kind, quote_currency, fx: self.fx.clone(), instruments })
+    }
 
-pub struct OrderbookService;
+    /// Checks the instrument and exchange of a history request and that there is a store to answer it from.
+    #[allow(clippy::result_large_err)]
+    fn history(&self, request: &HistoryRequest) -> Result<(Arc<Mutex<Store>>, store::Query), Status> {
+        let store = self.store.clone().ok_or_else(|| Status::failed_precondition(format!("history is kept only with recording.sqlite_path or {} set", SQLITE_PATH_ENV)))?;
+        let instrument = self.instruments().get(&request.instrument).cloned().ok_or_else(|| Status::not_found(format!("unknown instrument {}", request.instrument)))?;
+        if !request.exchange.is_empty() && !instrument.venues.contains_key(&request.exchange) {return Err(Status::not_found(format!("{} is not listed on {}", request.instrument, request.exchange)));}
+        let query = store::Query {
+            instrument: request.instrument.clone(),
+            exchange: Some(request.exchange.clone()).filter(|exchange| !exchange.is_empty()),
+            from_micros: request.from_micros,
+            to_micros: if request.to_micros == 0 {Utc::now().timestamp_micros()} else {request.to_micros},
+            limit: if request.limit == 0 {store::DEFAULT_LIMIT} else {request.limit as usize},
+        };
+        Ok((store, query))
+    }
+}
 
 #[tonic::async_trait]
 impl OrderbookAggregator for OrderbookService {
     type BookSummaryStream = futures::channel::mpsc::Receiver<Result<Summary, Status>>;
+    type ArbitrageStream = futures::channel::mpsc::Receiver<Result<ArbitrageOpportunity, Status>>;
+    type TradesStream = futures::channel::mpsc::Receiver<Result<TradeEvent, Status>>;
+    type AnalyticsStream = futures::channel::mpsc::Receiver<Result<MarketAnalytics, Status>>;
+    type CandlesStream = futures::channel::mpsc::Receiver<Result<Candle, Status>>;
 
-    async fn book_summary(&self, _request: Request<Empty>) -> Result<Response<Self::BookSummaryStream>, Status> {
+    async fn book_summary(&self, request: Request<SummaryRequest>) -> Result<Response<Self::BookSummaryStream>, Status> {
         let (mut tx, rx) = futures::channel::mpsc::channel(4);
+        let fees = if request.get_ref().apply_fees {Some(self.fees.clone())} else {None};
+        let selection = self.select(&request.get_ref().instrument, InstrumentKind::from(request.get_ref().kind()), &request.get_ref().quote_currency)?;
+        let grouping = aggregator::Grouping::new(request.get_ref().bucket_size, request.get_ref().bucket_percent).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let view = aggregator::View { depth: match request.get_ref().depth {0 => self.settings.lock().unwrap().summary_depth, depth => depth as usize}, grouping };
 
         tokio::spawn(async move {
             loop {
-                let (spread, top_bids, top_asks) = {
-                    let _combined_order_book = COMBINED_ORDER_BOOK.lock().unwrap();
-        
-                    let spread = if !_combined_order_book.bids.is_empty() && !_combined_order_book.asks.is_empty() {_combined_order_book.asks[0].price.into_inner() - _combined_order_book.bids[0].price.into_inner()} 
-                    else {0.0};
-        
-                    let top_bids: Vec<Level> = _combined_order_book.bids.iter().take(10).map(|bid| Level { exchange: "Bitstamp".to_string(), price: bid.price.into_inner(), amount: bid.size.into_inner() }).collect();
-                    let top_asks: Vec<Level> = _combined_order_book.asks.iter().take(10).map(|ask| Level { exchange: "Bitstamp".to_string(), price: ask.price.into_inner(), amount: ask.size.into_inner() }).collect();
-        
-                    (spread, top_bids, top_asks)
-                };
-        
-                let summary = Summary {spread,bids: top_bids,asks: top_asks,};
-        
-                tx.send(Ok(summary)).await.expect("Channel send failed");
+                let summary = selection.summary(view, fees.as_ref());
+
+                if tx.send(Ok(summary)).await.is_err() {break;}
                 tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
             }
         });
-        
+
+        Ok(Response::new(rx))
+    }
+
+    async fn analytics(&self, request: Request<AnalyticsRequest>) -> Result<Response<Self::AnalyticsStream>, Status> {
+        let (mut tx, rx) = futures::channel::mpsc::channel(4);
+        let request = request.into_inner();
+        let selection = self.select(&request.instrument, InstrumentKind::from(request.kind()), &request.quote_currency)?;
+        if request.depth_bps < 0.0 || request.vwap_amount < 0.0 {return Err(Status::invalid_argument("depth_bps and vwap_amount must not be negative"));}
+        let defaults = self.settings.lock().unwrap().analytics;
+        let parameters = analytics::Parameters {
+            imbalance_levels: match request.imbalance_levels {0 => defaults.imbalance_levels, levels => levels as usize},
+            depth_bps: if request.depth_bps > 0.0 {request.depth_bps} else {defaults.depth_bps},
+            vwap_amount: if request.vwap_amount > 0.0 {request.vwap_amount} else {defaults.vwap_amount},
+        };
+        // Every level of every book, ranked by raw price.
+        let view = aggregator::View { depth: usize::MAX, grouping: aggregator::Grouping::Raw };
+
+        tokio::spawn(async move {
+            loop {
+                let analytics = analytics::analyze(&selection.summary(view, None), parameters);
+
+                if tx.send(Ok(analytics)).await.is_err() {break;}
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    async fn impact(&self, request: Request<ImpactRequest>) -> Result<Response<ImpactEstimate>, Status> {
+        let request = request.into_inner();
+        let selection = self.select(&request.instrument, InstrumentKind::from(request.kind()), &request.quote_currency)?;
+        let target = match (request.quantity, request.notional) {
+            (quantity, 0.0) if quantity > 0.0 => impact::Target::Quantity(quantity),
+            (0.0, notional) if notional > 0.0 => impact::Target::Notional(notional),
+            _ => return Err(Status::invalid_argument("set exactly one of a positive quantity or notional")),
+        };
+        let summary = selection.summary(aggregator::View { depth: usize::MAX, grouping: aggregator::Grouping::Raw }, Some(&self.fees));
+        Ok(Response::new(impact::plan(&summary, request.side(), target, &self.fees)))
+    }
+
+    async fn candles(&self, request: Request<CandlesRequest>) -> Result<Response<Self::CandlesStream>, Status> {
+        let (mut tx, rx) = futures::channel::mpsc::channel(64);
+        let request = request.into_inner();
+        let instrument = self.instruments().get(&request.instrument).cloned().ok_or_else(|| Status::not_found(format!("unknown instrument {}", request.instrument)))?;
+        if !request.exchange.is_empty() && !instrument.venues.contains_key(&request.exchange) {return Err(Status::not_found(format!("{} is not listed on {}", request.instrument, request.exchange)));}
+        let interval_secs = candles::parse_interval(&request.interval).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if !self.candles.lock().unwrap().intervals().contains(&interval_secs) {return Err(Status::invalid_argument(format!("no {} candles are built", request.interval)));}
+        let series = candles::Series { exchange: request.exchange, instrument: request.instrument, interval_secs };
+        // Subscribe before reading the history so no update falls in between.
+        let mut updates = self.candle_updates.subscribe();
+        let history = self.candles.lock().unwrap().history(&series);
+
+        tokio::spawn(async move {
+            for candle in history {if tx.send(Ok(candle)).await.is_err() {return;}}
+            loop {
+                match updates.recv().await {
+                    Ok(candle) if candle.exchange == series.exchange && candle.instrument == series.instrument && candle.interval_secs == series.interval_secs => if tx.send(Ok(candle)).await.is_err() {break;},
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    async fn spread_history(&self, request: Request<SpreadHistoryRequest>) -> Result<Response<SpreadHistoryResponse>, Status> {
+        let request = request.into_inner();
+        if self.instruments().get(&request.instrument).is_none() {return Err(Status::not_found(format!("unknown instrument {}", request.instrument)));}
+        let to_micros = if request.to_micros == 0 {Utc::now().timestamp_micros()} else {request.to_micros};
+        let (buffered, archived) = self.spreads.lock().unwrap().query(&request.instrument, request.from_micros, to_micros);
+        let mut samples = match archived {
+            Some(archived) => tokio::task::spawn_blocking(move || archived.read()).await.map_err(|e| Status::internal(e.to_string()))?.map_err(|e| Status::internal(e.to_string()))?,
+            None => Vec::new(),
+        };
+        samples.extend(buffered);
+        Ok(Response::new(SpreadHistoryResponse { samples: spreads::downsample(samples, request.step_ms as i64 * 1000) }))
+    }
+
+    async fn trade_history(&self, request: Request<HistoryRequest>) -> Result<Response<TradeHistoryResponse>, Status> {
+        let (store, query) = self.history(request.get_ref())?;
+        let trades = store::blocking(store, move |store| store.trades(&query)).await.map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(TradeHistoryResponse { trades }))
+    }
+
+    async fn candle_history(&self, request: Request<HistoryRequest>) -> Result<Response<CandleHistoryResponse>, Status> {
+        let (store, mut query) = self.history(request.get_ref())?;
+        let interval_secs = candles::parse_interval(&request.get_ref().interval).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        // The series across venues is stored with an empty exchange.
+        query.exchange = Some(query.exchange.unwrap_or_default());
+        let candles = store::blocking(store, move |store| store.candles(&query, interval_secs)).await.map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(CandleHistoryResponse { candles }))
+    }
+
+    async fn top_of_book_history(&self, request: Request<HistoryRequest>) -> Result<Response<TopOfBookHistoryResponse>, Status> {
+        let (store, query) = self.history(request.get_ref())?;
+        let samples = store::blocking(store, move |store| store.top_of_book(&query)).await.map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(TopOfBookHistoryResponse { samples }))
+    }
+
+    async fn arbitrage(&self, _request: Request<Empty>) -> Result<Response<Self::ArbitrageStream>, Status> {
+        let (mut tx, rx) = futures::channel::mpsc::channel(16);
+        let mut updates = self.opportunities.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match updates.recv().await {
+                    Ok(opportunity) => if tx.send(Ok(opportunity)).await.is_err() {break;},
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    async fn trades(&self, _request: Request<Empty>) -> Result<Response<Self::TradesStream>, Status> {
+        let (mut tx, rx) = futures::channel::mpsc::channel(64);
+        let mut trades = TRADES.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match trades.recv().await {
+                    Ok(trade) => if tx.send(Ok(trade)).await.is_err() {break;},
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
 
         Ok(Response::new(rx))
     }
@@ -58,212 +331,295 @@ impl OrderbookAggregator for OrderbookService {
 impl From<url::ParseError> for AppError {fn from(err: url::ParseError) -> AppError {AppError::UrlParseError(err.to_string())}}
 
 #[derive(Debug)]
-enum AppError { ConnectionFailed(String), ParsingFailed(String), MessageError(String), AddrParseError(std::net::AddrParseError), UnknownError, UrlParseError(String)}
+pub enum AppError { ConnectionFailed(String), ParsingFailed(String), MessageError(String), AddrParseError(std::net::AddrParseError), UnknownError, UrlParseError(String), RecordingFailed(String), IntegrityFailed(String), SequenceGap(String), ExportFailed(String), StorageFailed(String), ConfigInvalid(String), TerminalFailed(String)}
 
-impl fmt::Display for AppError {fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {match self { AppError::ConnectionFailed(s) => write!(f, "Connection failed: {}", s), AppError::ParsingFailed(s) => write!(f, "Parsing failed: {}", s), AppError::MessageError(s) => write!(f, "Message error: {}", s), AppError::UnknownError => write!(f, "An unknown error occurred"), AppError::AddrParseError(e) => write!(f, "Address parsing error: {}", e), AppError::UrlParseError(e) => write!(f, "URL parsing error: {}", e)}}}
+impl fmt::Display for AppError {fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {match self { AppError::ConnectionFailed(s) => write!(f, "Connection failed: {}", s), AppError::ParsingFailed(s) => write!(f, "Parsing failed: {}", s), AppError::MessageError(s) => write!(f, "Message error: {}", s), AppError::UnknownError => write!(f, "An unknown error occurred"), AppError::AddrParseError(e) => write!(f, "Address parsing error: {}", e), AppError::UrlParseError(e) => write!(f, "URL parsing error: {}", e), AppError::RecordingFailed(e) => write!(f, "Recording failed: {}", e), AppError::IntegrityFailed(e) => write!(f, "Book integrity check failed: {}", e), AppError::SequenceGap(e) => write!(f, "Sequence gap: {}", e), AppError::ExportFailed(e) => write!(f, "Export failed: {}", e), AppError::StorageFailed(e) => write!(f, "Storage failed: {}", e), AppError::ConfigInvalid(e) => write!(f, "Invalid configuration: {}", e), AppError::TerminalFailed(e) => write!(f, "Terminal failed: {}", e)}}}
 
 impl std::error::Error for AppError {}
 
-fn print_order_book(order_book: &OrderBook) {
-    clearscreen::clear().expect("Error clearing screen");
-
-    println!("Status  : Connected");
-    println!("Exchange: Bitstamp.net");
-    println!("Symbol  : BTC/USD");
-    println!("Time    : {} UTC", Utc::now().format("%a %b %e %T %Y"));
-    println!();
-    println!("         Bids                    Asks");
-
-    let mut i = 0;
-    let mut rev_bids = order_book.bids.clone();
-    rev_bids.reverse();
-    for bid in rev_bids {
-        if order_book.asks.len() > i {println!("{:08.8} @ {:08.2}\t{:08.8} @ {:08.2}",bid.size, bid.price, order_book.asks[i].size, order_book.asks[i].price);} 
-        else {println!("{:08.8} @ {:08.2}",bid.size, bid.price);}
-        i += 1;
-        if i > 10 {break;}
-    }
-}
-
-fn pull_binance() -> Result<(), AppError> {
-    let bnnc_url = format!("{}/ws/ethbtc@depth10@100ms", BINANCE_WS_API);
+fn pull_binance(feed: &FeedConfig) -> Result<(), AppError> {
+    let bnnc_url = format!("{}/ws/{}", feed.url, feed.listing.channel()?);
+    let mut impaired = feed.impaired()?;
 
-    let (mut socket, response) = connect(Url::parse(&bnnc_url)?).map_err(|_| AppError::ConnectionFailed("Binance".to_string()))?;
+    let (mut socket, response) = connect(Url::parse(&bnnc_url)?).map_err(|_| AppError::ConnectionFailed(feed.key.to_string()))?;
 
     println!("Connected to binance stream.");
     println!("HTTP status code: {}", response.status());
     println!("Response headers:");
     for (ref header, ref header_value) in response.headers() {println!("- {}: {:?}", header, header_value);}
+    match feed.book(&mut VENUE_BOOKS.lock().unwrap()) {Some(order_book) => order_book.resync(), None => return Ok(())}
 
     loop {
-        let msg = socket.read_message().map_err(|e| AppError::MessageError(e.to_string()))?;
+        let msg = impairment::read(&mut socket, impaired.as_ref())?;
+        if feed.stopped() {return Ok(());}
+        if let (Some(recorder), Some(msg)) = (&feed.recorder, &msg) {recorder.lock().unwrap().record(&feed.key, msg).map_err(|e| AppError::RecordingFailed(e.to_string()))?;}
+        let frames = match impaired.as_mut() {Some(impaired) => impaired.process(msg)?, None => msg.into_iter().collect()};
 
-        match msg {
-            tungstenite::Message::Text(s) => {
-                // Handle text message
+        for msg in frames {
+            if let tungstenite::Message::Text(s) = msg {
                 let parser: models::DepthStreamData = serde_json::from_str(&s).map_err(|_| AppError::ParsingFailed(s.clone()))?;
-                for i in 0..parser.asks.len() {
-                    println!("{}. ask: {}, size: {}",i, parser.asks[i].price, parser.asks[i].size);
-                    println!("{}. bid: {}, size: {}",i, parser.bids[i].price, parser.bids[i].size);
-                }
-            },
-            _ => {}
-        };
+                let snapshot = OrderBook::from(&parser);
+                integrity::check_book(&snapshot).map_err(|e| AppError::IntegrityFailed(format!("{} {}", feed.key, e)))?;
+                let mut books = VENUE_BOOKS.lock().unwrap();
+                let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+                // Partial depth frames are full snapshots, so IDs only need to increase; an older one is discarded.
+                if order_book.sequence(parser.last_update_id, false) == Sequence::Outdated {continue;}
+                order_book.bids = snapshot.bids;
+                order_book.asks = snapshot.asks;
+                order_book.touch(Some(parser.last_update_id));
+                log_crossing(&feed.key, order_book);
+            }
+        }
     }
 }
 
 
-fn pull_bitstamp(){
-    let mut order_book = OrderBook {bids: Vec::new(),asks: Vec::new(),};
+fn pull_bitstamp(feed: &FeedConfig) -> Result<(), AppError> {
     let mut start = Instant::now();
-    let (mut socket, _response) =connect(Url::parse("wss://ws.bitstamp.net").unwrap()).expect("Can't connect");
-
-    socket.write_message(Message::Text(json!({"event": "bts:subscribe","data": {"channel": "live_trades_btcusd"}}).to_string(),).into(),).expect("Error sending message");
-    socket.write_message(Message::Text(json!({"event": "bts:subscribe","data": {"channel": "live_orders_btcusd"}}).to_string(),).into(),).expect("Error sending message");
+    let mut impaired = feed.impaired()?;
+    let (mut socket, _response) = connect(Url::parse(&feed.url)?).map_err(|_| AppError::ConnectionFailed(feed.key.to_string()))?;
+    // Bitstamp sends no snapshot, only order events, so this connection's book is built from empty. Until it has both
+    // sides the previous or restored levels stay in VENUE_BOOKS, marked stale, and are then replaced as a whole.
+    match feed.book(&mut VENUE_BOOKS.lock().unwrap()) {Some(order_book) => order_book.resync(), None => return Ok(())}
+    let mut building = Some(OrderBook::default());
+
+    for channel in &feed.listing.channels {
+        socket.write_message(Message::Text(json!({"event": "bts:subscribe","data": {"channel": channel}}).to_string(),),).map_err(|e| AppError::MessageError(e.to_string()))?;
+    }
 
     loop {
-        let msg = socket.read_message().expect("Error reading message");
-        let result: Result<Msg, serde_json::Error> = serde_json::from_str(msg.to_text().unwrap());
-
-        let _value = match result {
-            Ok(msg) => {
-                if msg.event == "bts:subscription_succeeded" { } 
-                else if msg.event == "trade" {} 
-                else if msg.event == "order_created" {
-                    if let Data::Order(order) = msg.data {
-                        let limit_price = LimitPrice { price: OrderedFloat(order.price), size: OrderedFloat(order.amount), orders: vec![order.clone()],};
-                        match (&order).order_type {
-                            buy if buy == OrderType::Buy as u8 => {
-                                let _value = match order_book.bids.binary_search(&limit_price) {
-                                    Ok(i) => {
-                                        order_book.bids[i].size += order.amount;
-                                        order_book.bids[i].orders.push(order);
-                                    }
-                                    Err(i) => {
-                                        if order_book.asks.len() > 0&& &limit_price.price >= &order_book.asks[0].price{for ask in order_book.asks.clone() {if limit_price.price >= ask.price {order_book.asks.remove(0);}}} 
-                                        else {order_book.bids.insert(i, limit_price.clone());}
-                                    }
-                                };
-                            }
-                            ask if ask == OrderType::Sell as u8 => {
-                                let _value = match order_book.asks.binary_search(&limit_price) {
-                                    Ok(i) => {
-                                        order_book.asks[i].size += order.amount;
-                                        order_book.asks[i].orders.push(order);
-                                    }
-                                    Err(i) => {
-                                        let mut rev_bids: Vec<LimitPrice> = order_book.bids.clone();
-                                        rev_bids.reverse();
-                                        if order_book.bids.len() > 0 && &limit_price.price <= &rev_bids[0].price{for bid in rev_bids {if limit_price.price <= bid.price {order_book.bids.remove(order_book.bids.len() - 1);}}} 
-                                        else {order_book.asks.insert(i, limit_price.clone());}
-                                    }
-                                };
-                            }
-                            _ => (),
-                        }
-                    }
-                } else if msg.event == "order_deleted" {
-                    if let Data::Order(order) = msg.data {
-                        let limit_price = LimitPrice {price: OrderedFloat(order.price),size: OrderedFloat(order.amount),orders: vec![order.clone()],};
-                        match (&order).order_type {
-                            buy if buy == OrderType::Buy as u8 => {
-                                let _value = match order_book.bids.binary_search(&limit_price) {
-                                    Ok(i) => {
-                                        let _value =match order_book.bids[i].orders.binary_search(&order) {
-                                                Ok(j) => {
-                                                    order_book.bids[i].orders.remove(j);
-                                                    if order_book.bids[i].orders.len() == 0 {order_book.bids.remove(i);} 
-                                                    else {order_book.bids[i].size -= order.amount;}
-                                                }
-                                                Err(_) => (),
-                                            };
-                                    }
-                                    Err(_) => (),
-                                };
-                            }
-                            ask if ask == OrderType::Sell as u8 => {
-                                let _value = match order_book.asks.binary_search(&limit_price) {
-                                    Ok(i) => {
-                                        let _value = match order_book.asks[i].orders.binary_search(&order) {
-                                                Ok(j) => {
-                                                    order_book.asks[i].orders.remove(j);
-                                                    if order_book.asks[i].orders.len() == 0 {order_book.asks.remove(i);} 
-                                                    else {order_book.asks[i].size -= order.amount;}
-                                                }
-                                                Err(_) => (),
-                                            };
-                                    }
-                                    Err(_) => (),
-                                };
-                            }
-                            _ => (),
-                        }
-                    }
-                } else if msg.event == "order_changed" {
-                    if let Data::Order(order) = msg.data {
-                        let limit_price = LimitPrice {price: OrderedFloat(order.price),size: OrderedFloat(order.amount),orders: vec![order.clone()],};
-                        match (&order).order_type {
-                            buy if buy == OrderType::Buy as u8 => {
-                                let _value = match order_book.bids.binary_search(&limit_price) {
-                                    Ok(i) => {
-                                        let _value =match order_book.bids[i].orders.binary_search(&order) {
-                                                Ok(j) => {
-                                                    order_book.bids[i].orders.remove(j);
-                                                    if order_book.bids[i].orders.len() == 0 {order_book.bids.remove(i);} 
-                                                    else {order_book.bids[i].size -= order.amount;}
-                                                }
-                                                Err(_) => (),
-                                            };
-                                        order_book.bids[i].size += order.amount;
-                                        order_book.bids[i].orders.push(order);
-                                    }
-                                    Err(i) => {
-                                        order_book.bids.insert(i, limit_price.clone());
-                                        if order_book.asks.len() > 0 {if &limit_price.price >= &order_book.asks[0].price {for ask in order_book.asks.clone() {if limit_price.price >= ask.price {order_book.asks.remove(0);}} }}
-                                    }
-                                };
-                            }
-                            ask if ask == OrderType::Sell as u8 => {
-                                let _value = match order_book.asks.binary_search(&limit_price) {
-                                    Ok(i) => {
-                                        let _value =match order_book.asks[i].orders.binary_search(&order) {
-                                                Ok(j) => {
-                                                    order_book.asks[i].orders.remove(j);
-                                                    if order_book.asks[i].orders.len() == 0 { order_book.asks.remove(i); } 
-                                                    else {order_book.asks[i].size -= order.amount;}
-                                                }
-                                                Err(_) => (),
-                                            };
-                                        order_book.asks[i].size += order.amount;
-                                        order_book.asks[i].orders.push(order);
-                                    }
-                                    Err(i) => {
-                                        order_book.asks.insert(i, limit_price.clone());
-                                        if order_book.bids.len() > 0 {
-                                            let mut rev_bids: Vec<LimitPrice> = order_book.bids.clone();
-                                            rev_bids.reverse();
-                                            if &limit_price.price <= &rev_bids[0].price {for bid in rev_bids {if limit_price.price <= bid.price {order_book.bids.remove(order_book.bids.len() - 1);}}}
-                                        }
-                                    }
-                                };
-                            }
-                            _ => (),
-                        }
-                    }
-                }
-            }
-            Err(_) => (),
-        };
+        let msg = impairment::read(&mut socket, impaired.as_ref())?;
+        if feed.stopped() {return Ok(());}
+        if let (Some(recorder), Some(msg)) = (&feed.recorder, &msg) {recorder.lock().unwrap().record(&feed.key, msg).map_err(|e| AppError::RecordingFailed(e.to_string()))?;}
+        let frames = match impaired.as_mut() {Some(impaired) => impaired.process(msg)?, None => msg.into_iter().collect()};
+
+        let mut books = VENUE_BOOKS.lock().unwrap();
+        let Some(venue_book) = feed.book(&mut books) else {return Ok(())};
+        let order_book = building.as_mut().unwrap_or(&mut *venue_book);
+        for msg in frames {
+            let result: Result<Msg, serde_json::Error> = serde_json::from_str(msg.to_text().unwrap_or_default());
+            if let Ok(msg) = result {apply_bitstamp_event(&feed.key, order_book, msg);}
+        }
         if start.elapsed().as_millis() > 500 {
             start = Instant::now();
-            print_order_book(&order_book);
+            integrity::check_book(order_book).map_err(|e| AppError::IntegrityFailed(format!("{} {}", feed.key, e)))?;
+        }
+        if let Some(book) = building.take_if(|book| book.best_bid().is_some() && book.best_ask().is_some()) {
+            venue_book.replace_with(book);
+        }
+    }
+}
+
+/// Applies one Bitstamp `live_orders` event to the venue book and logs the start of any crossed-book incident.
+/// `live_trades` events are published to [`TRADES`].
+fn apply_bitstamp_event(key: &BookKey, order_book: &mut OrderBook, msg: Msg) {
+    let order = match msg.data {
+        Data::Order(order) => order,
+        Data::Trade(trade) if msg.event == "trade" => {
+            let _ = TRADES.send(bitstamp_trade(key, &trade));
+            return;
+        }
+        _ => return,
+    };
+    match msg.event.as_str() {
+        "order_created" | "order_changed" => order_book.upsert_order(order),
+        "order_deleted" => {order_book.remove_order(&order);}
+        _ => return,
+    }
+    order_book.touch(None);
+    log_crossing(key, order_book);
+}
+
+/// Bitstamp's trade `type` is the taker's side.
+fn bitstamp_trade(key: &BookKey, trade: &models::Trade) -> TradeEvent {
+    let side = if trade._type == OrderType::Buy as u8 {"buy"} else {"sell"};
+    TradeEvent { exchange: key.exchange.clone(), price: trade.price, amount: trade.amount, side: side.to_string(), trade_id: trade.id, timestamp_micros: trade.microtimestamp.parse().unwrap_or_default(), instrument: key.instrument.clone() }
+}
+
+fn log_crossing(key: &BookKey, order_book: &mut OrderBook) {
+    if order_book.track_crossing() {
+        if let (Some(bid), Some(ask)) = (order_book.best_bid(), order_book.best_ask()) {
+            eprintln!("{} book crossed: bid {} >= ask {} (incident {})", key, bid.price, ask.price, order_book.crossed_incidents);
+        }
+    }
+}
+
+fn impairment_from_env(venue: &str) -> Result<Option<Impairment>, AppError> {
+    match env::var(format!("{}{}", IMPAIRMENT_ENV_PREFIX, venue.to_uppercase())) {
+        Ok(spec) => Ok(Some(spec.parse()?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Everything a connector needs to follow one instrument on one venue.
+pub struct FeedConfig {
+    pub key: BookKey,
+    pub instrument: Instrument,
+    pub listing: Listing,
+    pub url: String,
+    pub recorder: Option<Arc<Mutex<Recorder>>>,
+    pub impairment: Option<Impairment>,
+    /// Set to stop the connector. It returns at the next frame it receives, or within [`STOP_CHECK_INTERVAL`] when
+    /// none arrives.
+    pub stop: Arc<AtomicBool>,
+    /// Connections made so far, which seed each connection's impairment differently.
+    pub connections: AtomicU64,
+}
+
+impl FeedConfig {
+    pub fn stopped(&self) -> bool {self.stop.load(Ordering::Relaxed)}
+
+    /// The impairment layer of a new connection, when the feed is impaired.
+    pub fn impaired(&self) -> Result<Option<ImpairedFeed>, AppError> {
+        let connection = self.connections.fetch_add(1, Ordering::Relaxed);
+        self.impairment.clone().map(|impairment| ImpairedFeed::new(impairment, connection)).transpose()
+    }
+
+    /// This feed's book in the locked [`VENUE_BOOKS`], created if missing and tagged with the instrument's kind, or None
+    /// once the feed is stopped. A reload
+    /// sets `stop` before taking the lock to remove the book, so a stopped connector never writes into the book of the
+    /// feed that replaces it under the same key.
+    pub fn book<'a>(&self, books: &'a mut HashMap<BookKey, OrderBook>) -> Option<&'a mut OrderBook> {
+        if self.stopped() {return None;}
+        let order_book = books.entry(self.key.clone()).or_default();
+        order_book.kind = self.instrument.kind;
+        Some(order_book)
+    }
+}
+
+type Connector = fn(&FeedConfig) -> Result<(), AppError>;
+
+/// Every exchange with a connector.
+static EXCHANGES: [&str; 6] = ["Binance", "Bitstamp", "Kraken", "Coinbase", "OKX", "Bybit"];
+
+/// Connector, default endpoint and endpoint override variable of a supported exchange.
+fn venue(exchange: &str) -> Option<(Connector, &'static str, &'static str)> {
+    match exchange {
+        "Binance" => Some((pull_binance, BINANCE_WS_API, BINANCE_URL_ENV)),
+        "Bitstamp" => Some((pull_bitstamp, BITSTAMP_WS_API, BITSTAMP_URL_ENV)),
+        "Kraken" => Some((kraken::pull, KRAKEN_WS_API, KRAKEN_URL_ENV)),
+        "Coinbase" => Some((coinbase::pull, COINBASE_WS_API, COINBASE_URL_ENV)),
+        "OKX" => Some((okx::pull, OKX_WS_API, OKX_URL_ENV)),
+        "Bybit" => Some((bybit::pull, BYBIT_WS_API, BYBIT_URL_ENV)),
+        _ => None,
+    }
+}
+
+/// Runs a connector, marking its book stale and reconnecting with a fresh book whenever it fails, until the feed is
+/// stopped.
+fn supervise(feed: &FeedConfig, mut connector: impl FnMut() -> Result<(), AppError>) {
+    while !feed.stopped() {
+        match connector() {
+            Ok(()) => return,
+            Err(e) if feed.stopped() => eprintln!("{} connector stopped: {}.", feed.key, e),
+            Err(e) => {
+                eprintln!("{} connector stopped: {}. Reconnecting in {:?}.", feed.key, e, RECONNECT_DELAY);
+                if let Some(order_book) = VENUE_BOOKS.lock().unwrap().get_mut(&feed.key).filter(|_| !feed.stopped()) {order_book.mark_stale(e.to_string());}
+                thread::sleep(RECONNECT_DELAY);
+            }
         }
     }
 }
 
+/// Aggregates order books from several exchanges and serves them over gRPC. Without a command it runs `watch`, or
+/// `simulate` on `EXCHANGE_SIMULATOR_ADDR` when that is set. Settings without a flag come from the environment.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// TOML configuration file. `EXCHANGE_CONFIG_FILE` names one too.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Aggregate the exchange feeds and serve the consolidated book over gRPC.
+    Serve(ServeOptions),
+    /// Like `serve`, and show the books in the terminal.
+    Watch(ServeOptions),
+    /// Like `serve`, and record every frame received from the exchanges.
+    Record {
+        /// Directory to write `frames-*.jsonl.gz` files into.
+        dir: PathBuf,
+        #[command(flatten)]
+        serve: ServeOptions,
+    },
+    /// Rebuild the books from a recorded session instead of the exchanges, and serve them over gRPC.
+    Replay {
+        #[command(flatten)]
+        replay: replay::Options,
+        #[command(flatten)]
+        serve: ServeOptions,
+    },
+    /// Convert a recorded session into CSV and Parquet files.
+    Export(export::Options),
+    /// Run the fake exchange.
+    Simulate(simulator::Options),
+}
+
+/// The gRPC server and the feeds it aggregates. Flags take precedence over the configuration file.
+#[derive(Args, Debug, Clone, Default)]
+struct ServeOptions {
+    /// Address of the gRPC server [default: 127.0.0.1:50051]
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+    /// Exchanges to follow, e.g. `Kraken,Coinbase`. Every exchange listing a followed instrument by default.
+    #[arg(long, value_delimiter = ',')]
+    venues: Vec<String>,
+    /// Instruments to follow, e.g. `BTC/USD,BTC/USDT`. Every instrument in the registry by default.
+    #[arg(long, value_delimiter = ',')]
+    symbols: Vec<String>,
+    /// Levels per side in the terminal UI, and in `BookSummary` responses to requests that set no depth.
+    #[arg(long)]
+    depth: Option<usize>,
+}
+
+impl ServeOptions {
+    /// The instruments of `registry` selected by `symbols`, listed on the exchanges selected by `venues`.
+    fn select(&self, mut registry: Registry) -> Result<Registry, AppError> {
+        if let Some(symbol) = self.symbols.iter().find(|symbol| registry.get(symbol).is_none()) {return Err(AppError::ParsingFailed(format!("unknown symbol {}", symbol)));}
+        if let Some(exchange) = self.venues.iter().find(|exchange| venue(exchange).is_none()) {return Err(AppError::ParsingFailed(format!("unsupported exchange {}", exchange)));}
+        registry.instruments.retain(|instrument| self.symbols.is_empty() || self.symbols.contains(&instrument.name));
+        for instrument in &mut registry.instruments {instrument.venues.retain(|exchange, _| self.venues.is_empty() || self.venues.contains(exchange));}
+        registry.instruments.retain(|instrument| !instrument.venues.is_empty());
+        if registry.instruments.is_empty() {return Err(AppError::ParsingFailed("no selected instrument is listed on the selected exchanges".to_string()));}
+        Ok(registry)
+    }
+}
+
+/// Where the books come from.
+enum Source {
+    /// The exchanges, optionally printing the books and recording every frame into `record_dir`.
+    Live { terminal: bool, record_dir: Option<PathBuf> },
+    Replay(replay::Options),
+}
+
 #[tokio::main]
 async fn main() {
-    match run_app().await {
+    let cli = Cli::parse();
+    let config = cli.config.or_else(|| env::var(CONFIG_FILE_ENV).ok().map(PathBuf::from));
+    let config = config.as_deref();
+    let result = match cli.command {
+        Some(Command::Serve(options)) => run_app(config, options, Source::Live { terminal: false, record_dir: None }).await,
+        Some(Command::Watch(options)) => run_app(config, options, Source::Live { terminal: true, record_dir: None }).await,
+        Some(Command::Record { dir, serve }) => run_app(config, serve, Source::Live { terminal: false, record_dir: Some(dir) }).await,
+        Some(Command::Replay { replay, serve }) => run_app(config, serve, Source::Replay(replay)).await,
+        Some(Command::Export(options)) => Config::load(config).and_then(|config| export::run(&options, &config.registry())),
+        Some(Command::Simulate(options)) => match options.model() {
+            Ok(model) => simulator::serve(options.listen, model).await,
+            Err(e) => Err(e),
+        },
+        None => match env::var(SIMULATOR_ADDR_ENV) {
+            Ok(addr) => match addr.parse() {
+                Ok(addr) => simulator::serve(addr, simulator::MarketModel::default()).await,
+                Err(e) => Err(AppError::AddrParseError(e)),
+            },
+            Err(_) => run_app(config, ServeOptions::default(), Source::Live { terminal: true, record_dir: None }).await,
+        },
+    };
+    match result {
         Ok(_) => println!("Application exited gracefully."),
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -272,19 +628,107 @@ async fn main() {
     }
 }
 
-async fn run_app() -> Result<(), AppError> {
-    let binance_handle = thread::spawn(|| pull_binance());
-    let bitstamp_handle = thread::spawn(|| pull_bitstamp());
-
-    let binance_result = binance_handle.join().map_err(|_| AppError::UnknownError)?;
-    let bitstamp_result = bitstamp_handle.join().map_err(|_| AppError::UnknownError)?;
+async fn run_app(config_path: Option<&Path>, options: ServeOptions, source: Source) -> Result<(), AppError> {
+    let config = Config::load(config_path)?;
+    if options.depth == Some(0) {return Err(AppError::ParsingFailed("depth must be at least 1".to_string()));}
+    let record_dir = match &source {
+        Source::Live { record_dir: Some(dir), .. } => Some(dir.clone()),
+        Source::Live { record_dir: None, .. } => config.recording.dir.clone(),
+        Source::Replay(_) => None,
+    };
+    let recorder = match record_dir {
+        Some(dir) => Some(Arc::new(Mutex::new(Recorder::new(dir, config.max_file_bytes()?).map_err(|e| AppError::RecordingFailed(e.to_string()))?))),
+        None => None,
+    };
+
+    let fees = match &config.analytics.fees_file {
+        Some(path) => FeeTable::load(path)?,
+        None => FeeTable::default(),
+    };
+    let fx = match &config.analytics.fx_file {
+        Some(path) => FxTable::load(path)?,
+        None => FxTable::default(),
+    };
+    let display = Arc::new(Mutex::new(aggregator::View { depth: options.depth.unwrap_or(config.display()?.depth), ..config.display()? }));
+    let instruments = options.select(config.registry())?;
+
+    // A replay neither restores nor overwrites the snapshot of the live books.
+    let live = matches!(source, Source::Live { .. });
+    let snapshot_dir = config.recording.snapshot_dir.clone().filter(|_| live);
+    if let Some(dir) = snapshot_dir.as_deref().filter(|_| config.recording.warm_start) {
+        if let Some(snapshot) = Snapshot::load(dir)? {
+            let listed = |key: &BookKey| instruments.get(&key.instrument).is_some_and(|instrument| instrument.venues.contains_key(&key.exchange));
+            let restored = snapshot.restore(&mut VENUE_BOOKS.lock().unwrap(), listed);
+            println!("Restored {} venue books from {}.", restored, dir.display());
+        }
+    }
 
-    let addr = "127.0.0.1:50051".parse().map_err(AppError::AddrParseError)?;
-    let orderbook_service = OrderbookService {};
+    let terminal = match source {
+        Source::Live { terminal, .. } => terminal,
+        Source::Replay(replay) => {
+            let (files, registry) = (replay::recordings(&replay)?, instruments.clone());
+            thread::spawn(move || if let Err(e) = replay::run(&replay, files, &registry, &VENUE_BOOKS, &TRADES) {eprintln!("Replay stopped: {}", e);});
+            false
+        }
+    };
+    let terminal = terminal && (io::stdin().is_terminal() && io::stdout().is_terminal() || {eprintln!("Not running in a terminal, so the books are served without the terminal UI."); false});
+    let settings = Arc::new(Mutex::new(reload::Settings { instruments: instruments.clone(), analytics: config.analytics(), summary_depth: options.depth.unwrap_or(config.summary_depth()) }));
+    // Bound before anything is started, so that an address in use is reported on a usable terminal.
+    let addr = options.listen.unwrap_or(config.listen());
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| AppError::ConnectionFailed(format!("{}: {}", addr, e)))?;
+    if live {
+        let mut feeds = reload::Feeds::new(recorder, if terminal {Some(display.clone())} else {None});
+        feeds.apply(&config, &instruments)?;
+        tokio::spawn(reload::run(config_path.map(Path::to_path_buf), config.clone(), options.clone(), feeds, settings.clone()));
+    }
 
-    println!("gRPC Server started on {}", addr);
+    tokio::spawn(aggregator::watch_freshness(VENUE_BOOKS.clone(), config.stale_after()));
+
+    let (opportunities, _) = broadcast::channel(256);
+    tokio::spawn(arbitrage::run(VENUE_BOOKS.clone(), settings.clone(), fees.clone(), fx.clone(), opportunities.clone()));
+    let candles = Arc::new(Mutex::new(CandleBuilder::new(config.candle_intervals()?, config.recording.candles_dir.as_deref())?));
+    let (candle_updates, _) = broadcast::channel(1024);
+    tokio::spawn(candles::run(candles.clone(), TRADES.subscribe(), candle_updates.clone()));
+    let sample_interval = config.sample_interval();
+    let spreads = Arc::new(Mutex::new(SpreadHistory::new(config.recording.spreads_dir.as_deref())?));
+    tokio::spawn(spreads::run(spreads.clone(), VENUE_BOOKS.clone(), sample_interval));
+    if let Some(dir) = snapshot_dir {tokio::spawn(snapshots::run(VENUE_BOOKS.clone(), dir, config.snapshot_interval()));}
+    let store = match &config.recording.sqlite_path {
+        Some(path) => Some(Arc::new(Mutex::new(Store::open(path)?))),
+        None => None,
+    };
+    if let Some(store) = &store {tokio::spawn(store::run(store.clone(), VENUE_BOOKS.clone(), TRADES.subscribe(), candle_updates.subscribe(), sample_interval));}
+    let orderbook_service = OrderbookService { fees, fx, settings: settings.clone(), opportunities, candles, candle_updates, spreads: spreads.clone(), store };
+
+    let mut server = Server::builder();
+    if let Some(tls) = &config.server.tls {
+        let read = |path: &Path| fs::read(path).map_err(|e| AppError::ConfigInvalid(format!("{}: {}", path.display(), e)));
+        let identity = Identity::from_pem(read(&tls.cert)?, read(&tls.key)?);
+        server = server.tls_config(ServerTlsConfig::new().identity(identity)).map_err(|e| AppError::ConfigInvalid(format!("server.tls: {}", std::error::Error::source(&e).map_or(e.to_string(), |cause| cause.to_string()))))?;
+        println!("gRPC Server started on {} with TLS", addr);
+    } else {
+        println!("gRPC Server started on {}", addr);
+    }
 
-    Server::builder().add_service(OrderbookAggregatorServer::new(orderbook_service)).serve(addr).await;
+    let incoming = futures::stream::unfold(listener, |listener| async move {Some((listener.accept().await.map(|(stream, _)| stream), listener))});
+    let server = server.add_service(OrderbookAggregatorServer::new(orderbook_service)).serve_with_incoming(incoming);
+    if terminal {
+        // Quitting the terminal UI ends the aggregator.
+        let quit = Arc::new(AtomicBool::new(false));
+        let tui_quit = quit.clone();
+        let mut tui = tokio::task::spawn_blocking(move || tui::run(settings, display, spreads, VENUE_BOOKS.clone(), TRADES.subscribe(), tui_quit));
+        tokio::select! {
+            result = server => {
+                // The terminal is given back before the error is reported on it.
+                quit.store(true, Ordering::Relaxed);
+                let _ = (&mut tui).await;
+                result.map_err(|e| AppError::ConnectionFailed(e.to_string()))?
+            }
+            result = &mut tui => result.map_err(|e| AppError::TerminalFailed(e.to_string()))??,
+        }
+    } else {
+        server.await.map_err(|e| AppError::ConnectionFailed(e.to_string()))?;
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,17 +1,238 @@
+use std::fmt;
 use serde::de;
 use derivative::Derivative;
 use ordered_float::OrderedFloat;
+use chrono::Utc;
 use serde::{Serialize, Deserialize, Deserializer};
+use crate::{AppError, orderbook};
 
 pub enum OrderType {
     Buy = 0,
     Sell = 1,
 }
 
-#[derive(Debug)]
+/// Identifies a venue book: the exchange and the canonical instrument it trades.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BookKey {
+    pub exchange: String,
+    pub instrument: String,
+}
+
+impl BookKey {
+    pub fn new(exchange: &str, instrument: &str) -> BookKey {BookKey { exchange: exchange.to_string(), instrument: instrument.to_string() }}
+}
+
+impl fmt::Display for BookKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {write!(f, "{} {}", self.exchange, self.instrument)}
+}
+
+/// Whether a venue book trades the asset itself or a perpetual swap on it. Books of different kinds are kept in separate views.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum InstrumentKind {
+    #[default]
+    Spot,
+    Perpetual,
+}
+
+impl From<orderbook::InstrumentKind> for InstrumentKind {
+    fn from(kind: orderbook::InstrumentKind) -> InstrumentKind {
+        match kind {
+            orderbook::InstrumentKind::Spot => InstrumentKind::Spot,
+            orderbook::InstrumentKind::Perpetual => InstrumentKind::Perpetual,
+        }
+    }
+}
+
+impl From<InstrumentKind> for orderbook::InstrumentKind {
+    fn from(kind: InstrumentKind) -> orderbook::InstrumentKind {
+        match kind {
+            InstrumentKind::Spot => orderbook::InstrumentKind::Spot,
+            InstrumentKind::Perpetual => orderbook::InstrumentKind::Perpetual,
+        }
+    }
+}
+
+/// Freshness of a venue book. Only `Live` books contribute to the consolidated view.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FeedStatus {
+    #[default]
+    Connecting,
+    Live,
+    Stale(String),
+}
+
+/// Outcome of comparing an incoming update ID with the last one applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sequence {
+    Next,
+    /// Updates were missed; the book must be rebuilt.
+    Gap { expected: u64, received: u64 },
+    /// The update is older than the book and must be discarded.
+    Outdated,
+}
+
+impl Sequence {
+    /// Classifies `update_id` against `last`, the last applied ID. With `contiguous` the venue numbers every
+    /// update consecutively and any jump is a gap; otherwise IDs only have to increase.
+    pub fn classify(last: Option<u64>, update_id: u64, contiguous: bool) -> Sequence {
+        match last {
+            Some(last) if update_id <= last => Sequence::Outdated,
+            Some(last) if contiguous && update_id != last + 1 => Sequence::Gap { expected: last + 1, received: update_id },
+            _ => Sequence::Next,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct OrderBook {
+    /// Highest price first.
     pub bids: Vec<LimitPrice>,
+    /// Lowest price first.
     pub asks: Vec<LimitPrice>,
+    /// Whether the best bid was at or above the best ask after the last update.
+    pub crossed: bool,
+    /// Number of times the book went from uncrossed to crossed.
+    pub crossed_incidents: u64,
+    pub status: FeedStatus,
+    pub kind: InstrumentKind,
+    /// Venue sequence number of the last applied update, where the venue provides one.
+    pub last_update_id: Option<u64>,
+    /// Receive time of the last applied update in microseconds since the Unix epoch.
+    pub last_update_micros: i64,
+}
+
+impl OrderBook {
+    /// Drops all levels ahead of a resync, keeping the incident counters.
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+        self.crossed = false;
+        self.status = FeedStatus::Connecting;
+        self.last_update_id = None;
+    }
+
+    /// Forgets the sequence ahead of a resync. Unlike [`OrderBook::clear`] the levels are kept, marked stale, until
+    /// the venue's snapshot replaces them; a book without levels is connecting.
+    pub fn resync(&mut self) {
+        self.crossed = false;
+        self.last_update_id = None;
+        self.status = match &self.status {
+            _ if self.bids.is_empty() && self.asks.is_empty() => FeedStatus::Connecting,
+            FeedStatus::Stale(reason) => FeedStatus::Stale(reason.clone()),
+            _ => FeedStatus::Stale("resynchronising".to_string()),
+        };
+    }
+
+    /// Takes over the levels, sequence and status of `book`, built from scratch on a new connection, keeping the kind
+    /// and adding up the crossed-book incidents.
+    pub fn replace_with(&mut self, book: OrderBook) {
+        *self = OrderBook { kind: self.kind, crossed_incidents: self.crossed_incidents + book.crossed_incidents, ..book };
+    }
+
+    /// Classifies `update_id` against the last applied one, see [`Sequence::classify`].
+    pub fn sequence(&self, update_id: u64, contiguous: bool) -> Sequence {Sequence::classify(self.last_update_id, update_id, contiguous)}
+
+    /// Records that an update was applied and marks the book live.
+    pub fn touch(&mut self, update_id: Option<u64>) {
+        if update_id.is_some() {self.last_update_id = update_id;}
+        self.last_update_micros = Utc::now().timestamp_micros();
+        self.status = FeedStatus::Live;
+    }
+
+    pub fn mark_stale(&mut self, reason: String) {self.status = FeedStatus::Stale(reason);}
+
+    pub fn is_live(&self) -> bool {self.status == FeedStatus::Live}
+
+    pub fn bids_best_first(&self) -> impl Iterator<Item = &LimitPrice> {self.bids.iter()}
+
+    pub fn best_bid(&self) -> Option<&LimitPrice> {self.bids.first()}
+
+    pub fn best_ask(&self) -> Option<&LimitPrice> {self.asks.first()}
+
+    pub fn is_crossed(&self) -> bool {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => bid.price >= ask.price,
+            _ => false,
+        }
+    }
+
+    /// Updates `crossed` and returns true when this update started a new crossed-book incident.
+    pub fn track_crossing(&mut self) -> bool {
+        let crossed = self.is_crossed();
+        let started = crossed && !self.crossed;
+        if started {self.crossed_incidents += 1;}
+        self.crossed = crossed;
+        started
+    }
+
+    fn side_mut(&mut self, order_type: u8) -> Option<(&mut Vec<LimitPrice>, bool)> {
+        match order_type {
+            buy if buy == OrderType::Buy as u8 => Some((&mut self.bids, true)),
+            sell if sell == OrderType::Sell as u8 => Some((&mut self.asks, false)),
+            _ => None,
+        }
+    }
+
+    fn find_level(side: &[LimitPrice], is_bid: bool, price: OrderedFloat<f64>) -> Result<usize, usize> {
+        if is_bid {side.binary_search_by(|level| price.cmp(&level.price))} else {side.binary_search_by(|level| level.price.cmp(&price))}
+    }
+
+    /// Inserts an order, or replaces the resting order with the same id at its price.
+    ///
+    /// An order priced through the other side is inserted like any other; the opposite levels are left
+    /// untouched because the venue reports the resulting fills as separate changes and deletions.
+    pub fn upsert_order(&mut self, order: Order) {
+        let price = OrderedFloat(order.price);
+        let Some((side, is_bid)) = self.side_mut(order.order_type) else {return};
+        match OrderBook::find_level(side, is_bid, price) {
+            Ok(i) => {
+                let level = &mut side[i];
+                match level.orders.binary_search(&order) {
+                    Ok(j) => {level.size += order.amount - level.orders[j].amount; level.orders[j] = order;}
+                    Err(j) => {level.size += order.amount; level.orders.insert(j, order);}
+                }
+            }
+            Err(i) => side.insert(i, LimitPrice { price, size: OrderedFloat(order.amount), orders: vec![order] }),
+        }
+    }
+
+    /// Sets the total size at `price` on a book without individual orders, removing the level when `size` is zero.
+    pub fn set_level(&mut self, order_type: u8, price: f64, size: f64) {
+        let price = OrderedFloat(price);
+        let Some((side, is_bid)) = self.side_mut(order_type) else {return};
+        match (OrderBook::find_level(side, is_bid, price), size > 0.0) {
+            (Ok(i), true) => side[i].size = OrderedFloat(size),
+            (Ok(i), false) => {side.remove(i);}
+            (Err(i), true) => side.insert(i, LimitPrice { price, size: OrderedFloat(size), orders: Vec::new() }),
+            (Err(_), false) => {}
+        }
+    }
+
+    /// Keeps the best `depth` levels of each side.
+    pub fn truncate(&mut self, depth: usize) {
+        self.bids.truncate(depth);
+        self.asks.truncate(depth);
+    }
+
+    /// Removes the resting order with the same id and price, dropping its level once empty.
+    pub fn remove_order(&mut self, order: &Order) -> Option<Order> {
+        let price = OrderedFloat(order.price);
+        let (side, is_bid) = self.side_mut(order.order_type)?;
+        let i = OrderBook::find_level(side, is_bid, price).ok()?;
+        let j = side[i].orders.binary_search(order).ok()?;
+        let removed = side[i].orders.remove(j);
+        if side[i].orders.is_empty() {side.remove(i);}
+        else {side[i].size -= removed.amount;}
+        Some(removed)
+    }
+}
+
+impl From<&DepthStreamData> for OrderBook {
+    fn from(depth: &DepthStreamData) -> OrderBook {
+        let level = |offer: &OfferData| LimitPrice { price: OrderedFloat(offer.price), size: OrderedFloat(offer.size), orders: Vec::new() };
+        OrderBook { bids: depth.bids.iter().map(level).collect(), asks: depth.asks.iter().map(level).collect(), ..OrderBook::default() }
+    }
 }
 
 #[derive(Derivative)]
@@ -45,7 +266,7 @@ pub enum Data {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Msg { pub channel: String, pub event: String, pub data: Data}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Trade {
     pub id: u64,
     pub amount: f64,
@@ -126,21 +347,24 @@ pub struct Order {
 #[derive(Debug, Deserialize)]
 pub struct OfferData {
     #[serde(deserialize_with = "de_float_from_str")]
-    pub price: f32,
+    pub price: f64,
     #[serde(deserialize_with = "de_float_from_str")]
-    pub size: f32,
+    pub size: f64,
 }
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct DepthStreamData { pub last_update_id: usize, pub bids: Vec<OfferData>, pub asks: Vec<OfferData>}
-pub fn de_float_from_str<'a, D>(deserializer: D) -> Result<f32, D::Error>
+pub struct DepthStreamData { pub last_update_id: u64, pub bids: Vec<OfferData>, pub asks: Vec<OfferData>}
+pub fn de_float_from_str<'a, D>(deserializer: D) -> Result<f64, D::Error>
 where
     D: Deserializer<'a>,
 {
     let str_val = String::deserialize(deserializer)?;
-    str_val.parse::<f32>().map_err(de::Error::custom)
+    str_val.parse::<f64>().map_err(de::Error::custom)
 }
 
+/// Parses a price or size that a venue sends as a decimal string.
+pub fn parse_decimal(value: &str) -> Result<f64, AppError> {value.parse().map_err(|_| AppError::ParsingFailed(value.to_string()))}
+
 /// The request with a id of the book
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetBookRequest {
@@ -359,3 +583,128 @@ pub mod bookstore_server {
         const NAME: &'static str = "bookstore.Bookstore";
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: u64, order_type: OrderType, price: f64, amount: f64) -> Order {
+        Order { id, id_str: id.to_string(), order_type: order_type as u8, datetime: String::new(), microtimestamp: String::new(), amount, amount_str: amount.to_string(), price, price_str: price.to_string() }
+    }
+
+    fn book() -> OrderBook {
+        let mut book = OrderBook::default();
+        book.upsert_order(order(1, OrderType::Buy, 100.0, 1.0));
+        book.upsert_order(order(2, OrderType::Sell, 101.0, 2.0));
+        assert!(!book.track_crossing());
+        book
+    }
+
+    #[test]
+    fn an_order_through_the_other_side_crosses_the_book() {
+        let mut book = book();
+        book.upsert_order(order(3, OrderType::Buy, 101.0, 0.5));
+        assert_eq!(book.best_bid().unwrap().price, OrderedFloat(101.0));
+        assert_eq!(book.best_ask().unwrap().price, OrderedFloat(101.0));
+        assert!(book.track_crossing());
+        assert!(!book.track_crossing(), "a book that stays crossed is one incident");
+        assert_eq!((book.crossed, book.crossed_incidents), (true, 1));
+    }
+
+    #[test]
+    fn a_later_delete_uncrosses_the_book() {
+        let mut book = book();
+        book.upsert_order(order(3, OrderType::Buy, 102.0, 0.5));
+        assert!(book.track_crossing());
+        assert_eq!(book.remove_order(&order(3, OrderType::Buy, 102.0, 0.0)).map(|removed| removed.amount), Some(0.5));
+        assert!(!book.track_crossing());
+        assert_eq!((book.crossed, book.crossed_incidents), (false, 1));
+        assert_eq!(book.best_bid().unwrap().price, OrderedFloat(100.0));
+
+        book.upsert_order(order(4, OrderType::Sell, 99.0, 1.0));
+        assert!(book.track_crossing());
+        assert_eq!(book.crossed_incidents, 2);
+    }
+
+    #[test]
+    fn changes_while_crossed_are_one_incident_until_the_fill_uncrosses_the_book() {
+        let mut book = book();
+        book.upsert_order(order(3, OrderType::Buy, 101.0, 0.5));
+        assert!(book.track_crossing());
+        book.upsert_order(order(2, OrderType::Sell, 101.0, 1.5));
+        assert!(!book.track_crossing());
+        assert_eq!(book.best_ask().unwrap().size, OrderedFloat(1.5));
+        book.upsert_order(order(3, OrderType::Buy, 101.0, 0.2));
+        assert_eq!(book.best_bid().unwrap().size, OrderedFloat(0.2));
+        book.remove_order(&order(2, OrderType::Sell, 101.0, 0.0));
+        assert!(!book.track_crossing());
+        assert_eq!((book.crossed, book.crossed_incidents), (false, 1));
+        assert!(book.best_ask().is_none());
+    }
+
+    #[test]
+    fn sequence_classification() {
+        assert_eq!(Sequence::classify(None, 7, true), Sequence::Next);
+        assert_eq!(Sequence::classify(Some(7), 8, true), Sequence::Next);
+        assert_eq!(Sequence::classify(Some(7), 10, true), Sequence::Gap { expected: 8, received: 10 });
+        assert_eq!(Sequence::classify(Some(7), 10, false), Sequence::Next);
+        assert_eq!(Sequence::classify(Some(7), 7, true), Sequence::Outdated);
+        assert_eq!(Sequence::classify(Some(7), 3, false), Sequence::Outdated);
+    }
+
+    #[test]
+    fn a_resync_keeps_the_levels_stale_until_the_next_update() {
+        let mut book = book();
+        book.touch(Some(7));
+        book.resync();
+        assert_eq!((book.last_update_id, book.status.clone()), (None, FeedStatus::Stale("resynchronising".to_string())));
+        assert_eq!(book.sequence(3, true), Sequence::Next);
+        book.touch(Some(3));
+        assert_eq!(book.status, FeedStatus::Live);
+        assert_eq!(book.sequence(5, true), Sequence::Gap { expected: 4, received: 5 });
+        assert_eq!({let mut empty = OrderBook::default(); empty.resync(); empty.status}, FeedStatus::Connecting);
+    }
+
+    #[test]
+    fn a_rebuilt_book_replaces_the_stale_one() {
+        let mut restored = OrderBook { kind: InstrumentKind::Spot, crossed_incidents: 2, ..book() };
+        restored.mark_stale("restored from snapshot".to_string());
+        restored.resync();
+        assert_eq!(restored.status, FeedStatus::Stale("restored from snapshot".to_string()));
+        assert!(!restored.is_live());
+
+        let mut rebuilt = OrderBook::default();
+        rebuilt.upsert_order(order(7, OrderType::Buy, 100.5, 1.0));
+        rebuilt.upsert_order(order(8, OrderType::Sell, 100.5, 1.0));
+        rebuilt.touch(None);
+        assert!(rebuilt.track_crossing());
+        restored.replace_with(rebuilt);
+        assert!(restored.is_live());
+        assert_eq!((restored.bids.len(), restored.asks.len(), restored.crossed_incidents), (1, 1, 3));
+        assert_eq!(restored.best_bid().unwrap().orders[0].id, 7);
+    }
+
+    #[test]
+    fn an_order_change_adjusts_the_level_by_the_delta() {
+        let mut book = book();
+        book.upsert_order(order(3, OrderType::Buy, 100.0, 2.0));
+        assert_eq!(book.best_bid().unwrap().size, OrderedFloat(3.0));
+        book.upsert_order(order(3, OrderType::Buy, 100.0, 0.5));
+        assert_eq!(book.best_bid().unwrap().size, OrderedFloat(1.5));
+        assert_eq!(book.best_bid().unwrap().orders.len(), 2);
+        book.remove_order(&order(1, OrderType::Buy, 100.0, 1.0));
+        assert_eq!(book.best_bid().unwrap().size, OrderedFloat(0.5));
+        assert!(book.remove_order(&order(9, OrderType::Buy, 100.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn binance_levels_a_tick_apart_stay_apart_at_high_prices() {
+        let frame = r#"{"lastUpdateId": 7, "bids": [["131072.02", "0.5"], ["131072.01", "1.25"], ["131072.00", "0.00012345"]], "asks": [["131072.03", "2"], ["131072.04", "0.1"]]}"#;
+        let depth: DepthStreamData = serde_json::from_str(frame).unwrap();
+        let book = OrderBook::from(&depth);
+        assert_eq!(book.bids.iter().map(|level| level.price.into_inner()).collect::<Vec<_>>(), vec![131072.02, 131072.01, 131072.00]);
+        assert_eq!(book.bids[2].size, OrderedFloat(0.00012345));
+        assert_eq!(book.best_ask().unwrap().price, OrderedFloat(131072.03));
+        assert!(crate::integrity::check_book(&book).is_ok());
+    }
+}
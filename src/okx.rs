@@ -0,0 +1,162 @@
+use std::{collections::BTreeMap, time::Duration};
+use ordered_float::OrderedFloat;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tungstenite::{connect, Message};
+use url::Url;
+use crate::{AppError, FeedConfig, VENUE_BOOKS, log_crossing, integrity, keepalive::Keepalive, models::{parse_decimal, LimitPrice, OrderBook}, impairment::ImpairedFeed};
+
+/// OKX closes connections that see no traffic for 30 s; it answers a plain-text "ping" with "pong".
+pub const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BooksArg { pub channel: String, #[serde(rename = "instId")] pub inst_id: String }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BooksData {
+    /// `[price, size, deprecated, order count]`; a zero size removes the level.
+    pub asks: Vec<[String; 4]>,
+    pub bids: Vec<[String; 4]>,
+    pub ts: String,
+    pub checksum: i32,
+    /// `seqId` of the previous message, -1 on a snapshot. Equal to `seq_id` when nothing changed.
+    pub prev_seq_id: i64,
+    pub seq_id: i64,
+}
+
+/// A `books` channel push; `action` is "snapshot" or "update".
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BooksMessage { pub arg: BooksArg, pub action: String, pub data: Vec<BooksData> }
+
+pub fn subscribe_request(channel: &str, inst_id: &str) -> String {json!({"op": "subscribe", "args": [{"channel": channel, "instId": inst_id}]}).to_string()}
+
+/// A level with the price and size exactly as OKX sent them, which the checksum is computed over.
+struct RawLevel { price: String, size: String, amount: f64 }
+
+/// The local OKX book, keyed by price. Sizes are in contracts of `contract_size` base units each.
+pub struct RawBook {
+    contract_size: f64,
+    bids: BTreeMap<OrderedFloat<f64>, RawLevel>,
+    asks: BTreeMap<OrderedFloat<f64>, RawLevel>,
+}
+
+impl RawBook {
+    pub fn new(contract_size: f64) -> RawBook {RawBook { contract_size, bids: BTreeMap::new(), asks: BTreeMap::new() }}
+
+    pub fn apply(&mut self, data: &BooksData) -> Result<(), AppError> {
+        for (side, levels) in [(&mut self.bids, &data.bids), (&mut self.asks, &data.asks)] {
+            for [price, size, ..] in levels {
+                let key = OrderedFloat(parse_decimal(price)?);
+                let amount = parse_decimal(size)?;
+                if amount > 0.0 {side.insert(key, RawLevel { price: price.clone(), size: size.clone(), amount });}
+                else {side.remove(&key);}
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a snapshot, or an update that must follow `last_seq_id`, and checks the result against the checksum.
+    pub fn update(&mut self, action: &str, data: &BooksData, last_seq_id: Option<i64>) -> Result<(), AppError> {
+        match (action, last_seq_id) {
+            ("snapshot", _) => *self = RawBook::new(self.contract_size),
+            (_, None) => return Err(AppError::SequenceGap("OKX update before snapshot".to_string())),
+            (_, Some(last)) if data.prev_seq_id != last => return Err(AppError::SequenceGap(format!("OKX update follows seqId {} but the book is at {}", data.prev_seq_id, last))),
+            _ => {}
+        }
+        self.apply(data)?;
+        if self.checksum() != data.checksum {return Err(AppError::IntegrityFailed(format!("OKX checksum mismatch at seqId {}", data.seq_id)));}
+        Ok(())
+    }
+
+    fn checksum(&self) -> i32 {
+        let bids: Vec<(&str, &str)> = self.bids.values().rev().map(|level| (level.price.as_str(), level.size.as_str())).collect();
+        let asks: Vec<(&str, &str)> = self.asks.values().map(|level| (level.price.as_str(), level.size.as_str())).collect();
+        integrity::okx_checksum(&bids, &asks)
+    }
+
+    pub fn fill(&self, order_book: &mut OrderBook) {
+        let level = |(price, level): (&OrderedFloat<f64>, &RawLevel)| LimitPrice { price: *price, size: OrderedFloat(level.amount * self.contract_size), orders: Vec::new() };
+        order_book.bids = self.bids.iter().rev().map(level).collect();
+        order_book.asks = self.asks.iter().map(level).collect();
+    }
+}
+
+/// Maintains the OKX venue book from the `books` channel. Each update must continue from the previous
+/// `seqId` and match the checksum; otherwise the connector stops and the supervisor resubscribes from scratch.
+/// Sizes are converted from contracts to base units using the listing's `contract_size`.
+pub fn pull(feed: &FeedConfig) -> Result<(), AppError> {
+    let channel = feed.listing.channel()?;
+    let contract_size = feed.listing.contract_size.unwrap_or(1.0);
+    let mut impaired = feed.impaired()?;
+    let (mut socket, _response) = connect(Url::parse(&feed.url)?).map_err(|_| AppError::ConnectionFailed(feed.key.to_string()))?;
+    let mut keepalive = Keepalive::new(&mut socket, "OKX", PING_INTERVAL, "ping".to_string())?;
+    {
+        let mut books = VENUE_BOOKS.lock().unwrap();
+        let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+        order_book.resync();
+    }
+    socket.write_message(Message::Text(subscribe_request(channel, &feed.listing.symbol))).map_err(|e| AppError::MessageError(e.to_string()))?;
+    let mut book = RawBook::new(contract_size);
+    let mut last_seq_id: Option<i64> = None;
+
+    loop {
+        let msg = keepalive.read(&mut socket, impaired.as_ref().and_then(ImpairedFeed::release_at))?;
+        if feed.stopped() {return Ok(());}
+        if let (Some(recorder), Some(msg)) = (&feed.recorder, &msg) {recorder.lock().unwrap().record(&feed.key, msg).map_err(|e| AppError::RecordingFailed(e.to_string()))?;}
+        let frames = match impaired.as_mut() {Some(impaired) => impaired.process(msg)?, None => msg.into_iter().collect()};
+
+        for msg in frames {
+            let Message::Text(text) = msg else {continue};
+            if text == "pong" {keepalive.pong(); continue;}
+            let value: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+            if value["event"] == "error" {return Err(AppError::ConnectionFailed(format!("OKX error {}: {}", value["code"], value["msg"])));}
+            // Subscription acknowledgements carry `arg` but no `data`.
+            if value["arg"]["channel"] != channel || value["arg"]["instId"] != feed.listing.symbol.as_str() || value.get("data").is_none() {continue;}
+            let message: BooksMessage = serde_json::from_value(value).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+
+            for data in &message.data {
+                book.update(&message.action, data, last_seq_id)?;
+                last_seq_id = Some(data.seq_id);
+            }
+
+            let mut books = VENUE_BOOKS.lock().unwrap();
+            let Some(order_book) = feed.book(&mut books) else {return Ok(())};
+            book.fill(order_book);
+            order_book.touch(last_seq_id.and_then(|id| u64::try_from(id).ok()));
+            log_crossing(&feed.key, order_book);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(bids: &[[&str; 2]], asks: &[[&str; 2]], checksum: i32, prev_seq_id: i64, seq_id: i64) -> BooksData {
+        let side = |levels: &[[&str; 2]]| levels.iter().map(|[price, size]| [price.to_string(), size.to_string(), "0".to_string(), "1".to_string()]).collect();
+        BooksData { bids: side(bids), asks: side(asks), ts: "0".to_string(), checksum, prev_seq_id, seq_id }
+    }
+
+    #[test]
+    fn checksum_interleaves_the_levels_as_sent() {
+        // CRC32 of "3366.1:7:3366.8:9:3366:6:3368:8" as a signed integer.
+        assert_eq!(integrity::okx_checksum(&[("3366.1", "7"), ("3366", "6")], &[("3366.8", "9"), ("3368", "8")]), -1881014294);
+        // A longer side continues alone once the other runs out.
+        assert_eq!(integrity::okx_checksum(&[("3366.1", "7"), ("3366", "6"), ("3365", "1")], &[("3366.8", "9"), ("3368", "8")]), -1793206555);
+    }
+
+    #[test]
+    fn updates_follow_the_sequence_and_the_checksum() {
+        let mut book = RawBook::new(0.01);
+        book.update("snapshot", &data(&[["3366.1", "7"], ["3366", "6"]], &[["3366.8", "9"], ["3368", "8"]], -1881014294, -1, 10), None).unwrap();
+        book.update("update", &data(&[["3366", "0"]], &[], -1471518219, 10, 11), Some(10)).unwrap();
+        let mut order_book = OrderBook::default();
+        book.fill(&mut order_book);
+        assert_eq!((order_book.bids.len(), order_book.asks[0].size), (1, OrderedFloat(0.09)));
+
+        assert!(matches!(book.update("update", &data(&[], &[], -1471518219, 12, 13), Some(11)), Err(AppError::SequenceGap(_))));
+        assert!(matches!(book.update("update", &data(&[["3365", "1"]], &[], -1471518219, 11, 12), Some(11)), Err(AppError::IntegrityFailed(_))));
+        assert!(matches!(RawBook::new(1.0).update("update", &data(&[], &[], 0, 1, 2), None), Err(AppError::SequenceGap(_))));
+    }
+}
@@ -0,0 +1,172 @@
+use std::{borrow::Cow, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Read, Write}, path::{Path, PathBuf}, time::Instant};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::Utc;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{Serialize, Deserialize};
+use tungstenite::Message;
+use crate::{AppError, instruments::Registry, models::BookKey};
+
+/// Rotate to a new file once this many uncompressed bytes have been written.
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// One received WebSocket frame as written to disk, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedFrame {
+    /// Receive time in microseconds since the Unix epoch.
+    pub received_at: i64,
+    pub venue: String,
+    /// Canonical instrument of the feed. Empty in recordings made before it was recorded.
+    #[serde(default)]
+    pub instrument: String,
+    pub kind: String,
+    pub payload: String,
+    /// `base64` when `payload` encodes the bytes of a binary frame. Empty for text frames, and for binary frames in
+    /// recordings made before they were encoded, which were stored lossily as UTF-8.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub encoding: String,
+}
+
+impl RecordedFrame {
+    /// The bytes of the frame as received.
+    pub fn data(&self) -> Result<Cow<'_, [u8]>, AppError> {
+        match self.encoding.as_str() {
+            "" => Ok(Cow::Borrowed(self.payload.as_bytes())),
+            "base64" => BASE64.decode(&self.payload).map(Cow::Owned).map_err(|e| AppError::RecordingFailed(format!("{} frame at {}: {}", self.venue, self.received_at, e))),
+            encoding => Err(AppError::RecordingFailed(format!("{} frame at {}: unknown encoding {}", self.venue, self.received_at, encoding))),
+        }
+    }
+
+    /// The frame as text, for the JSON feeds. A binary frame must hold UTF-8.
+    pub fn text(&self) -> Result<Cow<'_, str>, AppError> {
+        match self.data()? {
+            Cow::Borrowed(_) => Ok(Cow::Borrowed(&self.payload)),
+            Cow::Owned(bytes) => String::from_utf8(bytes).map(Cow::Owned).map_err(|_| AppError::ParsingFailed(format!("{} binary frame at {} is not UTF-8", self.venue, self.received_at))),
+        }
+    }
+
+    /// The instrument of the frame. Recordings made before frames carried one are resolved through the registry
+    /// when the venue lists a single instrument.
+    pub fn instrument<'a>(&'a self, registry: &'a Registry) -> Option<&'a str> {
+        if !self.instrument.is_empty() {return Some(&self.instrument);}
+        let mut listed = registry.instruments.iter().filter(|instrument| instrument.venues.contains_key(&self.venue));
+        match (listed.next(), listed.next()) {
+            (Some(instrument), None) => Some(&instrument.name),
+            _ => None,
+        }
+    }
+}
+
+/// The frame files of a recording, oldest first. `input` is a recording directory or a single frame file.
+pub fn recordings(input: &Path) -> Result<Vec<PathBuf>, AppError> {
+    if input.is_file() {return Ok(vec![input.to_path_buf()]);}
+    let entries = fs::read_dir(input).map_err(|e| AppError::RecordingFailed(format!("{}: {}", input.display(), e)))?;
+    let mut files: Vec<PathBuf> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("frames-") && name.contains(".jsonl")))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// The frames of one file, gzip-compressed or not, in the order they were received.
+pub fn frames(path: &Path) -> Result<impl Iterator<Item = RecordedFrame>, AppError> {
+    let file = File::open(path).map_err(|e| AppError::RecordingFailed(format!("{}: {}", path.display(), e)))?;
+    let reader: Box<dyn Read> = if path.extension().is_some_and(|extension| extension == "gz") {Box::new(MultiGzDecoder::new(file))} else {Box::new(file)};
+    let path = path.to_path_buf();
+    let mut lines = BufReader::new(reader).lines();
+    Ok(std::iter::from_fn(move || {
+        let line = lines.next()?;
+        // A recorder that was not shut down leaves its last file truncated.
+        let frame = line.map_err(|e| e.to_string()).and_then(|line| serde_json::from_str::<RecordedFrame>(&line).map_err(|e| e.to_string()));
+        if frame.is_err() {eprintln!("{} ends early; skipping the rest of it.", path.display());}
+        frame.ok()
+    }))
+}
+
+/// Append-only, gzip-compressed frame log that rolls over to a new file after `max_file_bytes`.
+pub struct Recorder {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    written: u64,
+    last_flush: Instant,
+    encoder: Option<GzEncoder<File>>,
+}
+
+impl Recorder {
+    pub fn new(dir: impl Into<PathBuf>, max_file_bytes: u64) -> io::Result<Recorder> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Recorder { dir, max_file_bytes, written: 0, last_flush: Instant::now(), encoder: None })
+    }
+
+    /// Records a frame received on the feed of `key`. Control frames carry no market data and are skipped.
+    pub fn record(&mut self, key: &BookKey, msg: &Message) -> io::Result<()> {
+        let (kind, payload, encoding) = match msg {
+            Message::Text(s) => ("text", s.clone(), ""),
+            Message::Binary(b) => ("binary", BASE64.encode(b), "base64"),
+            _ => return Ok(()),
+        };
+        let frame = RecordedFrame { received_at: Utc::now().timestamp_micros(), venue: key.exchange.clone(), instrument: key.instrument.clone(), kind: kind.to_string(), payload, encoding: encoding.to_string() };
+        let mut line = serde_json::to_vec(&frame).map_err(io::Error::from)?;
+        line.push(b'\n');
+
+        if self.encoder.is_none() || self.written + line.len() as u64 > self.max_file_bytes {self.rotate()?;}
+        let encoder = self.encoder.as_mut().expect("recorder file is open after rotate");
+        encoder.write_all(&line)?;
+        self.written += line.len() as u64;
+
+        if self.last_flush.elapsed().as_secs() >= 1 {
+            self.last_flush = Instant::now();
+            encoder.flush()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {encoder.finish()?;}
+        let path = self.dir.join(format!("frames-{}.jsonl.gz", Utc::now().format("%Y%m%dT%H%M%S%.6f")));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.encoder = Some(GzEncoder::new(file, Compression::default()));
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.encoder.take() {let _ = encoder.finish();}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_frames_are_recorded_losslessly() {
+        let dir = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        let key = BookKey::new("Binance", "BTC/USDT");
+        let binary = vec![0x00, 0xff, 0xfe, 0x80, b'{'];
+        let mut recorder = Recorder::new(&dir, DEFAULT_MAX_FILE_BYTES).unwrap();
+        recorder.record(&key, &Message::Text("{\"e\":1}".to_string())).unwrap();
+        recorder.record(&key, &Message::Binary(binary.clone())).unwrap();
+        recorder.record(&key, &Message::Ping(vec![1])).unwrap();
+        drop(recorder);
+
+        let files = recordings(&dir).unwrap();
+        let frames: Vec<RecordedFrame> = files.iter().flat_map(|path| frames(path).unwrap()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].kind.as_str(), frames[0].encoding.as_str()), ("text", ""));
+        assert_eq!(frames[0].text().unwrap(), "{\"e\":1}");
+        assert_eq!((frames[1].kind.as_str(), frames[1].encoding.as_str()), ("binary", "base64"));
+        assert_eq!(frames[1].data().unwrap().as_ref(), binary.as_slice());
+        assert!(frames[1].text().is_err());
+    }
+
+    #[test]
+    fn unencoded_payloads_are_read_as_they_are() {
+        let frame = RecordedFrame { received_at: 1, venue: "Kraken".to_string(), instrument: String::new(), kind: "binary".to_string(), payload: "abc".to_string(), encoding: String::new() };
+        assert_eq!(frame.data().unwrap().as_ref(), b"abc");
+        assert!(RecordedFrame { encoding: "rot13".to_string(), ..frame }.data().is_err());
+    }
+}
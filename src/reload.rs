@@ -0,0 +1,301 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, thread, time::{Duration, SystemTime}};
+use crate::{AppError, FeedConfig, ServeOptions, VENUE_BOOKS, aggregator, analytics, config::Config, impairment::Impairment, impairment_from_env, instruments::{Instrument, Listing, Registry}, models::BookKey, recorder::Recorder, supervise, venue};
+
+/// How often the configuration file is checked for changes.
+static WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the gRPC service answers from that a reload can change. Streams already running keep what they started with.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub instruments: Registry,
+    pub analytics: analytics::Parameters,
+    /// Levels per side of `BookSummary` responses to requests that set no depth.
+    pub summary_depth: usize,
+}
+
+/// A started connector and what it was started with.
+struct Running {
+    /// Without its venues, which only matter to the other feeds.
+    instrument: Instrument,
+    listing: Listing,
+    url: String,
+    impairment: Option<Impairment>,
+    stop: Arc<AtomicBool>,
+}
+
+/// The connectors of the live feeds, started and stopped to match the configuration.
+pub struct Feeds {
+    running: HashMap<BookKey, Running>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    /// The view of the terminal UI, when it is shown.
+    display: Option<Arc<Mutex<aggregator::View>>>,
+}
+
+impl Feeds {
+    pub fn new(recorder: Option<Arc<Mutex<Recorder>>>, display: Option<Arc<Mutex<aggregator::View>>>) -> Feeds {
+        Feeds { running: HashMap::new(), recorder, display }
+    }
+
+    /// Starts a connector for every listing of `instruments` that is not already running with the same listing,
+    /// endpoint and impairment, and stops the rest, dropping their books. Returns the feeds started and stopped.
+    pub fn apply(&mut self, config: &Config, instruments: &Registry) -> Result<(Vec<BookKey>, Vec<BookKey>), AppError> {
+        let mut wanted = HashMap::new();
+        for instrument in &instruments.instruments {
+            for (exchange, listing) in &instrument.venues {
+                let (connector, default_url, _) = venue(exchange).ok_or_else(|| AppError::ParsingFailed(format!("{} is listed on unsupported exchange {}", instrument.name, exchange)))?;
+                let url = config.exchange(exchange).url.unwrap_or_else(|| default_url.to_string());
+                wanted.insert(BookKey::new(exchange, &instrument.name), (connector, instrument, listing, url, impairment_from_env(exchange)?));
+            }
+        }
+
+        let changed: Vec<BookKey> = self.running.iter()
+            .filter(|(key, running)| !wanted.get(*key).is_some_and(|(_, instrument, listing, url, impairment)| running.instrument == unlisted(instrument) && running.listing == **listing && running.url == *url && running.impairment == *impairment))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &changed {
+            if let Some(running) = self.running.remove(key) {running.stop.store(true, Ordering::Relaxed);}
+            VENUE_BOOKS.lock().unwrap().remove(key);
+        }
+
+        let mut started = Vec::new();
+        for (key, (connector, instrument, listing, url, impairment)) in wanted {
+            if self.running.contains_key(&key) {continue;}
+            let stop = Arc::new(AtomicBool::new(false));
+            self.running.insert(key.clone(), Running { instrument: unlisted(instrument), listing: listing.clone(), url: url.clone(), impairment: impairment.clone(), stop: stop.clone() });
+            started.push(key.clone());
+            let feed = FeedConfig { key, instrument: instrument.clone(), listing: listing.clone(), url, recorder: self.recorder.clone(), impairment, stop, connections: AtomicU64::new(0) };
+            thread::spawn(move || supervise(&feed, || connector(&feed)));
+        }
+        Ok((started, changed))
+    }
+}
+
+fn unlisted(instrument: &Instrument) -> Instrument {Instrument { venues: Default::default(), ..instrument.clone() }}
+
+/// SIGHUP where the platform has it. Elsewhere the configuration is only reloaded when its file changes.
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Hangup {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(hangup) => Hangup(Some(hangup)),
+            Err(e) => {eprintln!("Reloading on SIGHUP is unavailable: {}", e); Hangup(None)}
+        }
+    }
+
+    async fn recv(&mut self) -> Option<()> {self.0.as_mut()?.recv().await}
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Hangup {Hangup}
+
+    async fn recv(&mut self) -> Option<()> {None}
+}
+
+/// Reloads the configuration on SIGHUP and whenever the file at `path` changes, and applies what can change without a
+/// restart: the exchanges and instruments followed, the depths and the analytics defaults. Flags still take precedence.
+/// A configuration that does not load or validate is reported and the running one is kept.
+pub async fn run(path: Option<PathBuf>, mut config: Config, options: ServeOptions, mut feeds: Feeds, settings: Arc<Mutex<Settings>>) {
+    let mut hangup = Hangup::new();
+    let modified = || path.as_deref().and_then(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok());
+    let mut last_modified: Option<SystemTime> = modified();
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            Some(()) = hangup.recv() => {}
+            _ = interval.tick() => {
+                let current = modified();
+                if current == last_modified {continue;}
+                last_modified = current;
+            }
+        }
+        match reload(path.as_deref(), &config, &options, &mut feeds, &settings) {
+            Ok(reloaded) => config = reloaded,
+            Err(e) => eprintln!("Configuration not reloaded: {}", e),
+        }
+    }
+}
+
+fn reload(path: Option<&Path>, current: &Config, options: &ServeOptions, feeds: &mut Feeds, settings: &Mutex<Settings>) -> Result<Config, AppError> {
+    let config = Config::load(path)?;
+    let instruments = options.select(config.registry())?;
+    let display = aggregator::View { depth: options.depth.unwrap_or(config.display()?.depth), ..config.display()? };
+    let (started, stopped) = feeds.apply(&config, &instruments)?;
+
+    *settings.lock().unwrap() = Settings { instruments, analytics: config.analytics(), summary_depth: options.depth.unwrap_or(config.summary_depth()) };
+    if let Some(view) = &feeds.display {*view.lock().unwrap() = display;}
+
+    let list = |keys: Vec<BookKey>| if keys.is_empty() {"none".to_string()} else {keys.iter().map(BookKey::to_string).collect::<Vec<_>>().join(", ")};
+    println!("Configuration reloaded. Stopped: {}. Started: {}.", list(stopped), list(started));
+    let restart = needs_restart(current, &config);
+    if !restart.is_empty() {eprintln!("Changes to [{}] take effect after a restart.", restart.join("], ["));}
+    Ok(config)
+}
+
+/// The sections of `new` that differ from `current` in settings read only at startup.
+fn needs_restart(current: &Config, new: &Config) -> Vec<&'static str> {
+    let startup = |config: &Config| (config.analytics.fees_file.clone(), config.analytics.fx_file.clone(), config.analytics.candle_intervals.clone(), config.analytics.spread_sample_ms, config.analytics.stale_after_ms);
+    [("server", current.server != new.server), ("recording", current.recording != new.recording), ("analytics", startup(current) != startup(new))]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(section, _)| section)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, net::TcpListener, sync::mpsc};
+    use crate::{STOP_CHECK_INTERVAL, config, kraken, models::{InstrumentKind, OrderBook}};
+
+    /// An endpoint that refuses connections, so that the connectors started keep retrying without reaching a venue.
+    const NOWHERE: &str = "ws://127.0.0.1:9";
+
+    fn config(urls: &[(&str, &str)]) -> Config {
+        let exchanges = urls.iter().map(|(exchange, url)| (exchange.to_string(), config::Exchange { url: Some(url.to_string()), ..config::Exchange::default() }));
+        Config { exchanges: exchanges.collect(), ..Config::default() }
+    }
+
+    /// An instrument of its own on Kraken and Bitstamp, so that other tests using the global books are not disturbed.
+    fn registry(kraken_symbol: &str) -> Registry {
+        let instrument = Instrument {
+            name: "RELOAD/TEST".to_string(), base: "RELOAD".to_string(), quote: "TEST".to_string(), tick_size: 0.1, lot_size: 0.1, kind: InstrumentKind::Spot,
+            venues: BTreeMap::from([
+                ("Kraken".to_string(), Listing::new(kraken_symbol, &["book"])),
+                ("Bitstamp".to_string(), Listing::new("reloadtest", &["live_orders_reloadtest"])),
+            ]),
+        };
+        Registry { instruments: vec![instrument] }
+    }
+
+    #[test]
+    fn apply_restarts_only_the_feeds_that_changed() {
+        let (kraken, bitstamp) = (BookKey::new("Kraken", "RELOAD/TEST"), BookKey::new("Bitstamp", "RELOAD/TEST"));
+        let mut feeds = Feeds::new(None, None);
+        let urls = [("Kraken", NOWHERE), ("Bitstamp", NOWHERE)];
+        let (mut started, stopped) = feeds.apply(&config(&urls), &registry("RELOAD/TEST")).unwrap();
+        started.sort();
+        assert_eq!((started, stopped), (vec![bitstamp.clone(), kraken.clone()], vec![]));
+
+        let fill_books = || {
+            let mut books = VENUE_BOOKS.lock().unwrap();
+            for key in [&kraken, &bitstamp] {books.insert(key.clone(), OrderBook::default());}
+        };
+        let has_book = |key: &BookKey| VENUE_BOOKS.lock().unwrap().contains_key(key);
+        let stop = |feeds: &Feeds, key: &BookKey| feeds.running[key].stop.clone();
+        fill_books();
+        assert_eq!(feeds.apply(&config(&urls), &registry("RELOAD/TEST")).unwrap(), (vec![], vec![]));
+        assert!(has_book(&kraken) && has_book(&bitstamp));
+
+        // A new listing restarts its feed alone and drops its book.
+        let kraken_stop = stop(&feeds, &kraken);
+        assert_eq!(feeds.apply(&config(&urls), &registry("RELOAD-TEST")).unwrap(), (vec![kraken.clone()], vec![kraken.clone()]));
+        assert!(kraken_stop.load(Ordering::Relaxed) && !stop(&feeds, &kraken).load(Ordering::Relaxed));
+        assert!(!has_book(&kraken) && has_book(&bitstamp));
+
+        // So do a new endpoint and a new impairment.
+        fill_books();
+        let moved = [("Kraken", NOWHERE), ("Bitstamp", "ws://127.0.0.1:10")];
+        assert_eq!(feeds.apply(&config(&moved), &registry("RELOAD-TEST")).unwrap(), (vec![bitstamp.clone()], vec![bitstamp.clone()]));
+        assert!(has_book(&kraken) && !has_book(&bitstamp));
+        fill_books();
+        std::env::set_var("EXCHANGE_IMPAIRMENT_BITSTAMP", "drop=0.5");
+        let impaired = feeds.apply(&config(&moved), &registry("RELOAD-TEST"));
+        std::env::remove_var("EXCHANGE_IMPAIRMENT_BITSTAMP");
+        assert_eq!(impaired.unwrap(), (vec![bitstamp.clone()], vec![bitstamp.clone()]));
+        assert!(has_book(&kraken) && !has_book(&bitstamp));
+
+        // A disabled exchange's feeds are stopped and not replaced.
+        let (kraken_stop, bitstamp_stop) = (stop(&feeds, &kraken), stop(&feeds, &bitstamp));
+        let mut without_bitstamp = registry("RELOAD-TEST");
+        without_bitstamp.instruments[0].venues.remove("Bitstamp");
+        assert_eq!(feeds.apply(&config(&moved), &without_bitstamp).unwrap(), (vec![], vec![bitstamp.clone()]));
+        assert!(bitstamp_stop.load(Ordering::Relaxed) && !kraken_stop.load(Ordering::Relaxed));
+        assert_eq!(feeds.apply(&config(&moved), &Registry { instruments: Vec::new() }).unwrap(), (vec![], vec![kraken.clone()]));
+        assert!(kraken_stop.load(Ordering::Relaxed) && feeds.running.is_empty());
+        VENUE_BOOKS.lock().unwrap().remove(&kraken);
+    }
+
+    #[test]
+    fn only_startup_settings_need_a_restart() {
+        let current = Config::default();
+        let changed = |change: fn(&mut Config)| {
+            let mut new = current.clone();
+            change(&mut new);
+            needs_restart(&current, &new)
+        };
+        assert!(changed(|_| {}).is_empty());
+        assert_eq!(changed(|config| config.server.listen = Some("127.0.0.1:6000".parse().unwrap())), ["server"]);
+        assert_eq!(changed(|config| config.recording.dir = Some(PathBuf::from("frames"))), ["recording"]);
+        assert_eq!(changed(|config| config.analytics.fees_file = Some(PathBuf::from("fees.json"))), ["analytics"]);
+        assert_eq!(changed(|config| config.analytics.candle_intervals = Some(vec!["1h".to_string()])), ["analytics"]);
+        assert_eq!(changed(|config| {config.server.listen = Some("127.0.0.1:6000".parse().unwrap()); config.analytics.stale_after_ms = Some(1)}), ["server", "analytics"]);
+        // What a reload applies itself.
+        assert!(changed(|config| config.analytics.depth_bps = Some(5.0)).is_empty());
+        assert!(changed(|config| config.display.depth = Some(3)).is_empty());
+        assert!(changed(|config| {config.exchanges.insert("Kraken".to_string(), config::Exchange { enabled: false, ..config::Exchange::default() });}).is_empty());
+    }
+
+    #[test]
+    fn a_stopped_connector_returns_while_its_feed_is_quiet() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (hang_up, hung_up) = mpsc::channel::<()>();
+        // A venue that takes the subscription and then sends nothing.
+        let server = thread::spawn(move || {
+            let mut socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            socket.read_message().unwrap();
+            let _ = hung_up.recv();
+        });
+        let instrument = registry("RELOAD/TEST").instruments.remove(0);
+        let feed = FeedConfig { key: BookKey::new("Kraken", "RELOAD/QUIET"), listing: instrument.venues["Kraken"].clone(), instrument, url, recorder: None, impairment: None, stop: Arc::new(AtomicBool::new(false)), connections: AtomicU64::new(0) };
+        let stop = feed.stop.clone();
+        let (returned, connector) = mpsc::channel();
+        thread::spawn(move || returned.send(kraken::pull(&feed).is_ok()).unwrap());
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !VENUE_BOOKS.lock().unwrap().contains_key(&BookKey::new("Kraken", "RELOAD/QUIET")) {
+            assert!(std::time::Instant::now() < deadline, "the connector did not connect");
+            thread::sleep(Duration::from_millis(10));
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(connector.recv_timeout(STOP_CHECK_INTERVAL * 3), Ok(true), "the connector did not return");
+        hang_up.send(()).unwrap();
+        server.join().unwrap();
+        VENUE_BOOKS.lock().unwrap().remove(&BookKey::new("Kraken", "RELOAD/QUIET"));
+    }
+
+    #[test]
+    fn a_stopped_feed_no_longer_reaches_its_book() {
+        let instrument = Registry::default().instruments[0].clone();
+        let (exchange, listing) = instrument.venues.iter().next().map(|(exchange, listing)| (exchange.clone(), listing.clone())).unwrap();
+        let feed = FeedConfig { key: BookKey::new(&exchange, &instrument.name), instrument, listing, url: String::new(), recorder: None, impairment: None, stop: Arc::new(AtomicBool::new(false)), connections: AtomicU64::new(0) };
+        let mut books = HashMap::new();
+        assert!(feed.book(&mut books).is_some());
+        assert!(books.contains_key(&feed.key));
+
+        // What a reload does to a feed it replaces.
+        feed.stop.store(true, Ordering::Relaxed);
+        books.remove(&feed.key);
+        assert!(feed.book(&mut books).is_none());
+        assert!(books.is_empty());
+    }
+
+    #[test]
+    fn a_feed_tags_its_book_with_the_instrument_kind() {
+        // A perpetual on a venue whose connector knows nothing about contracts.
+        let mut instrument = Registry::default().instruments.into_iter().find(|instrument| instrument.kind == InstrumentKind::Perpetual).unwrap();
+        let listing = Registry::default().instruments[0].venues.get("Kraken").cloned().unwrap();
+        instrument.venues = [("Kraken".to_string(), listing.clone())].into_iter().collect();
+        let feed = FeedConfig { key: BookKey::new("Kraken", &instrument.name), instrument, listing, url: String::new(), recorder: None, impairment: None, stop: Arc::new(AtomicBool::new(false)), connections: AtomicU64::new(0) };
+        let mut books = HashMap::new();
+        assert_eq!(feed.book(&mut books).unwrap().kind, InstrumentKind::Perpetual);
+    }
+}
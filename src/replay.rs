@@ -0,0 +1,61 @@
+use std::{collections::HashMap, path::PathBuf, sync::Mutex, thread, time::{Duration, Instant}};
+use clap::Args;
+use tokio::sync::broadcast;
+use crate::{AppError, log_crossing, export::Feed, instruments::Registry, models::{BookKey, OrderBook}, orderbook::TradeEvent, recorder};
+
+/// Which recording to replay, and how fast.
+#[derive(Args, Debug, Clone)]
+pub struct Options {
+    /// Directory of `frames-*.jsonl.gz` files, or a single such file.
+    pub input: PathBuf,
+    /// Playback speed relative to the recording, e.g. 10 for ten times faster. 0 replays as fast as possible.
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+}
+
+/// The frame files to replay, after checking that there are some and that the speed is usable.
+pub fn recordings(options: &Options) -> Result<Vec<PathBuf>, AppError> {
+    if !(options.speed >= 0.0 && options.speed.is_finite()) {return Err(AppError::ParsingFailed(format!("replay speed {}", options.speed)));}
+    let files = recorder::recordings(&options.input)?;
+    if files.is_empty() {return Err(AppError::RecordingFailed(format!("no frames-*.jsonl files in {}", options.input.display())));}
+    Ok(files)
+}
+
+/// Applies the recorded frames of every feed in `registry` to `books` and publishes their trades on `trades`, as the
+/// connectors did when the frames were received. Books are marked stale once the recording ends.
+pub fn run(options: &Options, files: Vec<PathBuf>, registry: &Registry, books: &Mutex<HashMap<BookKey, OrderBook>>, trades: &broadcast::Sender<TradeEvent>) -> Result<(), AppError> {
+    let mut feeds: HashMap<BookKey, Feed> = HashMap::new();
+    let mut start: Option<(i64, Instant)> = None;
+    let mut replayed = 0;
+
+    for path in files {
+        for frame in recorder::frames(&path)? {
+            let Some(name) = frame.instrument(registry) else {continue};
+            let (Some(instrument), Some(listing)) = (registry.get(name), registry.get(name).and_then(|instrument| instrument.venues.get(&frame.venue))) else {continue};
+            if options.speed > 0.0 {
+                let (first, started) = *start.get_or_insert((frame.received_at, Instant::now()));
+                let due = Duration::from_micros(((frame.received_at - first).max(0) as f64 / options.speed) as u64);
+                if let Some(wait) = due.checked_sub(started.elapsed()) {thread::sleep(wait);}
+            }
+
+            let key = BookKey::new(&frame.venue, name);
+            let feed = feeds.entry(key.clone()).or_default();
+            let update = feed.apply(&key, instrument, listing, &frame.text()?)?;
+            let mut books = books.lock().unwrap();
+            let order_book = books.entry(key.clone()).or_default();
+            order_book.kind = instrument.kind;
+            (order_book.bids, order_book.asks) = (feed.book.bids.clone(), feed.book.asks.clone());
+            order_book.touch(update.update_id);
+            log_crossing(&key, order_book);
+            drop(books);
+            for trade in update.trades {let _ = trades.send(trade);}
+            replayed += 1;
+        }
+    }
+
+    for key in feeds.keys() {
+        if let Some(order_book) = books.lock().unwrap().get_mut(key) {order_book.mark_stale("replay finished".to_string());}
+    }
+    println!("Replayed {} frames from {}.", replayed, options.input.display());
+    Ok(())
+}
@@ -0,0 +1,796 @@
+use std::{collections::BTreeMap, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, sync::{Arc, Mutex}, time::Duration};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use clap::Args;
+use futures::{SinkExt, StreamExt};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Exp, LogNormal, Normal};
+use serde_json::json;
+use tokio::{net::{TcpListener, TcpStream}, sync::broadcast};
+use tokio_tungstenite::tungstenite::{handshake::server::{Request, Response}, Message};
+use crate::{AppError, integrity, bybit::{OrderbookData, OrderbookMessage}, coinbase::{FeedMessage, Match}, kraken::{self, BookData, BookLevel, BookMessage}, okx::{BooksArg, BooksData, BooksMessage}, fees::{FeeSchedule, Liquidity}, models::{Data, LimitPrice, Msg, Order, OrderBook, OrderType, Trade}};
+
+pub const DEFAULT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9555));
+/// How often the fake exchange logs the fees it has charged on simulated fills.
+const FILL_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the fake exchange sends Kraken book updates.
+const KRAKEN_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+/// Batching interval of the fake `level2_batch` channel.
+const COINBASE_BATCH_INTERVAL: Duration = Duration::from_millis(50);
+const COINBASE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Push intervals of the fake OKX `books` and Bybit `orderbook.50` channels.
+const OKX_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+const BYBIT_UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+const OKX_BOOK_DEPTH: usize = 400;
+const BYBIT_BOOK_DEPTH: usize = 50;
+/// Decimal places of the fake Kraken prices and quantities.
+const KRAKEN_PRICE_PRECISION: usize = 1;
+const KRAKEN_QTY_PRECISION: usize = 8;
+/// Base units per contract of the fake OKX swap.
+const OKX_CONTRACT_SIZE: f64 = 0.01;
+
+/// Parameters of the stochastic order flow generated by the fake exchange.
+#[derive(Debug, Clone)]
+pub struct MarketModel {
+    /// Mid price the random walk starts from.
+    pub start_mid: f64,
+    /// Standard deviation of the mid price move per second.
+    pub volatility: f64,
+    pub tick_size: f64,
+    /// Mean number of order events per second (Poisson arrivals).
+    pub arrival_rate: f64,
+    /// Probability that an event cancels a resting order.
+    pub cancel_probability: f64,
+    /// Probability that an event partially reduces a resting order.
+    pub change_probability: f64,
+    /// Mean distance of new orders from the mid, in ticks (exponentially distributed).
+    pub mean_offset_ticks: f64,
+    /// Orders further than this from the mid are cancelled.
+    pub max_offset_ticks: f64,
+    /// Parameters of the log-normal order size distribution.
+    pub size_mu: f64,
+    pub size_sigma: f64,
+    /// Fees charged to both sides of every simulated fill.
+    pub fees: FeeSchedule,
+    pub seed: Option<u64>,
+}
+
+impl Default for MarketModel {
+    fn default() -> MarketModel {
+        MarketModel {
+            start_mid: 30000.0,
+            volatility: 5.0,
+            tick_size: 1.0,
+            arrival_rate: 50.0,
+            cancel_probability: 0.3,
+            change_probability: 0.1,
+            mean_offset_ticks: 10.0,
+            max_offset_ticks: 200.0,
+            size_mu: -2.0,
+            size_sigma: 1.0,
+            fees: FeeSchedule { maker_bps: 30.0, taker_bps: 40.0 },
+            seed: None,
+        }
+    }
+}
+
+/// Where the fake exchange listens and how its market starts.
+#[derive(Args, Debug, Clone)]
+pub struct Options {
+    #[arg(long, default_value_t = DEFAULT_ADDR)]
+    pub listen: SocketAddr,
+    /// Mid price the market starts from.
+    #[arg(long, default_value_t = MarketModel::default().start_mid)]
+    pub start_mid: f64,
+    /// Mean number of order events per second.
+    #[arg(long, default_value_t = MarketModel::default().arrival_rate)]
+    pub arrival_rate: f64,
+    /// Seed of the order flow, for a market that plays out the same way every run.
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+impl Options {
+    pub fn model(&self) -> Result<MarketModel, AppError> {
+        if !(self.start_mid > 0.0 && self.start_mid.is_finite()) {return Err(AppError::ParsingFailed(format!("start mid {}", self.start_mid)));}
+        if !(self.arrival_rate > 0.0 && self.arrival_rate.is_finite()) {return Err(AppError::ParsingFailed(format!("arrival rate {}", self.arrival_rate)));}
+        Ok(MarketModel { start_mid: self.start_mid, arrival_rate: self.arrival_rate, seed: self.seed, ..MarketModel::default() })
+    }
+}
+
+/// `(price, quantity)` pairs of one side, best first.
+type Levels = Vec<(f64, f64)>;
+
+#[derive(Debug, Clone)]
+enum Event {
+    Order(&'static str, Order),
+    Trade(Trade),
+}
+
+/// Running totals of simulated fills and the fees charged on them.
+#[derive(Debug, Default)]
+struct FillStats {
+    fills: u64,
+    volume: f64,
+    notional: f64,
+    maker_fees: f64,
+    taker_fees: f64,
+}
+
+struct Market {
+    model: MarketModel,
+    stats: FillStats,
+    rng: StdRng,
+    mid: f64,
+    orders: BTreeMap<u64, Order>,
+    next_order_id: u64,
+    next_trade_id: u64,
+    last_trade: Option<Trade>,
+    update_id: u64,
+}
+
+impl Market {
+    fn new(model: MarketModel) -> Market {
+        let rng = match model.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Market { mid: model.start_mid, model, stats: FillStats::default(), rng, orders: BTreeMap::new(), next_order_id: 1, next_trade_id: 1, last_trade: None, update_id: 1 }
+    }
+
+    fn next_arrival(&mut self) -> Duration {
+        let exp = Exp::new(self.model.arrival_rate).expect("arrival rate must be positive");
+        Duration::from_secs_f64(exp.sample(&mut self.rng))
+    }
+
+    /// Advances the mid by `dt` and generates one order event (plus any trades it causes).
+    fn step(&mut self, dt: Duration) -> Vec<Event> {
+        let walk = Normal::new(0.0, self.model.volatility * dt.as_secs_f64().sqrt()).expect("volatility must be finite");
+        self.mid = (self.mid + walk.sample(&mut self.rng)).max(self.model.tick_size);
+        self.update_id += 1;
+
+        let mut events = Vec::new();
+        let max_offset = self.model.max_offset_ticks * self.model.tick_size;
+        let stale: Vec<u64> = self.orders.values().filter(|o| (o.price - self.mid).abs() > max_offset).map(|o| o.id).collect();
+        for id in stale {
+            if let Some(order) = self.orders.remove(&id) {events.push(Event::Order("order_deleted", order));}
+        }
+
+        let u: f64 = self.rng.gen();
+        if u < self.model.cancel_probability && !self.orders.is_empty() {
+            let id = self.random_order_id();
+            if let Some(order) = self.orders.remove(&id) {events.push(Event::Order("order_deleted", order));}
+        } else if u < self.model.cancel_probability + self.model.change_probability && !self.orders.is_empty() {
+            let id = self.random_order_id();
+            let fraction: f64 = self.rng.gen_range(0.1..0.9);
+            if let Some(order) = self.orders.get_mut(&id) {
+                set_amount(order, order.amount * fraction);
+                events.push(Event::Order("order_changed", order.clone()));
+            }
+        } else {
+            self.new_order(&mut events);
+        }
+        events
+    }
+
+    fn random_order_id(&mut self) -> u64 {
+        let n = self.rng.gen_range(0..self.orders.len());
+        *self.orders.keys().nth(n).expect("index within bounds")
+    }
+
+    fn new_order(&mut self, events: &mut Vec<Event>) {
+        let order_type = if self.rng.gen_bool(0.5) {OrderType::Buy as u8} else {OrderType::Sell as u8};
+        let offset = Exp::new(1.0 / self.model.mean_offset_ticks).expect("mean offset must be positive").sample(&mut self.rng).round() * self.model.tick_size;
+        let raw_price = if order_type == OrderType::Buy as u8 {self.mid - offset} else {self.mid + offset};
+        let price = ((raw_price / self.model.tick_size).round() * self.model.tick_size).max(self.model.tick_size);
+        let size = LogNormal::new(self.model.size_mu, self.model.size_sigma).expect("size distribution parameters must be finite").sample(&mut self.rng);
+        let amount = (size * 1e8).round() / 1e8;
+        if amount <= 0.0 {return;}
+
+        let id = self.next_order_id;
+        self.next_order_id += 1;
+        let mut order = make_order(id, order_type, price, amount);
+        self.match_order(&mut order, events);
+        if order.amount > 0.0 {
+            self.orders.insert(id, order.clone());
+            events.push(Event::Order("order_created", order));
+        }
+    }
+
+    /// Fills an incoming order against resting orders on the other side, best price first.
+    fn match_order(&mut self, taker: &mut Order, events: &mut Vec<Event>) {
+        let is_buy = taker.order_type == OrderType::Buy as u8;
+        while taker.amount > 0.0 {
+            let best = self.orders.values()
+                .filter(|o| o.order_type != taker.order_type)
+                .filter(|o| if is_buy {o.price <= taker.price} else {o.price >= taker.price})
+                .min_by(|a, b| if is_buy {a.price.total_cmp(&b.price)} else {b.price.total_cmp(&a.price)})
+                .map(|o| o.id);
+            let Some(maker_id) = best else {break};
+            let maker = self.orders.get_mut(&maker_id).expect("maker order exists");
+            let fill = maker.amount.min(taker.amount);
+            let (buy_order_id, sell_order_id) = if is_buy {(taker.id, maker.id)} else {(maker.id, taker.id)};
+            let now = Utc::now();
+            let trade = Trade {
+                id: self.next_trade_id,
+                amount: fill,
+                amount_str: format!("{:.8}", fill),
+                buy_order_id,
+                microtimestamp: now.timestamp_micros().to_string(),
+                price: maker.price,
+                price_str: format!("{:.2}", maker.price),
+                sell_order_id,
+                timestamp: now.timestamp().to_string(),
+                _type: taker.order_type,
+            };
+            self.last_trade = Some(trade.clone());
+            events.push(Event::Trade(trade));
+            self.next_trade_id += 1;
+            self.stats.fills += 1;
+            self.stats.volume += fill;
+            self.stats.notional += fill * maker.price;
+            self.stats.maker_fees += self.model.fees.fee(Liquidity::Maker, maker.price, fill);
+            self.stats.taker_fees += self.model.fees.fee(Liquidity::Taker, maker.price, fill);
+
+            let remaining = ((taker.amount - fill) * 1e8).round() / 1e8;
+            set_amount(taker, remaining);
+            let left = ((maker.amount - fill) * 1e8).round() / 1e8;
+            if left > 0.0 {
+                set_amount(maker, left);
+                events.push(Event::Order("order_changed", maker.clone()));
+            } else if let Some(maker) = self.orders.remove(&maker_id) {
+                events.push(Event::Order("order_deleted", maker));
+            }
+        }
+    }
+
+    /// Aggregated levels of both sides.
+    fn levels(&self, levels: usize) -> (Levels, Levels) {
+        let mut bids: BTreeMap<i64, f64> = BTreeMap::new();
+        let mut asks: BTreeMap<i64, f64> = BTreeMap::new();
+        for order in self.orders.values() {
+            let key = (order.price / self.model.tick_size).round() as i64;
+            let side = if order.order_type == OrderType::Buy as u8 {&mut bids} else {&mut asks};
+            *side.entry(key).or_insert(0.0) += order.amount;
+        }
+        let level = |(key, size): (&i64, &f64)| (*key as f64 * self.model.tick_size, *size);
+        (bids.iter().rev().take(levels).map(level).collect(), asks.iter().take(levels).map(level).collect())
+    }
+
+    /// Aggregated price levels, best first, in Binance's `[price, quantity]` string form.
+    fn depth(&self, levels: usize) -> (Vec<[String; 2]>, Vec<[String; 2]>) {
+        let (bids, asks) = self.levels(levels);
+        let level = |&(price, size): &(f64, f64)| [format!("{:.8}", price), format!("{:.8}", size)];
+        (bids.iter().map(level).collect(), asks.iter().map(level).collect())
+    }
+}
+
+fn set_amount(order: &mut Order, amount: f64) {
+    order.amount = amount;
+    order.amount_str = format!("{:.8}", amount);
+}
+
+fn make_order(id: u64, order_type: u8, price: f64, amount: f64) -> Order {
+    let now = Utc::now();
+    Order {
+        id,
+        id_str: id.to_string(),
+        order_type,
+        datetime: now.timestamp().to_string(),
+        microtimestamp: now.timestamp_micros().to_string(),
+        amount,
+        amount_str: format!("{:.8}", amount),
+        price,
+        price_str: format!("{:.2}", price),
+    }
+}
+
+/// Runs a local WebSocket server that speaks Bitstamp's `bts:subscribe` protocol on `/`,
+/// Binance's partial depth stream on `/ws/<symbol>@depth<N>[@100ms]`, Kraken's v2 `book` channel on `/v2`,
+/// Coinbase's `level2_batch`, `matches` and `heartbeat` channels on `/coinbase`, OKX's `books` channel on
+/// `/ws/v5/public` and Bybit's `orderbook.50` topic on `/v5/public/<category>`. All venues share one market.
+pub async fn serve(addr: SocketAddr, model: MarketModel) -> Result<(), AppError> {
+    let listener = TcpListener::bind(addr).await.map_err(|e| AppError::ConnectionFailed(e.to_string()))?;
+    let market = Arc::new(Mutex::new(Market::new(model)));
+    let (events, _) = broadcast::channel(1024);
+
+    println!("Fake exchange listening on ws://{}", addr);
+    tokio::spawn(generate(market.clone(), events.clone()));
+
+    loop {
+        let (stream, peer) = listener.accept().await.map_err(|e| AppError::ConnectionFailed(e.to_string()))?;
+        let market = market.clone();
+        let events = events.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, market, events).await {eprintln!("Fake exchange connection {} closed: {}", peer, e);}
+        });
+    }
+}
+
+async fn generate(market: Arc<Mutex<Market>>, events: broadcast::Sender<Event>) {
+    let mut last_report = std::time::Instant::now();
+    loop {
+        if last_report.elapsed() >= FILL_REPORT_INTERVAL {
+            last_report = std::time::Instant::now();
+            let stats = &market.lock().unwrap().stats;
+            println!("Fake exchange: {} fills, volume {:.8}, notional {:.2}, maker fees {:.2}, taker fees {:.2}", stats.fills, stats.volume, stats.notional, stats.maker_fees, stats.taker_fees);
+        }
+        let delay = market.lock().unwrap().next_arrival();
+        tokio::time::sleep(delay).await;
+        let generated = market.lock().unwrap().step(delay);
+        for event in generated {let _ = events.send(event);}
+    }
+}
+
+async fn handle_connection(stream: TcpStream, market: Arc<Mutex<Market>>, events: broadcast::Receiver<Event>) -> Result<(), AppError> {
+    let mut path = String::new();
+    // The error type is fixed by tungstenite's handshake callback signature.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        path = request.uri().path().to_string();
+        Ok(response)
+    };
+    let socket = tokio_tungstenite::accept_hdr_async(stream, callback).await.map_err(|e| AppError::ConnectionFailed(e.to_string()))?;
+
+    match path.as_str() {
+        "/v2" => serve_kraken(socket, market).await,
+        "/coinbase" => serve_coinbase(socket, market, events).await,
+        "/ws/v5/public" => serve_okx(socket, market).await,
+        bybit if bybit.starts_with("/v5/public/") => serve_bybit(socket, market).await,
+        other => match other.strip_prefix("/ws/") {
+            Some(stream_name) => serve_binance(socket, stream_name, market).await,
+            None => serve_bitstamp(socket, events).await,
+        },
+    }
+}
+
+async fn serve_binance(mut socket: tokio_tungstenite::WebSocketStream<TcpStream>, stream_name: &str, market: Arc<Mutex<Market>>) -> Result<(), AppError> {
+    let levels = stream_name.split('@').find_map(|part| part.strip_prefix("depth")).and_then(|n| n.parse().ok()).unwrap_or(10);
+    let interval = if stream_name.ends_with("@100ms") {Duration::from_millis(100)} else {Duration::from_secs(1)};
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let frame = {
+            let market = market.lock().unwrap();
+            let (bids, asks) = market.depth(levels);
+            json!({"lastUpdateId": market.update_id, "bids": bids, "asks": asks}).to_string()
+        };
+        socket.send(Message::Text(frame)).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+    }
+}
+
+async fn serve_bitstamp(socket: tokio_tungstenite::WebSocketStream<TcpStream>, mut events: broadcast::Receiver<Event>) -> Result<(), AppError> {
+    let (mut sink, mut source) = socket.split();
+    let mut orders_channel: Option<String> = None;
+    let mut trades_channel: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            incoming = source.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(AppError::MessageError(e.to_string())),
+                };
+                let request: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                if request["event"] != "bts:subscribe" {continue;}
+                let channel = request["data"]["channel"].as_str().unwrap_or_default().to_string();
+                if channel.starts_with("live_orders_") {orders_channel = Some(channel.clone());}
+                else if channel.starts_with("live_trades_") {trades_channel = Some(channel.clone());}
+                let reply = Msg { channel, event: "bts:subscription_succeeded".to_string(), data: Data::None {} };
+                sink.send(Message::Text(serde_json::to_string(&reply).expect("Msg serializes"))).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+            }
+            event = events.recv() => {
+                let reply = match event {
+                    Ok(Event::Order(name, order)) => orders_channel.as_ref().map(|channel| Msg { channel: channel.clone(), event: name.to_string(), data: Data::Order(order) }),
+                    Ok(Event::Trade(trade)) => trades_channel.as_ref().map(|channel| Msg { channel: channel.clone(), event: "trade".to_string(), data: Data::Trade(trade) }),
+                    Err(broadcast::error::RecvError::Lagged(_)) => None,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                if let Some(reply) = reply {
+                    sink.send(Message::Text(serde_json::to_string(&reply).expect("Msg serializes"))).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+                }
+            }
+        }
+    }
+}
+
+/// Levels that take a client holding `old` to `new`; levels that disappeared are sent with a zero quantity.
+fn level_changes(old: &[LimitPrice], new: &[(f64, f64)]) -> Levels {
+    let removed = old.iter().filter(|l| !new.iter().any(|&(price, _)| price == l.price.into_inner())).map(|l| (l.price.into_inner(), 0.0));
+    let changed = new.iter().filter(|&&(price, qty)| !old.iter().any(|l| l.price.into_inner() == price && l.size.into_inner() == qty)).copied();
+    removed.chain(changed).collect()
+}
+
+fn kraken_changes(old: &[LimitPrice], new: &[(f64, f64)]) -> Vec<BookLevel> {
+    level_changes(old, new).into_iter().map(|(price, qty)| BookLevel { price, qty }).collect()
+}
+
+async fn serve_kraken(socket: tokio_tungstenite::WebSocketStream<TcpStream>, market: Arc<Mutex<Market>>) -> Result<(), AppError> {
+    let (mut sink, mut source) = socket.split();
+    // Requested depth while subscribed, and the book as the client should currently hold it.
+    let mut depth: Option<usize> = None;
+    let mut symbol = String::new();
+    let mut sent: Option<OrderBook> = None;
+    let mut ticker = tokio::time::interval(KRAKEN_UPDATE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            incoming = source.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(AppError::MessageError(e.to_string())),
+                };
+                let request: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                let requested = request["params"]["depth"].as_u64().unwrap_or(kraken::BOOK_DEPTH as u64);
+                match request["method"].as_str() {
+                    Some("subscribe") => {
+                        depth = Some(requested as usize);
+                        symbol = request["params"]["symbol"][0].as_str().unwrap_or_default().to_string();
+                    }
+                    Some("unsubscribe") => depth = None,
+                    _ => continue,
+                }
+                sent = None;
+                let reply = json!({"method": request["method"], "result": {"channel": "book", "depth": requested, "symbol": symbol}, "success": true});
+                sink.send(Message::Text(reply.to_string())).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+            }
+            _ = ticker.tick() => {
+                let Some(depth) = depth else {continue};
+                let (bids, asks) = market.lock().unwrap().levels(depth);
+                let (kind, book) = match sent.as_mut() {
+                    Some(book) => ("update", book),
+                    None => ("snapshot", sent.insert(OrderBook::default())),
+                };
+                let (bid_changes, ask_changes) = (kraken_changes(&book.bids, &bids), kraken_changes(&book.asks, &asks));
+                if kind == "update" && bid_changes.is_empty() && ask_changes.is_empty() {continue;}
+
+                for level in &bid_changes {book.set_level(OrderType::Buy as u8, level.price, level.qty);}
+                for level in &ask_changes {book.set_level(OrderType::Sell as u8, level.price, level.qty);}
+                book.truncate(depth);
+                let checksum = integrity::kraken_checksum(book, KRAKEN_PRICE_PRECISION, KRAKEN_QTY_PRECISION);
+                let data = BookData { symbol: symbol.clone(), bids: bid_changes, asks: ask_changes, checksum, timestamp: Some(Utc::now().to_rfc3339()) };
+                let message = BookMessage { channel: "book".to_string(), kind: kind.to_string(), data: vec![data] };
+                sink.send(Message::Text(serde_json::to_string(&message).expect("BookMessage serializes"))).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+            }
+        }
+    }
+}
+
+/// A simulated trade as a Coinbase match. Coinbase reports the maker's side, the opposite of the taker's.
+fn coinbase_match(trade: &Trade, product_id: &str, sequence: u64) -> Match {
+    let micros: i64 = trade.microtimestamp.parse().unwrap_or_default();
+    Match {
+        trade_id: trade.id,
+        sequence,
+        maker_order_id: if trade._type == OrderType::Buy as u8 {trade.sell_order_id} else {trade.buy_order_id}.to_string(),
+        taker_order_id: if trade._type == OrderType::Buy as u8 {trade.buy_order_id} else {trade.sell_order_id}.to_string(),
+        time: Utc.timestamp_opt(micros / 1_000_000, (micros % 1_000_000 * 1000) as u32).single().unwrap_or_default().to_rfc3339_opts(SecondsFormat::Micros, true),
+        product_id: product_id.to_string(),
+        size: trade.amount_str.clone(),
+        price: trade.price_str.clone(),
+        side: if trade._type == OrderType::Buy as u8 {"sell"} else {"buy"}.to_string(),
+    }
+}
+
+async fn serve_coinbase(socket: tokio_tungstenite::WebSocketStream<TcpStream>, market: Arc<Mutex<Market>>, mut events: broadcast::Receiver<Event>) -> Result<(), AppError> {
+    let (mut sink, mut source) = socket.split();
+    let mut subscribed = false;
+    let mut product_id = String::new();
+    // The book as the client holds it and the last trade it was sent, for l2update diffs and heartbeats.
+    let mut sent = OrderBook::default();
+    let mut last_trade_id = 0;
+    let mut batch = tokio::time::interval(COINBASE_BATCH_INTERVAL);
+    let mut heartbeat = tokio::time::interval(COINBASE_HEARTBEAT_INTERVAL);
+
+    loop {
+        let mut replies: Vec<FeedMessage> = Vec::new();
+        tokio::select! {
+            incoming = source.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(AppError::MessageError(e.to_string())),
+                };
+                let request: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                subscribed = match request["type"].as_str() {
+                    Some("subscribe") => true,
+                    Some("unsubscribe") => false,
+                    _ => continue,
+                };
+                product_id = request["product_ids"][0].as_str().unwrap_or_default().to_string();
+                let channels = if subscribed {request["channels"].clone()} else {json!([])};
+                sink.send(Message::Text(json!({"type": "subscriptions", "channels": channels}).to_string())).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+                if !subscribed {continue;}
+
+                let market = market.lock().unwrap();
+                let (bids, asks) = market.levels(usize::MAX);
+                sent = OrderBook::default();
+                for &(price, qty) in &bids {sent.set_level(OrderType::Buy as u8, price, qty);}
+                for &(price, qty) in &asks {sent.set_level(OrderType::Sell as u8, price, qty);}
+                let level = |&(price, qty): &(f64, f64)| [format!("{:.2}", price), format!("{:.8}", qty)];
+                replies.push(FeedMessage::Snapshot { product_id: product_id.clone(), bids: bids.iter().map(level).collect(), asks: asks.iter().map(level).collect() });
+                // Trades still queued for this connection happened before the subscription.
+                last_trade_id = market.next_trade_id - 1;
+                if let Some(trade) = &market.last_trade {replies.push(FeedMessage::LastMatch(coinbase_match(trade, &product_id, market.update_id)));}
+            }
+            _ = batch.tick() => {
+                if !subscribed {continue;}
+                let (bids, asks) = market.lock().unwrap().levels(usize::MAX);
+                let mut changes = Vec::new();
+                for (side, order_type, old, new) in [("buy", OrderType::Buy as u8, &sent.bids, &bids), ("sell", OrderType::Sell as u8, &sent.asks, &asks)] {
+                    changes.extend(level_changes(old, new).into_iter().map(|(price, qty)| (side, order_type, price, qty)));
+                }
+                if changes.is_empty() {continue;}
+                for &(_, order_type, price, qty) in &changes {sent.set_level(order_type, price, qty);}
+                let changes = changes.into_iter().map(|(side, _, price, qty)| [side.to_string(), format!("{:.2}", price), format!("{:.8}", qty)]).collect();
+                replies.push(FeedMessage::L2update { product_id: product_id.clone(), time: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true), changes });
+            }
+            _ = heartbeat.tick() => {
+                if !subscribed {continue;}
+                let sequence = market.lock().unwrap().update_id;
+                replies.push(FeedMessage::Heartbeat { sequence, last_trade_id, product_id: product_id.clone(), time: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true) });
+            }
+            event = events.recv() => {
+                match event {
+                    Ok(Event::Trade(trade)) if subscribed && trade.id > last_trade_id => {
+                        last_trade_id = trade.id;
+                        replies.push(FeedMessage::Match(coinbase_match(&trade, &product_id, market.lock().unwrap().update_id)));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    _ => continue,
+                }
+            }
+        }
+        for reply in replies {
+            sink.send(Message::Text(serde_json::to_string(&reply).expect("FeedMessage serializes"))).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+        }
+    }
+}
+
+/// Applies `changes` to `sent` and returns them split into bid and ask changes.
+fn apply_changes(sent: &mut OrderBook, bids: &[(f64, f64)], asks: &[(f64, f64)], depth: usize) -> (Levels, Levels) {
+    let changes = (level_changes(&sent.bids, bids), level_changes(&sent.asks, asks));
+    for &(price, qty) in &changes.0 {sent.set_level(OrderType::Buy as u8, price, qty);}
+    for &(price, qty) in &changes.1 {sent.set_level(OrderType::Sell as u8, price, qty);}
+    sent.truncate(depth);
+    changes
+}
+
+async fn serve_okx(socket: tokio_tungstenite::WebSocketStream<TcpStream>, market: Arc<Mutex<Market>>) -> Result<(), AppError> {
+    let (mut sink, mut source) = socket.split();
+    // The book as the client holds it while subscribed, and the seqId of the last push.
+    let mut sent: Option<OrderBook> = None;
+    let mut subscribed = false;
+    let mut arg = BooksArg { channel: String::new(), inst_id: String::new() };
+    let mut seq_id: i64 = 0;
+    let mut ticker = tokio::time::interval(OKX_UPDATE_INTERVAL);
+    let raw = |&(price, qty): &(f64, f64)| [format!("{:.1}", price), format!("{:.8}", qty / OKX_CONTRACT_SIZE), "0".to_string(), "1".to_string()];
+
+    loop {
+        let reply = tokio::select! {
+            incoming = source.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(AppError::MessageError(e.to_string())),
+                };
+                if text == "ping" {"pong".to_string()} else {
+                    let request: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                    subscribed = match request["op"].as_str() {
+                        Some("subscribe") => true,
+                        Some("unsubscribe") => false,
+                        _ => continue,
+                    };
+                    sent = None;
+                    arg = serde_json::from_value(request["args"][0].clone()).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                    json!({"event": request["op"], "arg": request["args"][0], "connId": "fake"}).to_string()
+                }
+            }
+            _ = ticker.tick() => {
+                if !subscribed {continue;}
+                let (bids, asks) = market.lock().unwrap().levels(OKX_BOOK_DEPTH);
+                let (action, prev_seq_id) = if sent.is_some() {("update", seq_id)} else {("snapshot", -1)};
+                let book = sent.get_or_insert_with(OrderBook::default);
+                let (bid_changes, ask_changes) = apply_changes(book, &bids, &asks, OKX_BOOK_DEPTH);
+                // Quiet intervals still push an empty update with an unchanged seqId.
+                if !bid_changes.is_empty() || !ask_changes.is_empty() || action == "snapshot" {seq_id += 1;}
+                let pairs = |side: &[LimitPrice]| side.iter().map(|l| {let [price, size, ..] = raw(&(l.price.into_inner(), l.size.into_inner())); (price, size)}).collect::<Vec<(String, String)>>();
+                let checksum = integrity::okx_checksum(&pairs(&book.bids), &pairs(&book.asks));
+                let data = BooksData { asks: ask_changes.iter().map(raw).collect(), bids: bid_changes.iter().map(raw).collect(), ts: Utc::now().timestamp_millis().to_string(), checksum, prev_seq_id, seq_id };
+                let message = BooksMessage { arg: arg.clone(), action: action.to_string(), data: vec![data] };
+                serde_json::to_string(&message).expect("BooksMessage serializes")
+            }
+        };
+        sink.send(Message::Text(reply)).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+    }
+}
+
+async fn serve_bybit(socket: tokio_tungstenite::WebSocketStream<TcpStream>, market: Arc<Mutex<Market>>) -> Result<(), AppError> {
+    let (mut sink, mut source) = socket.split();
+    let mut sent: Option<OrderBook> = None;
+    let mut subscribed = false;
+    let mut topic = String::new();
+    let mut update_id: u64 = 0;
+    let mut ticker = tokio::time::interval(BYBIT_UPDATE_INTERVAL);
+    let raw = |&(price, qty): &(f64, f64)| [format!("{:.2}", price), format!("{:.3}", qty)];
+
+    loop {
+        let reply = tokio::select! {
+            incoming = source.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(AppError::MessageError(e.to_string())),
+                };
+                let request: serde_json::Value = serde_json::from_str(&text).map_err(|_| AppError::ParsingFailed(text.clone()))?;
+                match request["op"].as_str() {
+                    Some("ping") => json!({"success": true, "ret_msg": "pong", "conn_id": "fake", "op": "ping"}).to_string(),
+                    Some(op @ ("subscribe" | "unsubscribe")) => {
+                        subscribed = op == "subscribe";
+                        topic = request["args"][0].as_str().unwrap_or_default().to_string();
+                        sent = None;
+                        json!({"success": true, "ret_msg": "", "conn_id": "fake", "op": op}).to_string()
+                    }
+                    _ => continue,
+                }
+            }
+            _ = ticker.tick() => {
+                if !subscribed {continue;}
+                let (bids, asks) = market.lock().unwrap().levels(BYBIT_BOOK_DEPTH);
+                let kind = if sent.is_some() {"delta"} else {"snapshot"};
+                let book = sent.get_or_insert_with(OrderBook::default);
+                let (bid_changes, ask_changes) = apply_changes(book, &bids, &asks, BYBIT_BOOK_DEPTH);
+                if kind == "delta" && bid_changes.is_empty() && ask_changes.is_empty() {continue;}
+                update_id += 1;
+                let data = OrderbookData { s: topic.rsplit('.').next().unwrap_or_default().to_string(), b: bid_changes.iter().map(raw).collect(), a: ask_changes.iter().map(raw).collect(), u: update_id, seq: update_id };
+                let message = OrderbookMessage { topic: topic.clone(), kind: kind.to_string(), ts: Utc::now().timestamp_millis() as u64, data };
+                serde_json::to_string(&message).expect("OrderbookMessage serializes")
+            }
+        };
+        sink.send(Message::Text(reply)).await.map_err(|e| AppError::MessageError(e.to_string()))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpStream as StdTcpStream, sync::atomic::{AtomicBool, AtomicU64}, thread, time::Instant};
+    use ordered_float::OrderedFloat;
+    use crate::{FeedConfig, VENUE_BOOKS, instruments::Registry, models::{BookKey, FeedStatus}};
+
+    const BUY: u8 = OrderType::Buy as u8;
+    const SELL: u8 = OrderType::Sell as u8;
+
+    fn market(orders: &[(u64, u8, f64, f64)]) -> Market {
+        let mut market = Market::new(MarketModel { seed: Some(7), ..MarketModel::default() });
+        for &(id, order_type, price, amount) in orders {market.orders.insert(id, make_order(id, order_type, price, amount));}
+        market.next_order_id = orders.len() as u64 + 1;
+        market
+    }
+
+    fn level(price: f64, size: f64) -> LimitPrice {LimitPrice { price: OrderedFloat(price), size: OrderedFloat(size), orders: Vec::new() }}
+
+    #[test]
+    fn a_taker_fills_the_best_price_first_and_the_older_order_at_a_price() {
+        let mut market = market(&[(1, SELL, 100.0, 1.0), (2, SELL, 100.0, 1.0), (3, SELL, 99.0, 0.2), (4, SELL, 102.0, 1.0)]);
+        let mut taker = make_order(5, BUY, 101.0, 1.5);
+        let mut events = Vec::new();
+        market.match_order(&mut taker, &mut events);
+
+        let fills: Vec<(f64, f64, u64, u64)> = events.iter().filter_map(|event| match event {Event::Trade(trade) => Some((trade.price, trade.amount, trade.buy_order_id, trade.sell_order_id)), _ => None}).collect();
+        assert_eq!(fills, vec![(99.0, 0.2, 5, 3), (100.0, 1.0, 5, 1), (100.0, 0.3, 5, 2)]);
+        let orders: Vec<(&str, u64, f64)> = events.iter().filter_map(|event| match event {Event::Order(name, order) => Some((*name, order.id, order.amount)), _ => None}).collect();
+        assert_eq!(orders, vec![("order_deleted", 3, 0.2), ("order_deleted", 1, 1.0), ("order_changed", 2, 0.7)]);
+        assert_eq!(taker.amount, 0.0);
+        assert_eq!(market.orders.keys().copied().collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(market.orders[&2].amount_str, "0.70000000");
+
+        assert_eq!(market.stats.fills, 3);
+        assert!((market.stats.notional - (99.0 * 0.2 + 100.0 * 1.3)).abs() < 1e-9);
+        assert!((market.stats.maker_fees - market.stats.notional * 0.003).abs() < 1e-9);
+        assert!((market.stats.taker_fees - market.stats.notional * 0.004).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_taker_that_does_not_reach_the_other_side_is_left_whole() {
+        let mut market = market(&[(1, BUY, 100.0, 1.0), (2, SELL, 102.0, 1.0)]);
+        let mut taker = make_order(3, SELL, 101.0, 0.5);
+        let mut events = Vec::new();
+        market.match_order(&mut taker, &mut events);
+        assert!(events.is_empty());
+        assert_eq!(taker.amount, 0.5);
+
+        let mut taker = make_order(4, SELL, 99.0, 1.5);
+        market.match_order(&mut taker, &mut events);
+        assert!(matches!(&events[..], [Event::Trade(trade), Event::Order("order_deleted", maker)] if trade.price == 100.0 && trade.amount == 1.0 && maker.id == 1));
+        assert_eq!(taker.amount, 0.5, "the rest rests");
+    }
+
+    #[test]
+    fn levels_sum_the_orders_at_each_tick_best_first() {
+        let market = market(&[(1, BUY, 99.0, 0.5), (2, BUY, 100.0, 1.0), (3, BUY, 100.0, 0.25), (4, BUY, 98.0, 2.0), (5, SELL, 101.0, 0.1), (6, SELL, 103.0, 0.2), (7, SELL, 101.0, 0.3)]);
+        let (bids, asks) = market.levels(2);
+        assert_eq!(bids, vec![(100.0, 1.25), (99.0, 0.5)]);
+        assert_eq!(asks.len(), 2);
+        assert_eq!(asks[0].0, 101.0);
+        assert!((asks[0].1 - 0.4).abs() < 1e-12);
+        assert_eq!(asks[1], (103.0, 0.2));
+
+        let (bids, _) = market.depth(10);
+        assert_eq!(bids, vec![["100.00000000".to_string(), "1.25000000".to_string()], ["99.00000000".to_string(), "0.50000000".to_string()], ["98.00000000".to_string(), "2.00000000".to_string()]]);
+    }
+
+    #[test]
+    fn level_changes_send_what_changed_and_zero_what_disappeared() {
+        let old = [level(100.0, 1.0), level(99.0, 2.0), level(98.0, 3.0)];
+        assert_eq!(level_changes(&old, &[(100.0, 1.0), (99.0, 2.5), (97.0, 4.0)]), vec![(98.0, 0.0), (99.0, 2.5), (97.0, 4.0)]);
+        assert!(level_changes(&old, &[(100.0, 1.0), (99.0, 2.0), (98.0, 3.0)]).is_empty());
+        assert_eq!(level_changes(&[], &[(100.0, 1.0)]), vec![(100.0, 1.0)]);
+
+        let mut sent = OrderBook::default();
+        apply_changes(&mut sent, &[(100.0, 1.0), (99.0, 2.0)], &[], 10);
+        let (bids, asks) = apply_changes(&mut sent, &[(99.0, 2.0)], &[(101.0, 1.0)], 10);
+        assert_eq!((bids, asks), (vec![(100.0, 0.0)], vec![(101.0, 1.0)]));
+        assert_eq!(sent.bids.iter().map(|level| level.price.into_inner()).collect::<Vec<_>>(), vec![99.0], "a zero quantity removes the level");
+    }
+
+    #[test]
+    fn the_same_seed_plays_out_the_same_market() {
+        let run = || {
+            let mut market = Market::new(MarketModel { seed: Some(42), ..MarketModel::default() });
+            for _ in 0..500 {
+                let dt = market.next_arrival();
+                market.step(dt);
+            }
+            (market.mid, market.next_trade_id, market.orders.values().map(|order| (order.id, order.price, order.amount)).collect::<Vec<_>>())
+        };
+        let (mid, trades, orders) = run();
+        assert!(trades > 1 && !orders.is_empty());
+        assert_eq!(run(), (mid, trades, orders));
+    }
+
+    #[test]
+    fn the_binance_and_bitstamp_connectors_follow_the_fake_exchange() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(serve(addr, MarketModel { seed: Some(3), arrival_rate: 1000.0, ..MarketModel::default() }));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while StdTcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline, "the fake exchange did not start");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let registry = Registry::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let connectors: Vec<(BookKey, thread::JoinHandle<Result<(), AppError>>)> = [("Binance", "BTC/USDT", crate::pull_binance as crate::Connector), ("Bitstamp", "BTC/USD", crate::pull_bitstamp)].into_iter().map(|(exchange, name, connector)| {
+            let instrument = registry.get(name).unwrap().clone();
+            // Its own key, so that other tests using the global books are not disturbed.
+            let key = BookKey::new(exchange, &format!("{} simulated", name));
+            let feed = FeedConfig { key: key.clone(), listing: instrument.venues[exchange].clone(), instrument, url: format!("ws://{}", addr), recorder: None, impairment: None, stop: stop.clone(), connections: AtomicU64::new(0) };
+            (key, thread::spawn(move || connector(&feed)))
+        }).collect();
+
+        let live = |key: &BookKey| VENUE_BOOKS.lock().unwrap().get(key).is_some_and(|book| book.status == FeedStatus::Live && book.best_bid().is_some() && book.best_ask().is_some());
+        while !connectors.iter().all(|(key, _)| live(key)) {
+            assert!(Instant::now() < deadline, "the connectors did not build live books");
+            thread::sleep(Duration::from_millis(50));
+        }
+        for (key, _) in &connectors {
+            let books = VENUE_BOOKS.lock().unwrap();
+            let book = &books[key];
+            assert!(book.best_bid().unwrap().price < book.best_ask().unwrap().price, "{} is crossed", key);
+        }
+
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        for (key, connector) in connectors {
+            assert!(connector.join().unwrap().is_ok(), "{} did not stop cleanly", key);
+            VENUE_BOOKS.lock().unwrap().remove(&key);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+use chrono::{DateTime, Utc};
+use ordered_float::OrderedFloat;
+use serde::{Serialize, Deserialize};
+use crate::{AppError, models::{BookKey, FeedStatus, InstrumentKind, LimitPrice, Order, OrderBook}};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// The latest snapshot in the snapshot directory, replaced as a whole each time.
+const SNAPSHOT_FILE: &str = "books.json";
+
+/// A price level and, on venues that send them, its individual orders.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LevelSnapshot {
+    pub price: f64,
+    pub size: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub orders: Vec<Order>,
+}
+
+impl From<&LimitPrice> for LevelSnapshot {
+    fn from(level: &LimitPrice) -> LevelSnapshot {LevelSnapshot { price: level.price.into_inner(), size: level.size.into_inner(), orders: level.orders.clone() }}
+}
+
+impl From<LevelSnapshot> for LimitPrice {
+    fn from(level: LevelSnapshot) -> LimitPrice {LimitPrice { price: OrderedFloat(level.price), size: OrderedFloat(level.size), orders: level.orders }}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookSnapshot {
+    pub exchange: String,
+    pub instrument: String,
+    pub kind: InstrumentKind,
+    pub last_update_micros: i64,
+    /// Highest price first.
+    pub bids: Vec<LevelSnapshot>,
+    /// Lowest price first.
+    pub asks: Vec<LevelSnapshot>,
+}
+
+/// Every venue book that had levels at `taken_at_micros`, whether live or not.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub taken_at_micros: i64,
+    pub books: Vec<BookSnapshot>,
+}
+
+impl Snapshot {
+    pub fn take(books: &HashMap<BookKey, OrderBook>, taken_at_micros: i64) -> Snapshot {
+        let mut books: Vec<BookSnapshot> = books.iter().filter(|(_, book)| !book.bids.is_empty() || !book.asks.is_empty()).map(|(key, book)| BookSnapshot {
+            exchange: key.exchange.clone(),
+            instrument: key.instrument.clone(),
+            kind: book.kind,
+            last_update_micros: book.last_update_micros,
+            bids: book.bids.iter().map(LevelSnapshot::from).collect(),
+            asks: book.asks.iter().map(LevelSnapshot::from).collect(),
+        }).collect();
+        books.sort_by(|a, b| (&a.exchange, &a.instrument).cmp(&(&b.exchange, &b.instrument)));
+        Snapshot { taken_at_micros, books }
+    }
+
+    /// Writes the snapshot next to the previous one and renames it over it, so a crash never leaves a partial file.
+    pub fn save(&self, dir: &Path) -> Result<(), AppError> {
+        let (path, partial) = (dir.join(SNAPSHOT_FILE), dir.join(format!("{}.partial", SNAPSHOT_FILE)));
+        let error = |e: std::io::Error| AppError::RecordingFailed(format!("{}: {}", path.display(), e));
+        fs::create_dir_all(dir).map_err(error)?;
+        fs::write(&partial, serde_json::to_vec(self).expect("Snapshot serializes")).map_err(error)?;
+        fs::rename(&partial, &path).map_err(error)
+    }
+
+    /// The latest snapshot in `dir`, or None if none was taken yet.
+    pub fn load(dir: &Path) -> Result<Option<Snapshot>, AppError> {
+        let path = dir.join(SNAPSHOT_FILE);
+        if !path.exists() {return Ok(None);}
+        let text = fs::read_to_string(&path).map_err(|e| AppError::RecordingFailed(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&text).map(Some).map_err(|e| AppError::ParsingFailed(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Puts the books accepted by `wanted` into `books`, marked stale until their feeds resynchronise. Returns how many.
+    pub fn restore(self, books: &mut HashMap<BookKey, OrderBook>, wanted: impl Fn(&BookKey) -> bool) -> usize {
+        let taken_at = DateTime::from_timestamp_micros(self.taken_at_micros).unwrap_or_default();
+        let mut restored = 0;
+        for snapshot in self.books {
+            let key = BookKey::new(&snapshot.exchange, &snapshot.instrument);
+            if !wanted(&key) {continue;}
+            let mut book = OrderBook {
+                bids: snapshot.bids.into_iter().map(LimitPrice::from).collect(),
+                asks: snapshot.asks.into_iter().map(LimitPrice::from).collect(),
+                kind: snapshot.kind,
+                last_update_micros: snapshot.last_update_micros,
+                status: FeedStatus::Stale(format!("restored from snapshot taken at {}", taken_at.format("%Y-%m-%d %H:%M:%S UTC"))),
+                ..OrderBook::default()
+            };
+            book.crossed = book.is_crossed();
+            books.insert(key, book);
+            restored += 1;
+        }
+        restored
+    }
+}
+
+/// Snapshots `books` into `dir` every `interval`.
+pub async fn run(books: Arc<Mutex<HashMap<BookKey, OrderBook>>>, dir: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let snapshot = Snapshot::take(&books.lock().unwrap(), Utc::now().timestamp_micros());
+        if let Err(e) = snapshot.save(&dir) {eprintln!("Failed to save book snapshot: {}", e);}
+    }
+}
@@ -0,0 +1,203 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+use chrono::{DateTime, NaiveDate, Utc};
+use crate::{AppError, models::{BookKey, OrderBook}, orderbook::{SpreadSample, TopOfBook}};
+
+/// Samples kept in memory per instrument: an hour at the default interval.
+pub const HISTORY_LEN: usize = 3600;
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Top of book of every live venue and the consolidated spread of each instrument at `timestamp_micros`.
+/// Instruments without a live bid and ask are skipped.
+pub fn sample(books: &HashMap<BookKey, OrderBook>, timestamp_micros: i64) -> Vec<SpreadSample> {
+    let mut by_instrument: BTreeMap<&str, Vec<TopOfBook>> = BTreeMap::new();
+    for (key, book) in books.iter().filter(|(_, book)| book.is_live()) {
+        let (Some(bid), Some(ask)) = (book.best_bid(), book.best_ask()) else {continue};
+        by_instrument.entry(&key.instrument).or_default().push(TopOfBook {
+            exchange: key.exchange.clone(),
+            bid: bid.price.into_inner(),
+            bid_size: bid.size.into_inner(),
+            ask: ask.price.into_inner(),
+            ask_size: ask.size.into_inner(),
+        });
+    }
+
+    by_instrument.into_iter().filter_map(|(instrument, mut venues)| {
+        venues.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+        let best_bid = venues.iter().max_by(|a, b| a.bid.total_cmp(&b.bid))?;
+        let best_ask = venues.iter().min_by(|a, b| a.ask.total_cmp(&b.ask))?;
+        let spread = best_ask.ask - best_bid.bid;
+        Some(SpreadSample {
+            timestamp_micros,
+            instrument: instrument.to_string(),
+            best_bid: best_bid.bid,
+            best_bid_exchange: best_bid.exchange.clone(),
+            best_ask: best_ask.ask,
+            best_ask_exchange: best_ask.exchange.clone(),
+            spread,
+            spread_min: spread,
+            spread_max: spread,
+            venues,
+        })
+    }).collect()
+}
+
+/// Keeps one sample per `step_micros`, the last of each step, widened to the step's spread range.
+pub fn downsample(samples: Vec<SpreadSample>, step_micros: i64) -> Vec<SpreadSample> {
+    if step_micros <= 0 {return samples;}
+    let mut kept: Vec<SpreadSample> = Vec::new();
+    for sample in samples {
+        let step = sample.timestamp_micros.div_euclid(step_micros);
+        match kept.last_mut() {
+            Some(last) if last.timestamp_micros.div_euclid(step_micros) == step => {
+                let (spread_min, spread_max) = (last.spread_min.min(sample.spread_min), last.spread_max.max(sample.spread_max));
+                *last = SpreadSample { spread_min, spread_max, ..sample };
+            }
+            _ => kept.push(sample),
+        }
+    }
+    kept
+}
+
+/// A ring buffer of spread samples per instrument, optionally backed by a file per UTC day that holds the full history.
+pub struct SpreadHistory {
+    samples: HashMap<String, VecDeque<SpreadSample>>,
+    dir: Option<PathBuf>,
+    /// The day file samples are being appended to.
+    file: Option<(NaiveDate, File)>,
+}
+
+/// Samples to read from the day files, see [`SpreadHistory::query`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Archived {
+    paths: Vec<PathBuf>,
+    instrument: String,
+    from_micros: i64,
+    to_micros: i64,
+}
+
+impl Archived {
+    /// Reads the samples in range, oldest first. Blocks on file I/O.
+    pub fn read(&self) -> Result<Vec<SpreadSample>, AppError> {
+        let mut samples = Vec::new();
+        for path in &self.paths {
+            let error = |e: std::io::Error| AppError::RecordingFailed(format!("{}: {}", path.display(), e));
+            for line in BufReader::new(File::open(path).map_err(error)?).lines() {
+                let line = line.map_err(error)?;
+                let sample: SpreadSample = serde_json::from_str(&line).map_err(|_| AppError::ParsingFailed(format!("{}: {}", path.display(), line)))?;
+                if sample.instrument == self.instrument && sample.timestamp_micros >= self.from_micros && sample.timestamp_micros < self.to_micros {samples.push(sample);}
+            }
+        }
+        Ok(samples)
+    }
+}
+
+fn day_of(timestamp_micros: i64) -> NaiveDate {DateTime::from_timestamp_micros(timestamp_micros).unwrap_or_default().date_naive()}
+
+fn day_file(dir: &Path, day: NaiveDate) -> PathBuf {dir.join(format!("spreads-{}.jsonl", day.format("%Y-%m-%d")))}
+
+impl SpreadHistory {
+    pub fn new(dir: Option<&Path>) -> Result<SpreadHistory, AppError> {
+        if let Some(dir) = dir {fs::create_dir_all(dir).map_err(|e| AppError::RecordingFailed(format!("{}: {}", dir.display(), e)))?;}
+        Ok(SpreadHistory { samples: HashMap::new(), dir: dir.map(Path::to_path_buf), file: None })
+    }
+
+    /// Appends `sample` to the file of its day, opening the next day's file when the day changes.
+    fn persist(&mut self, sample: &SpreadSample) -> Result<(), std::io::Error> {
+        let Some(dir) = &self.dir else {return Ok(())};
+        let day = day_of(sample.timestamp_micros);
+        if self.file.as_ref().is_none_or(|(current, _)| *current != day) {
+            self.file = Some((day, OpenOptions::new().create(true).append(true).open(day_file(dir, day))?));
+        }
+        let (_, file) = self.file.as_mut().expect("just opened");
+        writeln!(file, "{}", serde_json::to_string(sample).expect("SpreadSample serializes"))
+    }
+
+    pub fn record(&mut self, sample: SpreadSample) {
+        if let Err(e) = self.persist(&sample) {eprintln!("Failed to persist spread sample: {}", e);}
+        let samples = self.samples.entry(sample.instrument.clone()).or_default();
+        samples.push_back(sample);
+        if samples.len() > HISTORY_LEN {samples.pop_front();}
+    }
+
+    /// The samples of `instrument` still in the ring buffer, oldest first.
+    pub fn buffered(&self, instrument: &str) -> impl Iterator<Item = &SpreadSample> {self.samples.get(instrument).into_iter().flatten()}
+
+    /// Samples of `instrument` from `from_micros` up to, not including, `to_micros`, oldest first: those in the ring
+    /// buffer, and the older part of the range to read from the day files it spans, when they are kept. A
+    /// `from_micros` of 0 means the start of the ring buffer.
+    pub fn query(&self, instrument: &str, from_micros: i64, to_micros: i64) -> (Vec<SpreadSample>, Option<Archived>) {
+        let oldest = self.buffered(instrument).next().map_or(to_micros, |sample| sample.timestamp_micros);
+        let from_micros = if from_micros == 0 {oldest} else {from_micros};
+        let buffered = self.buffered(instrument).filter(|sample| sample.timestamp_micros >= from_micros && sample.timestamp_micros < to_micros).cloned().collect();
+        let end = to_micros.min(oldest);
+        let archived = self.dir.as_deref().filter(|_| from_micros < end).map(|dir| Archived {
+            paths: day_of(from_micros).iter_days().take_while(|day| *day <= day_of(end - 1)).map(|day| day_file(dir, day)).filter(|path| path.exists()).collect(),
+            instrument: instrument.to_string(),
+            from_micros,
+            to_micros: end,
+        });
+        (buffered, archived)
+    }
+}
+
+/// Samples `books` into `history` every `interval`.
+pub async fn run(history: Arc<Mutex<SpreadHistory>>, books: Arc<Mutex<HashMap<BookKey, OrderBook>>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let samples = sample(&books.lock().unwrap(), Utc::now().timestamp_micros());
+        let mut history = history.lock().unwrap();
+        for sample in samples {history.record(sample);}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86_400_000_000;
+
+    fn spread(instrument: &str, timestamp_micros: i64) -> SpreadSample {
+        SpreadSample { timestamp_micros, instrument: instrument.to_string(), ..Default::default() }
+    }
+
+    fn times(samples: &[SpreadSample]) -> Vec<i64> {samples.iter().map(|sample| sample.timestamp_micros).collect()}
+
+    #[test]
+    fn queries_read_only_the_days_past_the_ring_buffer() {
+        let dir = std::env::temp_dir().join(format!("spreads-test-{}", std::process::id()));
+        let mut history = SpreadHistory::new(Some(&dir)).unwrap();
+        let start = 20_000 * DAY;
+        // Three days at one sample an hour, so the ring buffer holds them all; then push the first day out of it.
+        for hour in 0..72 {history.record(spread("BTC/USD", start + hour * DAY / 24));}
+        history.record(spread("BTC/USDT", start));
+        for i in 0..HISTORY_LEN as i64 - 49 {history.record(spread("BTC/USD", start + 3 * DAY + i));}
+
+        let oldest = history.buffered("BTC/USD").next().unwrap().timestamp_micros;
+        assert_eq!(oldest, start + DAY - DAY / 24);
+        let (buffered, archived) = history.query("BTC/USD", start + DAY / 2, start + 2 * DAY);
+        let archived = archived.unwrap();
+        assert_eq!(archived.paths, vec![day_file(&dir, day_of(start))]);
+        let archived = archived.read().unwrap();
+        assert_eq!((times(&archived).first(), times(&archived).last()), (Some(&(start + DAY / 2)), Some(&(oldest - DAY / 24))));
+        assert_eq!(times(&buffered), (23..48).map(|hour| start + hour * DAY / 24).collect::<Vec<_>>());
+
+        // Ranges inside the ring buffer, and the default start, read no file.
+        assert!(history.query("BTC/USD", oldest, start + 3 * DAY).1.is_none());
+        assert_eq!(history.query("BTC/USD", 0, start + 2 * DAY).0.len(), 25);
+        assert!(history.query("BTC/USD", 0, start + 2 * DAY).1.is_none());
+        // Other instruments are filtered out of the files.
+        let (_, archived) = history.query("BTC/USD", start, start + 1);
+        assert_eq!(times(&archived.unwrap().read().unwrap()), vec![start]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_a_directory_only_the_ring_buffer_answers() {
+        let mut history = SpreadHistory::new(None).unwrap();
+        history.record(spread("BTC/USD", 10));
+        history.record(spread("BTC/USD", 20));
+        assert_eq!(history.query("BTC/USD", 1, 30), (vec![spread("BTC/USD", 10), spread("BTC/USD", 20)], None));
+        assert_eq!(history.query("BTC/USD", 15, 20), (Vec::new(), None));
+    }
+}
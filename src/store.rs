@@ -0,0 +1,216 @@
+use std::{collections::HashMap, path::Path, sync::{Arc, Mutex}, time::Duration};
+use chrono::Utc;
+use rusqlite::{params, Connection, Row, ToSql};
+use tokio::sync::broadcast;
+use crate::{AppError, spreads, models::{BookKey, OrderBook}, orderbook::{Candle, TopOfBook, TopOfBookSample, TradeEvent}};
+
+/// Rows returned by a query that asks for no particular number.
+pub const DEFAULT_LIMIT: usize = 10_000;
+/// How often buffered rows are committed.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS trades (
+        exchange TEXT NOT NULL, instrument TEXT NOT NULL, timestamp_micros INTEGER NOT NULL,
+        trade_id INTEGER NOT NULL, side TEXT NOT NULL, price REAL NOT NULL, amount REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS trades_by_venue ON trades (instrument, exchange, timestamp_micros);
+    CREATE INDEX IF NOT EXISTS trades_by_time ON trades (instrument, timestamp_micros);
+    CREATE TABLE IF NOT EXISTS candles (
+        exchange TEXT NOT NULL, instrument TEXT NOT NULL, interval_secs INTEGER NOT NULL, open_time_micros INTEGER NOT NULL,
+        open REAL NOT NULL, high REAL NOT NULL, low REAL NOT NULL, close REAL NOT NULL, volume REAL NOT NULL, trade_count INTEGER NOT NULL,
+        PRIMARY KEY (instrument, exchange, interval_secs, open_time_micros)
+    );
+    CREATE TABLE IF NOT EXISTS top_of_book (
+        exchange TEXT NOT NULL, instrument TEXT NOT NULL, timestamp_micros INTEGER NOT NULL,
+        bid REAL NOT NULL, bid_size REAL NOT NULL, ask REAL NOT NULL, ask_size REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS top_of_book_by_venue ON top_of_book (instrument, exchange, timestamp_micros);
+    CREATE INDEX IF NOT EXISTS top_of_book_by_time ON top_of_book (instrument, timestamp_micros);
+";
+/// Trades are unique by venue trade ID, so a trade delivered twice, e.g. around a reconnection, is stored once.
+/// Databases created before the index have their duplicates removed first.
+const UNIQUE_TRADES: &str = "
+    DELETE FROM trades WHERE rowid NOT IN (SELECT MIN(rowid) FROM trades GROUP BY exchange, instrument, trade_id);
+    CREATE UNIQUE INDEX trades_by_id ON trades (exchange, instrument, trade_id);
+";
+
+/// Rows of one instrument in `[from_micros, to_micros)`, oldest first. Without `exchange`, rows of every venue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub instrument: String,
+    pub exchange: Option<String>,
+    pub from_micros: i64,
+    pub to_micros: i64,
+    pub limit: usize,
+}
+
+/// Trades, closed candles and top of book samples in an SQLite database.
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> Result<Store, AppError> {
+        let error = |e: rusqlite::Error| AppError::StorageFailed(format!("{}: {}", path.display(), e));
+        let connection = Connection::open(path).map_err(error)?;
+        // Readers do not block the writer in write-ahead mode.
+        connection.pragma_update(None, "journal_mode", "WAL").map_err(error)?;
+        connection.execute_batch(SCHEMA).map_err(error)?;
+        let unique: bool = connection.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'trades_by_id')", [], |row| row.get(0)).map_err(error)?;
+        if !unique {connection.execute_batch(UNIQUE_TRADES).map_err(error)?;}
+        Ok(Store { connection })
+    }
+
+    /// Inserts everything in one transaction. Trades already stored are skipped, and candles replace an earlier row
+    /// of the same series and open time.
+    pub fn insert(&mut self, trades: &[TradeEvent], candles: &[Candle], tops: &[TopOfBookSample]) -> Result<(), AppError> {
+        let error = |e: rusqlite::Error| AppError::StorageFailed(e.to_string());
+        let transaction = self.connection.transaction().map_err(error)?;
+        {
+            let mut insert = transaction.prepare_cached("INSERT OR IGNORE INTO trades VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)").map_err(error)?;
+            for t in trades {insert.execute(params![t.exchange, t.instrument, t.timestamp_micros, t.trade_id as i64, t.side, t.price, t.amount]).map_err(error)?;}
+            let mut insert = transaction.prepare_cached("INSERT OR REPLACE INTO candles VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)").map_err(error)?;
+            for c in candles {insert.execute(params![c.exchange, c.instrument, c.interval_secs as i64, c.open_time_micros, c.open, c.high, c.low, c.close, c.volume, c.trade_count as i64]).map_err(error)?;}
+            let mut insert = transaction.prepare_cached("INSERT INTO top_of_book VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)").map_err(error)?;
+            for sample in tops {
+                let top = sample.top.clone().unwrap_or_default();
+                insert.execute(params![top.exchange, sample.instrument, sample.timestamp_micros, top.bid, top.bid_size, top.ask, top.ask_size]).map_err(error)?;
+            }
+        }
+        transaction.commit().map_err(error)
+    }
+
+    fn select<T>(&self, sql: &str, parameters: &[&dyn ToSql], row: impl Fn(&Row) -> rusqlite::Result<T>) -> Result<Vec<T>, AppError> {
+        let error = |e: rusqlite::Error| AppError::StorageFailed(e.to_string());
+        let mut statement = self.connection.prepare_cached(sql).map_err(error)?;
+        let rows = statement.query_map(parameters, row).map_err(error)?;
+        rows.collect::<rusqlite::Result<Vec<T>>>().map_err(error)
+    }
+
+    pub fn trades(&self, query: &Query) -> Result<Vec<TradeEvent>, AppError> {
+        let sql = "SELECT exchange, instrument, timestamp_micros, trade_id, side, price, amount FROM trades
+            WHERE instrument = ?1 AND (?2 IS NULL OR exchange = ?2) AND timestamp_micros >= ?3 AND timestamp_micros < ?4
+            ORDER BY timestamp_micros LIMIT ?5";
+        self.select(sql, params![query.instrument, query.exchange, query.from_micros, query.to_micros, query.limit as i64], |row| Ok(TradeEvent {
+            exchange: row.get(0)?,
+            instrument: row.get(1)?,
+            timestamp_micros: row.get(2)?,
+            trade_id: row.get::<_, i64>(3)? as u64,
+            side: row.get(4)?,
+            price: row.get(5)?,
+            amount: row.get(6)?,
+        }))
+    }
+
+    /// Closed candles of `interval_secs` by open time. `query.exchange` of `Some("")` selects the candles across venues.
+    pub fn candles(&self, query: &Query, interval_secs: u64) -> Result<Vec<Candle>, AppError> {
+        let sql = "SELECT exchange, instrument, interval_secs, open_time_micros, open, high, low, close, volume, trade_count FROM candles
+            WHERE instrument = ?1 AND (?2 IS NULL OR exchange = ?2) AND open_time_micros >= ?3 AND open_time_micros < ?4 AND interval_secs = ?6
+            ORDER BY open_time_micros, exchange LIMIT ?5";
+        self.select(sql, params![query.instrument, query.exchange, query.from_micros, query.to_micros, query.limit as i64, interval_secs as i64], |row| Ok(Candle {
+            exchange: row.get(0)?,
+            instrument: row.get(1)?,
+            interval_secs: row.get::<_, i64>(2)? as u64,
+            open_time_micros: row.get(3)?,
+            open: row.get(4)?,
+            high: row.get(5)?,
+            low: row.get(6)?,
+            close: row.get(7)?,
+            volume: row.get(8)?,
+            trade_count: row.get::<_, i64>(9)? as u64,
+            closed: true,
+        }))
+    }
+
+    pub fn top_of_book(&self, query: &Query) -> Result<Vec<TopOfBookSample>, AppError> {
+        let sql = "SELECT exchange, instrument, timestamp_micros, bid, bid_size, ask, ask_size FROM top_of_book
+            WHERE instrument = ?1 AND (?2 IS NULL OR exchange = ?2) AND timestamp_micros >= ?3 AND timestamp_micros < ?4
+            ORDER BY timestamp_micros, exchange LIMIT ?5";
+        self.select(sql, params![query.instrument, query.exchange, query.from_micros, query.to_micros, query.limit as i64], |row| Ok(TopOfBookSample {
+            instrument: row.get(1)?,
+            timestamp_micros: row.get(2)?,
+            top: Some(TopOfBook { exchange: row.get(0)?, bid: row.get(3)?, bid_size: row.get(4)?, ask: row.get(5)?, ask_size: row.get(6)? }),
+        }))
+    }
+}
+
+/// Runs `f` on the store on a blocking thread, off the async runtime, since SQLite calls block.
+pub async fn blocking<T: Send + 'static>(store: Arc<Mutex<Store>>, f: impl FnOnce(&mut Store) -> Result<T, AppError> + Send + 'static) -> Result<T, AppError> {
+    tokio::task::spawn_blocking(move || f(&mut store.lock().unwrap())).await.map_err(|e| AppError::StorageFailed(e.to_string()))?
+}
+
+/// Stores every trade, every candle once closed, and the top of book of every live venue each `interval`.
+/// Rows are buffered and committed once a second.
+pub async fn run(store: Arc<Mutex<Store>>, books: Arc<Mutex<HashMap<BookKey, OrderBook>>>, mut trades: broadcast::Receiver<TradeEvent>, mut candles: broadcast::Receiver<Candle>, interval: Duration) {
+    let (mut sampler, mut committer) = (tokio::time::interval(interval), tokio::time::interval(COMMIT_INTERVAL));
+    let (mut new_trades, mut new_candles, mut new_tops) = (Vec::new(), Vec::new(), Vec::new());
+    loop {
+        tokio::select! {
+            trade = trades.recv() => match trade {
+                Ok(trade) => new_trades.push(trade),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => eprintln!("Store skipped {} trades", skipped),
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            candle = candles.recv() => match candle {
+                Ok(candle) if candle.closed => new_candles.push(candle),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => eprintln!("Store skipped {} candle updates", skipped),
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = sampler.tick() => {
+                let samples = spreads::sample(&books.lock().unwrap(), Utc::now().timestamp_micros());
+                for sample in samples {
+                    new_tops.extend(sample.venues.into_iter().map(|top| TopOfBookSample { timestamp_micros: sample.timestamp_micros, instrument: sample.instrument.clone(), top: Some(top) }));
+                }
+            }
+            _ = committer.tick() => {
+                let rows = (std::mem::take(&mut new_trades), std::mem::take(&mut new_candles), std::mem::take(&mut new_tops));
+                if let Err(e) = blocking(store.clone(), move |store| store.insert(&rows.0, &rows.1, &rows.2)).await {eprintln!("Failed to store history: {}", e);}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(trade_id: u64, timestamp_micros: i64) -> TradeEvent {
+        TradeEvent { exchange: "Coinbase".to_string(), price: 64_000.0, amount: 0.1, side: "buy".to_string(), trade_id, timestamp_micros, instrument: "BTC/USD".to_string() }
+    }
+
+    fn query() -> Query {Query { instrument: "BTC/USD".to_string(), exchange: None, from_micros: 0, to_micros: i64::MAX, limit: DEFAULT_LIMIT }}
+
+    #[test]
+    fn trades_are_stored_once() {
+        let path = std::env::temp_dir().join(format!("store-test-{}.db", std::process::id()));
+        let mut store = Store::open(&path).unwrap();
+        store.insert(&[trade(1, 10), trade(2, 20)], &[], &[]).unwrap();
+        store.insert(&[trade(2, 20), trade(3, 30)], &[], &[]).unwrap();
+        let ids: Vec<u64> = store.trades(&query()).unwrap().iter().map(|t| t.trade_id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        // The same ID on another venue is another trade.
+        store.insert(&[TradeEvent { exchange: "Bitstamp".to_string(), ..trade(1, 15) }], &[], &[]).unwrap();
+        assert_eq!(store.trades(&query()).unwrap().len(), 4);
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn older_databases_are_deduplicated() {
+        let path = std::env::temp_dir().join(format!("store-test-old-{}.db", std::process::id()));
+        {
+            let connection = Connection::open(&path).unwrap();
+            connection.execute_batch(SCHEMA).unwrap();
+            for _ in 0..2 {connection.execute("INSERT INTO trades VALUES ('Coinbase', 'BTC/USD', 10, 1, 'buy', 64000.0, 0.1)", []).unwrap();}
+        }
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(store.trades(&query()).unwrap().len(), 1);
+        store.insert(&[trade(1, 10)], &[], &[]).unwrap();
+        assert_eq!(store.trades(&query()).unwrap().len(), 1);
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{collections::{HashMap, VecDeque}, io, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, time::Duration};
+#[cfg(unix)]
+use std::{fs::File, io::{BufRead, BufReader, Write}, os::fd::{AsFd, OwnedFd}, thread};
+use chrono::{DateTime, Utc};
+#[cfg(unix)]
+use nix::unistd::{dup2_stderr, dup2_stdout};
+use ratatui::{
+    Frame, Terminal,
+    backend::CrosstermBackend,
+    crossterm::{event::{self, Event, KeyCode, KeyEventKind, KeyModifiers}, execute, terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen}},
+    layout::{Alignment, Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    symbols::Marker,
+    text::{Line, Span},
+    widgets::{Axis, Block, Cell, Chart, Dataset, GraphType, List, ListItem, Paragraph, Row, Table},
+};
+use tokio::sync::broadcast;
+use crate::{AppError, aggregator::{self, Grouping}, instruments::{self, Instrument}, models::{BookKey, FeedStatus, OrderBook}, orderbook::{Level, SpreadSample, TradeEvent}, reload::Settings, spreads::SpreadHistory};
+
+/// How often the screen is redrawn when no key is pressed.
+static REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+/// Span of the spread chart.
+static SPREAD_WINDOW: Duration = Duration::from_secs(300);
+const RECENT_TRADES: usize = 100;
+const LOG_LINES: usize = 200;
+const MAX_DEPTH: usize = 100;
+
+/// Standard output and error sent into a pipe while the TUI owns the terminal, so that messages of the connectors and
+/// tasks show in its log panel instead of being written over it. Dropping it restores them.
+#[cfg(unix)]
+struct Capture {
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+}
+
+#[cfg(unix)]
+impl Capture {
+    fn start(log: Arc<Mutex<VecDeque<String>>>) -> io::Result<Capture> {
+        let capture = Capture { stdout: io::stdout().as_fd().try_clone_to_owned()?, stderr: io::stderr().as_fd().try_clone_to_owned()? };
+        let (reader, writer) = io::pipe()?;
+        io::stdout().flush()?;
+        dup2_stdout(&writer)?;
+        dup2_stderr(&writer)?;
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                let mut log = log.lock().unwrap();
+                log.push_back(line);
+                if log.len() > LOG_LINES {log.pop_front();}
+            }
+        });
+        Ok(capture)
+    }
+
+    /// The terminal, where the TUI draws.
+    fn terminal(&self) -> io::Result<File> {Ok(File::from(self.stdout.try_clone()?))}
+}
+
+#[cfg(unix)]
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        let _ = dup2_stdout(&self.stdout);
+        let _ = dup2_stderr(&self.stderr);
+    }
+}
+
+/// Without a way to redirect standard output and error, messages are written over the TUI until the next redraw and
+/// the log panel stays empty.
+#[cfg(not(unix))]
+struct Capture;
+
+#[cfg(not(unix))]
+impl Capture {
+    fn start(_log: Arc<Mutex<VecDeque<String>>>) -> io::Result<Capture> {Ok(Capture)}
+
+    fn terminal(&self) -> io::Result<io::Stdout> {Ok(io::stdout())}
+}
+
+/// What the keyboard has selected, and what the screen shows besides the books.
+struct App {
+    /// Name of the instrument shown, kept across reloads that reorder the registry.
+    instrument: Option<String>,
+    view: Arc<Mutex<aggregator::View>>,
+    trades: VecDeque<TradeEvent>,
+    log: Arc<Mutex<VecDeque<String>>>,
+}
+
+/// Shows the consolidated ladder of one instrument with its venue books, recent trades, spread and log in a
+/// full-screen terminal UI until `q`, `Esc` or `Ctrl-C` is pressed, or `quit` is set. `←`/`→` switch the instrument,
+/// `↑`/`↓` change the depth of `view` and `g` its grouping, which a configuration reload may change too.
+pub fn run(settings: Arc<Mutex<Settings>>, view: Arc<Mutex<aggregator::View>>, spreads: Arc<Mutex<SpreadHistory>>, books: Arc<Mutex<HashMap<BookKey, OrderBook>>>, mut trades: broadcast::Receiver<TradeEvent>, quit: Arc<AtomicBool>) -> Result<(), AppError> {
+    let error = |e: io::Error| AppError::TerminalFailed(e.to_string());
+    let log = Arc::new(Mutex::new(VecDeque::new()));
+    let capture = Capture::start(log.clone()).map_err(error)?;
+    let mut out = capture.terminal().map_err(error)?;
+    enable_raw_mode().map_err(error)?;
+    let result = execute!(out, EnterAlternateScreen).and_then(|_| Terminal::new(CrosstermBackend::new(out))).map_err(error).and_then(|mut terminal| {
+        let mut app = App { instrument: None, view, trades: VecDeque::new(), log: log.clone() };
+        let result = (|| loop {
+            if quit.load(Ordering::Relaxed) {return Ok(());}
+            loop {
+                match trades.try_recv() {
+                    Ok(trade) => {
+                        app.trades.push_front(trade);
+                        app.trades.truncate(RECENT_TRADES);
+                    }
+                    Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+            let instruments = settings.lock().unwrap().instruments.instruments.clone();
+            // Copied out first, so that the connectors and the sampler do not wait on the terminal.
+            let (shown, samples): (HashMap<BookKey, OrderBook>, Vec<SpreadSample>) = match app.selected(&instruments) {
+                Some(instrument) => (
+                    books.lock().unwrap().iter().filter(|(key, _)| key.instrument == instrument.name).map(|(key, book)| (key.clone(), book.clone())).collect(),
+                    spreads.lock().unwrap().buffered(&instrument.name).cloned().collect(),
+                ),
+                None => Default::default(),
+            };
+            terminal.draw(|frame| app.draw(frame, &instruments, &shown, &samples)).map_err(error)?;
+            if !event::poll(REFRESH_INTERVAL).map_err(error)? {continue;}
+            let Event::Key(key) = event::read().map_err(error)? else {continue};
+            if key.kind != KeyEventKind::Press {continue;}
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Left | KeyCode::Char('h') | KeyCode::BackTab => app.step_instrument(&instruments, -1),
+                KeyCode::Right | KeyCode::Char('l') | KeyCode::Tab => app.step_instrument(&instruments, 1),
+                KeyCode::Up | KeyCode::Char('k') | KeyCode::Char('+') => app.step_depth(1),
+                KeyCode::Down | KeyCode::Char('j') | KeyCode::Char('-') => app.step_depth(-1),
+                KeyCode::Char('g') => app.step_grouping(&instruments, 1),
+                KeyCode::Char('G') => app.step_grouping(&instruments, -1),
+                _ => {}
+            }
+        })();
+        let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = terminal.show_cursor();
+        result
+    });
+    let _ = disable_raw_mode();
+    drop(capture);
+    // What was logged while the screen was taken is otherwise lost.
+    for line in log.lock().unwrap().iter() {eprintln!("{}", line);}
+    result
+}
+
+impl App {
+    fn selected<'a>(&self, instruments: &'a [Instrument]) -> Option<&'a Instrument> {
+        instruments.iter().find(|instrument| Some(&instrument.name) == self.instrument.as_ref()).or(instruments.first())
+    }
+
+    fn step_instrument(&mut self, instruments: &[Instrument], step: isize) {
+        if instruments.is_empty() {return;}
+        let current = self.selected(instruments).and_then(|selected| instruments.iter().position(|instrument| instrument.name == selected.name)).unwrap_or(0);
+        let next = (current as isize + step).rem_euclid(instruments.len() as isize) as usize;
+        self.instrument = Some(instruments[next].name.clone());
+    }
+
+    fn step_depth(&mut self, step: isize) {
+        let mut view = self.view.lock().unwrap();
+        view.depth = view.depth.saturating_add_signed(step).clamp(1, MAX_DEPTH);
+    }
+
+    /// Cycles through raw levels, buckets of 10, 100 and 1000 ticks of the instrument shown, and buckets of 0.01% and
+    /// 0.1% of the mid. A grouping off this list, e.g. from the configuration, moves to its start.
+    fn step_grouping(&mut self, instruments: &[Instrument], step: isize) {
+        let Some(instrument) = self.selected(instruments) else {return};
+        let groupings = [Grouping::Raw, Grouping::Step(instrument.tick_size * 10.0), Grouping::Step(instrument.tick_size * 100.0), Grouping::Step(instrument.tick_size * 1000.0), Grouping::PercentOfMid(0.01), Grouping::PercentOfMid(0.1)];
+        let mut view = self.view.lock().unwrap();
+        let current = groupings.iter().position(|grouping| *grouping == view.grouping).unwrap_or(0);
+        view.grouping = groupings[(current as isize + step).rem_euclid(groupings.len() as isize) as usize];
+    }
+
+    fn draw(&self, frame: &mut Frame, instruments: &[Instrument], books: &HashMap<BookKey, OrderBook>, spreads: &[SpreadSample]) {
+        let [header, body, bottom] = Layout::vertical([Constraint::Length(1), Constraint::Min(8), Constraint::Length(12)]).areas(frame.area());
+        let view = *self.view.lock().unwrap();
+        let Some(instrument) = self.selected(instruments) else {
+            frame.render_widget(Paragraph::new("No instruments are followed."), body);
+            return;
+        };
+
+        let position = instruments.iter().position(|i| i.name == instrument.name).unwrap_or(0) + 1;
+        frame.render_widget(Paragraph::new(Line::from(vec![
+            Span::from(format!(" {} ", instrument.name)).bold().reversed(),
+            Span::from(format!(" {}/{} · {:?} · depth {} · {} · {} UTC", position, instruments.len(), instrument.kind, view.depth, grouping_name(view.grouping), Utc::now().format("%T"))),
+            Span::from("   ←/→ symbol  ↑/↓ depth  g grouping  q quit").dark_gray(),
+        ])), header);
+
+        let [ladder, side] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+        let venue_rows = books.keys().filter(|key| key.instrument == instrument.name).count().max(instrument.venues.len()) as u16;
+        let [venues, recent] = Layout::vertical([Constraint::Length(venue_rows + 3), Constraint::Min(3)]).areas(side);
+        let [chart, log] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(bottom);
+
+        self.draw_ladder(frame, ladder, instrument, books, view);
+        draw_venues(frame, venues, instrument, books);
+        self.draw_trades(frame, recent, instrument);
+        draw_spread(frame, chart, instrument, spreads);
+        self.draw_log(frame, log);
+    }
+
+    /// Asks above bids, the best of each meeting at the spread, so every row is a single price level.
+    fn draw_ladder(&self, frame: &mut Frame, area: Rect, instrument: &Instrument, books: &HashMap<BookKey, OrderBook>, view: aggregator::View) {
+        // Borders, the header and the spread row take four lines; what is left is shared by the two sides.
+        let depth = view.depth.min((area.height.saturating_sub(4) / 2) as usize).max(1);
+        let summary = aggregator::consolidate(books, Some(&instrument.name), instrument.kind, aggregator::View { depth, ..view }, None, None);
+        let (price_decimals, size_decimals) = (instruments::decimals(instrument.tick_size), instruments::decimals(instrument.lot_size).min(8));
+        let row = |level: &Level, color: Color| Row::new(vec![
+            Cell::from(venue_names(&level.exchange)),
+            Cell::from(Line::from(format!("{:.*}", size_decimals, level.amount)).alignment(Alignment::Right)),
+            Cell::from(Line::from(format!("{:.*}", price_decimals, level.price)).alignment(Alignment::Right).fg(color)),
+        ]);
+
+        let mut rows: Vec<Row> = vec![Row::default().height(1); depth.saturating_sub(summary.asks.len())];
+        rows.extend(summary.asks.iter().rev().map(|ask| row(ask, Color::Red)));
+        let spread = match (summary.bids.first(), summary.asks.first()) {
+            (Some(bid), Some(ask)) => format!("{:.*} ({:.1} bps)", price_decimals, summary.spread, summary.spread / ((bid.price + ask.price) / 2.0) * 1e4),
+            _ => "no live book".to_string(),
+        };
+        rows.push(Row::new(vec![Cell::from(""), Cell::from(Line::from("spread").alignment(Alignment::Right)), Cell::from(Line::from(spread).alignment(Alignment::Right))]).style(Style::new().add_modifier(Modifier::DIM)));
+        rows.extend(summary.bids.iter().map(|bid| row(bid, Color::Green)));
+
+        let table = Table::new(rows, [Constraint::Fill(1), Constraint::Length(16), Constraint::Length(24)])
+            .header(Row::new(["Venues", "Size", "Price"].map(|title| Cell::from(Line::from(title).alignment(if title == "Venues" {Alignment::Left} else {Alignment::Right})))).bold())
+            .block(Block::bordered().title(format!(" Consolidated {} ", instrument.name)));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_trades(&self, frame: &mut Frame, area: Rect, instrument: &Instrument) {
+        let decimals = (instruments::decimals(instrument.tick_size), instruments::decimals(instrument.lot_size).min(8));
+        let rows = self.trades.iter().filter(|trade| trade.instrument == instrument.name).map(|trade| Row::new(vec![
+            Cell::from(DateTime::from_timestamp_micros(trade.timestamp_micros).map_or(String::new(), |time| time.format("%T%.3f").to_string())),
+            Cell::from(trade.exchange.clone()).fg(venue_color(&trade.exchange)),
+            Cell::from(trade.side.clone()).fg(if trade.side == "buy" {Color::Green} else {Color::Red}),
+            Cell::from(Line::from(format!("{:.*}", decimals.0, trade.price)).alignment(Alignment::Right)),
+            Cell::from(Line::from(format!("{:.*}", decimals.1, trade.amount)).alignment(Alignment::Right)),
+        ]));
+        let table = Table::new(rows, [Constraint::Length(12), Constraint::Length(9), Constraint::Length(4), Constraint::Fill(1), Constraint::Fill(1)])
+            .header(Row::new(["Time", "Venue", "Side", "Price", "Amount"]).bold())
+            .block(Block::bordered().title(" Recent trades "));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_log(&self, frame: &mut Frame, area: Rect) {
+        let log = self.log.lock().unwrap();
+        let shown = log.len().saturating_sub(area.height.saturating_sub(2) as usize);
+        let items: Vec<ListItem> = log.iter().skip(shown).map(|line| ListItem::new(line.as_str())).collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Log ")).dark_gray(), area);
+    }
+}
+
+/// One line per venue book of `instrument`, with listings that have no book yet shown as connecting.
+fn draw_venues(frame: &mut Frame, area: Rect, instrument: &Instrument, books: &HashMap<BookKey, OrderBook>) {
+    let decimals = instruments::decimals(instrument.tick_size);
+    let now = Utc::now().timestamp_micros();
+    let mut exchanges: Vec<&String> = instrument.venues.keys().collect();
+    exchanges.extend(books.keys().filter(|key| key.instrument == instrument.name && !instrument.venues.contains_key(&key.exchange)).map(|key| &key.exchange));
+    let rows = exchanges.into_iter().map(|exchange| {
+        let book = books.get(&BookKey::new(exchange, &instrument.name));
+        let status = match book.map(|book| &book.status) {
+            Some(FeedStatus::Live) => Span::from("live").green(),
+            Some(FeedStatus::Stale(reason)) => Span::from(format!("stale: {}", reason)).red(),
+            Some(FeedStatus::Connecting) | None => Span::from("connecting").yellow(),
+        };
+        let price = |level: Option<&crate::models::LimitPrice>| level.map_or("-".to_string(), |level| format!("{:.*}", decimals, level.price.into_inner()));
+        Row::new(vec![
+            Cell::from(exchange.clone()).fg(venue_color(exchange)),
+            Cell::from(Line::from(price(book.and_then(OrderBook::best_bid))).alignment(Alignment::Right)),
+            Cell::from(Line::from(price(book.and_then(OrderBook::best_ask))).alignment(Alignment::Right)),
+            Cell::from(Line::from(book.filter(|book| book.last_update_micros > 0).map_or("-".to_string(), |book| format!("{} ms", (now - book.last_update_micros) / 1000))).alignment(Alignment::Right)),
+            Cell::from(Line::from(book.map_or(0, |book| book.crossed_incidents).to_string()).alignment(Alignment::Right)),
+            Cell::from(status),
+        ])
+    });
+    let table = Table::new(rows, [Constraint::Length(9), Constraint::Length(12), Constraint::Length(12), Constraint::Length(9), Constraint::Length(7), Constraint::Fill(1)])
+        .header(Row::new(["Venue", "Bid", "Ask", "Age", "Crossed", "Status"]).bold())
+        .block(Block::bordered().title(" Venues "));
+    frame.render_widget(table, area);
+}
+
+/// The consolidated spread of `instrument` as sampled by the spread history, over the last [`SPREAD_WINDOW`].
+fn draw_spread(frame: &mut Frame, area: Rect, instrument: &Instrument, spreads: &[SpreadSample]) {
+    let now = Utc::now().timestamp_micros();
+    let window = SPREAD_WINDOW.as_secs_f64();
+    let points: Vec<(f64, f64)> = spreads.iter()
+        .map(|sample| ((sample.timestamp_micros - now) as f64 / 1e6, sample.spread))
+        .filter(|(seconds, _)| *seconds >= -window)
+        .collect();
+    let block = Block::bordered().title(format!(" Spread, last {} s ", window));
+    if points.is_empty() {
+        frame.render_widget(Paragraph::new("No spread samples yet.").block(block), area);
+        return;
+    }
+    let (low, high) = points.iter().fold((f64::MAX, f64::MIN), |(low, high), (_, spread)| (low.min(*spread), high.max(*spread)));
+    let margin = ((high - low) * 0.1).max(instrument.tick_size);
+    let (low, high) = (low - margin, high + margin);
+    let decimals = instruments::decimals(instrument.tick_size);
+    let dataset = Dataset::default().marker(Marker::Braille).graph_type(GraphType::Line).cyan().data(&points);
+    let chart = Chart::new(vec![dataset])
+        .block(block)
+        .x_axis(Axis::default().bounds([-window, 0.0]).labels([format!("-{}s", window), "now".to_string()]).dark_gray())
+        .y_axis(Axis::default().bounds([low, high]).labels([format!("{:.*}", decimals, low), format!("{:.*}", decimals, high)]).dark_gray());
+    frame.render_widget(chart, area);
+}
+
+/// The comma-separated venues of a level, each in its colour.
+fn venue_names(exchanges: &str) -> Line<'static> {
+    let mut spans = Vec::new();
+    for (i, exchange) in exchanges.split(',').enumerate() {
+        if i > 0 {spans.push(Span::from(","));}
+        spans.push(Span::from(exchange.to_string()).fg(venue_color(exchange)));
+    }
+    Line::from(spans)
+}
+
+fn venue_color(exchange: &str) -> Color {
+    match exchange {
+        "Binance" => Color::Yellow,
+        "Bitstamp" => Color::Cyan,
+        "Kraken" => Color::Magenta,
+        "Coinbase" => Color::Blue,
+        "OKX" => Color::White,
+        "Bybit" => Color::LightRed,
+        _ => Color::Gray,
+    }
+}
+
+fn grouping_name(grouping: Grouping) -> String {
+    match grouping {
+        Grouping::Raw => "raw".to_string(),
+        Grouping::Step(size) => format!("{} buckets", size),
+        Grouping::PercentOfMid(percent) => format!("{}% buckets", percent),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use crate::{instruments::Registry, models::OrderType};
+
+    fn app(depth: usize, grouping: Grouping) -> App {
+        App { instrument: None, view: Arc::new(Mutex::new(aggregator::View { depth, grouping })), trades: VecDeque::new(), log: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    fn book(bids: &[(f64, f64)], asks: &[(f64, f64)]) -> OrderBook {
+        let mut book = OrderBook::default();
+        for &(price, size) in bids {book.set_level(OrderType::Buy as u8, price, size);}
+        for &(price, size) in asks {book.set_level(OrderType::Sell as u8, price, size);}
+        book.touch(None);
+        book
+    }
+
+    /// The ladder drawn in a `height` lines high area, each line with its runs of spaces collapsed.
+    fn ladder(app: &App, instrument: &Instrument, books: &HashMap<BookKey, OrderBook>, height: u16) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(60, height)).unwrap();
+        let view = *app.view.lock().unwrap();
+        terminal.draw(|frame| app.draw_ladder(frame, frame.area(), instrument, books, view)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content.chunks(buffer.area.width as usize).map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>().split_whitespace().collect::<Vec<_>>().join(" ")).collect()
+    }
+
+    #[test]
+    fn the_ladder_has_asks_above_the_spread_and_bids_below_it() {
+        let instrument = Registry::default().get("BTC/USD").unwrap().clone();
+        let books = HashMap::from([
+            (BookKey::new("Kraken", "BTC/USD"), book(&[(64000.0, 1.0)], &[(64001.0, 2.0)])),
+            (BookKey::new("Bitstamp", "BTC/USD"), book(&[(63999.0, 0.5)], &[(64002.0, 1.0)])),
+            (BookKey::new("Binance", "BTC/USDT"), book(&[(70000.0, 1.0)], &[(70001.0, 1.0)])),
+        ]);
+        // Ten levels asked for, but ten lines leave room for three a side; the missing ones are blank rows on the outside.
+        assert_eq!(ladder(&app(10, Grouping::Raw), &instrument, &books, 10), [
+            "┌ Consolidated BTC/USD ────────────────────────────────────┐",
+            "│Venues Size Price│",
+            "│ │",
+            "│Bitstamp 1.00000000 64002.00│",
+            "│Kraken 2.00000000 64001.00│",
+            "│ spread 1.00 (0.2 bps)│",
+            "│Kraken 1.00000000 64000.00│",
+            "│Bitstamp 0.50000000 63999.00│",
+            "│ │",
+            "└──────────────────────────────────────────────────────────┘",
+        ]);
+    }
+
+    #[test]
+    fn the_ladder_says_when_no_book_is_live() {
+        let instrument = Registry::default().get("BTC/USD").unwrap().clone();
+        let stale = OrderBook { status: FeedStatus::Stale("gap".to_string()), ..book(&[(64000.0, 1.0)], &[(64001.0, 1.0)]) };
+        let lines = ladder(&app(1, Grouping::Raw), &instrument, &HashMap::from([(BookKey::new("Kraken", "BTC/USD"), stale)]), 6);
+        assert_eq!(lines[2..5], ["│ │", "│ spread no live book│", "│ │"]);
+    }
+
+    #[test]
+    fn depth_stays_within_one_and_the_maximum() {
+        let mut app = app(10, Grouping::Raw);
+        app.step_depth(1);
+        assert_eq!(app.view.lock().unwrap().depth, 11);
+        app.view.lock().unwrap().depth = 1;
+        app.step_depth(-1);
+        assert_eq!(app.view.lock().unwrap().depth, 1);
+        app.view.lock().unwrap().depth = MAX_DEPTH;
+        app.step_depth(1);
+        assert_eq!(app.view.lock().unwrap().depth, MAX_DEPTH);
+    }
+
+    #[test]
+    fn grouping_cycles_through_ticks_of_the_instrument_shown_and_percentages_of_the_mid() {
+        let instruments = Registry::default().instruments;
+        let mut app = app(10, Grouping::Raw);
+        let tick = app.selected(&instruments).unwrap().tick_size;
+        let mut seen = Vec::new();
+        for _ in 0..6 {
+            app.step_grouping(&instruments, 1);
+            seen.push(app.view.lock().unwrap().grouping);
+        }
+        assert_eq!(seen, [Grouping::Step(tick * 10.0), Grouping::Step(tick * 100.0), Grouping::Step(tick * 1000.0), Grouping::PercentOfMid(0.01), Grouping::PercentOfMid(0.1), Grouping::Raw]);
+        app.step_grouping(&instruments, -1);
+        assert_eq!(app.view.lock().unwrap().grouping, Grouping::PercentOfMid(0.1));
+
+        // Buckets follow the tick size of the instrument shown.
+        app.instrument = Some("BTC/USDT-PERP".to_string());
+        app.view.lock().unwrap().grouping = Grouping::Raw;
+        app.step_grouping(&instruments, 1);
+        assert_eq!(app.view.lock().unwrap().grouping, Grouping::Step(0.1 * 10.0));
+        // A grouping off the list, e.g. from the configuration, starts the cycle over.
+        app.view.lock().unwrap().grouping = Grouping::Step(5.0);
+        app.step_grouping(&instruments, 1);
+        assert_eq!(app.view.lock().unwrap().grouping, Grouping::Step(0.1 * 10.0));
+    }
+}